@@ -0,0 +1,242 @@
+use crate::nanopileup::PileupPos;
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Alleles counted per strand, in column order.
+pub const ALLELES: [char; 5] = ['A', 'C', 'G', 'T', 'N'];
+
+/// Allele counts of the reads on one strand at a position.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct StrandAlleleCounts {
+    /// Reads per allele, indexed like `ALLELES`
+    pub bases: [u32; 5],
+    /// Sum of the base qualities per allele
+    pub qual_sums: [u64; 5],
+    /// Reads with a deletion over the position
+    pub deletions: u32,
+    /// Reads with an insertion after the position
+    pub insertions: u32,
+}
+
+impl StrandAlleleCounts {
+    /// Count an aligned base on the reference strand; bases other than A, C, G
+    /// and T are counted as N.
+    pub fn add_base(&mut self, base: char, qual: u8) {
+        let idx = ALLELES[..4]
+            .iter()
+            .position(|&a| a == base.to_ascii_uppercase())
+            .unwrap_or(4);
+        self.bases[idx] += 1;
+        self.qual_sums[idx] += qual as u64;
+    }
+
+    pub fn mean_qual(&self, idx: usize) -> Option<f64> {
+        match self.bases[idx] {
+            0 => None,
+            n => Some(self.qual_sums[idx] as f64 / n as f64),
+        }
+    }
+}
+
+/// Per-strand allele counts at a position.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct AlleleCounts {
+    pub forward: StrandAlleleCounts,
+    pub reverse: StrandAlleleCounts,
+}
+
+impl AlleleCounts {
+    pub fn strand_mut(&mut self, is_reverse: bool) -> &mut StrandAlleleCounts {
+        if is_reverse {
+            &mut self.reverse
+        } else {
+            &mut self.forward
+        }
+    }
+}
+
+/// Writes one row of strand-split allele counts and mean base qualities per
+/// position.
+pub struct AlleleCountWriter {
+    out: BufWriter<File>,
+}
+
+impl AlleleCountWriter {
+    pub fn create(path: &Path, split_haplotypes: bool) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create allele count file '{}'", path.display()))?;
+        let mut out = BufWriter::new(file);
+        let mut header = vec![
+            "chrom".to_string(),
+            "pos".to_string(),
+            "ref_base".to_string(),
+        ];
+        if split_haplotypes {
+            header.push("haplotype".to_string());
+        }
+        for strand in ["fwd", "rev"] {
+            header.extend(ALLELES.iter().map(|a| format!("{}_{}", strand, a)));
+            header.push(format!("{}_del", strand));
+            header.push(format!("{}_ins", strand));
+        }
+        for strand in ["fwd", "rev"] {
+            header.extend(ALLELES.iter().map(|a| format!("{}_qual_{}", strand, a)));
+        }
+        writeln!(out, "{}", header.join("\t"))?;
+        Ok(Self { out })
+    }
+
+    pub fn write_position(&mut self, p: &PileupPos) -> Result<()> {
+        let Some(counts) = &p.allele_counts else {
+            return Ok(());
+        };
+        let mut fields = vec![
+            p.chrom.clone(),
+            (p.pos + 1).to_string(), // 1-based output
+            p.ref_base.to_string(),
+        ];
+        if let Some(haplotype) = p.haplotype {
+            fields.push(haplotype.to_string());
+        }
+        let strands = [&counts.forward, &counts.reverse];
+        for strand in strands {
+            fields.extend(strand.bases.iter().map(|n| n.to_string()));
+            fields.push(strand.deletions.to_string());
+            fields.push(strand.insertions.to_string());
+        }
+        for strand in strands {
+            fields.extend((0..ALLELES.len()).map(|i| {
+                strand
+                    .mean_qual(i)
+                    .map_or("NA".to_string(), |q| format!("{:.1}", q))
+            }));
+        }
+        writeln!(self.out, "{}", fields.join("\t"))?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nanopileup::{self, PileupOptions, RunSummary};
+    use crate::testutil::{self, TempDir};
+    use rust_htslib::bam::{
+        self,
+        record::{Cigar, CigarString},
+    };
+
+    fn read(name: &str, cigar: &[Cigar], seq: &[u8], qual: u8, is_reverse: bool) -> bam::Record {
+        let mut record = testutil::read(name, 0, 0, cigar, seq);
+        let cigar = CigarString(cigar.to_vec());
+        record.set(name.as_bytes(), Some(&cigar), seq, &vec![qual; seq.len()]);
+        if is_reverse {
+            record.set_reverse();
+        }
+        record
+    }
+
+    /// Pile up `regions` of a small fixture and return the allele count table.
+    fn allele_table(regions: &[&str], split_haplotypes: bool) -> Vec<String> {
+        let dir = TempDir::new();
+        let (bam, fasta, table) = (
+            dir.join("reads.bam"),
+            dir.join("ref.fa"),
+            dir.join("alleles.tsv"),
+        );
+        testutil::write_fasta(&fasta, &[("chr1", "ACGTACGTAC")]);
+        let reads = vec![
+            read("f1", &[Cigar::Match(5)], b"ACGTA", 30, false),
+            read("f2", &[Cigar::Match(5)], b"ACTTA", 20, false),
+            // Below the default minimum base quality of 13
+            read("f3", &[Cigar::Match(5)], b"ACGTA", 10, false),
+            read("f4", &[Cigar::Match(5)], b"ACGTA", 40, false),
+            read("r1", &[Cigar::Match(5)], b"ACGTA", 40, true),
+            // Deletes the G at position 3
+            read(
+                "r2",
+                &[Cigar::Match(2), Cigar::Del(1), Cigar::Match(2)],
+                b"ACTA",
+                30,
+                true,
+            ),
+            // N at position 3, followed by two inserted bases
+            read(
+                "r3",
+                &[Cigar::Match(3), Cigar::Ins(2), Cigar::Match(2)],
+                b"ACNTTTA",
+                25,
+                true,
+            ),
+        ];
+        testutil::write_bam(&bam, &[("chr1", 10)], reads);
+
+        let options = PileupOptions {
+            keep_allele_counts: true,
+            ..PileupOptions::default()
+        };
+        let mut writer = AlleleCountWriter::create(&table, split_haplotypes).unwrap();
+        for region in regions {
+            let region = region.parse().unwrap();
+            let mut summary = RunSummary::default();
+            for p in
+                nanopileup::nanopileup(&bam, &region, Some(&fasta), &options, None, &mut summary)
+                    .unwrap()
+            {
+                writer.write_position(&p).unwrap();
+            }
+        }
+        writer.finish().unwrap();
+        std::fs::read_to_string(&table)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn strand_counts() {
+        let lines = allele_table(&["chr1:3-3", "chr1:8-8"], false);
+        let header: Vec<&str> = lines[0].split('\t').collect();
+        assert_eq!(header.len(), 3 + 2 * 7 + 2 * 5);
+        assert_eq!(header[..5], ["chrom", "pos", "ref_base", "fwd_A", "fwd_C"]);
+        assert_eq!(
+            header[8..17],
+            [
+                "fwd_del", "fwd_ins", "rev_A", "rev_C", "rev_G", "rev_T", "rev_N", "rev_del",
+                "rev_ins"
+            ]
+        );
+        assert_eq!(header[17], "fwd_qual_A");
+        assert_eq!(header[26], "rev_qual_N");
+
+        // Bases on the reference strand per read strand, then the mean base
+        // quality of each allele
+        let counts = ["0\t0\t2\t1\t0\t0\t0", "0\t0\t1\t0\t1\t1\t1"].join("\t");
+        let quals = ["NA\tNA\t35.0\t20.0\tNA", "NA\tNA\t40.0\tNA\t25.0"].join("\t");
+        assert_eq!(lines[1], format!("chr1\t3\tG\t{}\t{}", counts, quals));
+        // Positions without reads still get a row
+        let zeros = ["0"; 14].join("\t");
+        let missing = ["NA"; 10].join("\t");
+        assert_eq!(lines[2], format!("chr1\t8\tT\t{}\t{}", zeros, missing));
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn haplotype_column() {
+        let lines = allele_table(&[], true);
+        let header: Vec<&str> = lines[0].split('\t').collect();
+        assert_eq!(
+            header[..5],
+            ["chrom", "pos", "ref_base", "haplotype", "fwd_A"]
+        );
+        assert_eq!(header.len(), 4 + 2 * 7 + 2 * 5);
+    }
+}
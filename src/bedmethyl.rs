@@ -0,0 +1,319 @@
+use crate::modbase::ModCounts;
+use crate::nanopileup::PileupPos;
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Reference context of the modified bases written to the bedMethyl file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ModContext {
+    #[default]
+    Any,
+    /// Only the C of reference CpGs
+    CpG,
+}
+
+impl FromStr for ModContext {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "any" => Ok(ModContext::Any),
+            "cpg" => Ok(ModContext::CpG),
+            _ => Err(anyhow::anyhow!(
+                "Invalid modification context '{}', expected 'any' or 'cpg'",
+                s
+            )),
+        }
+    }
+}
+
+/// CpG records on the + strand waiting for the - strand record one base later.
+struct PendingCpG {
+    chrom: String,
+    pos: usize,
+    counts: Vec<ModCounts>,
+}
+
+/// Writes per-position modification frequencies in the bedMethyl layout used by
+/// modkit: the nine BED columns followed by valid coverage, percent modified and
+/// the modified, canonical, other modification, deleted, filtered, different
+/// base and no-call read counts.
+pub struct BedMethylWriter {
+    out: BufWriter<File>,
+    context: ModContext,
+    combine_strands: bool,
+    pending: Option<PendingCpG>,
+}
+
+impl BedMethylWriter {
+    pub fn create(path: &Path, context: ModContext, combine_strands: bool) -> Result<Self> {
+        if combine_strands && context != ModContext::CpG {
+            return Err(anyhow::anyhow!(
+                "Combining strands requires the 'cpg' modification context"
+            ));
+        }
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create bedMethyl file '{}'", path.display()))?;
+        Ok(Self {
+            out: BufWriter::new(file),
+            context,
+            combine_strands,
+            pending: None,
+        })
+    }
+
+    pub fn write_position(&mut self, p: &PileupPos) -> Result<()> {
+        let Some(counts) = &p.mod_counts else {
+            return Ok(());
+        };
+        let counts = counts
+            .iter()
+            .filter(|c| self.context == ModContext::Any || c.cpg);
+        if !self.combine_strands {
+            for c in counts {
+                write_record(&mut self.out, &p.chrom, p.pos, c.strand, c)?;
+            }
+            return Ok(());
+        }
+
+        // The - strand C of a CpG sits one base after the + strand C
+        let (plus, minus): (Vec<ModCounts>, Vec<ModCounts>) = counts.partition(|c| c.strand == '+');
+        let mut merged = match self.pending.take() {
+            Some(pending) if pending.chrom == p.chrom && pending.pos + 1 == p.pos => pending,
+            other => {
+                self.flush(other)?;
+                PendingCpG {
+                    chrom: p.chrom.clone(),
+                    pos: p.pos.saturating_sub(1),
+                    counts: Vec::new(),
+                }
+            }
+        };
+        for c in minus {
+            match merged.counts.iter_mut().find(|m| m.code == c.code) {
+                Some(m) => m.merge(&c),
+                None => merged.counts.push(c),
+            }
+        }
+        self.flush(Some(merged))?;
+        if !plus.is_empty() {
+            self.pending = Some(PendingCpG {
+                chrom: p.chrom.clone(),
+                pos: p.pos,
+                counts: plus,
+            });
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, pending: Option<PendingCpG>) -> Result<()> {
+        if let Some(pending) = pending {
+            for c in &pending.counts {
+                write_record(&mut self.out, &pending.chrom, pending.pos, '.', c)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        let pending = self.pending.take();
+        self.flush(pending)?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+fn write_record(
+    out: &mut impl Write,
+    chrom: &str,
+    pos: usize,
+    strand: char,
+    c: &ModCounts,
+) -> Result<()> {
+    let coverage = c.valid_coverage();
+    writeln!(
+        out,
+        "{chrom}\t{start}\t{end}\t{code}\t{coverage}\t{strand}\t{start}\t{end}\t255,0,0\t{coverage}\t{percent:.2}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        c.n_mod,
+        c.n_canonical,
+        c.n_other_mod,
+        c.n_delete,
+        c.n_filtered,
+        c.n_diff,
+        c.n_nocall,
+        start = pos,
+        end = pos + 1,
+        code = c.code,
+        percent = c.percent_modified(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbase::ModCode;
+    use crate::nanopileup::PileupOptions;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn counts(code: char, strand: char, cpg: bool, n_mod: u32, n_canonical: u32) -> ModCounts {
+        ModCounts {
+            code: ModCode::Letter(code),
+            canonical: 'C',
+            strand,
+            cpg,
+            n_mod,
+            n_canonical,
+            n_other_mod: 0,
+            n_delete: 0,
+            n_filtered: 0,
+            n_diff: 0,
+            n_nocall: 0,
+        }
+    }
+
+    fn position(chrom: &str, pos: usize, mod_counts: Vec<ModCounts>) -> PileupPos {
+        let mut p = PileupPos::new(
+            chrom.to_string(),
+            pos,
+            'C',
+            &PileupOptions::default(),
+            false,
+        );
+        p.mod_counts = Some(mod_counts);
+        p
+    }
+
+    /// Write `positions` to a bedMethyl file and return its lines.
+    fn write(context: ModContext, combine_strands: bool, positions: &[PileupPos]) -> Vec<String> {
+        // Tests run in parallel, so every call gets its own file
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "nanopile-test-{}-{}.bed",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut writer = BedMethylWriter::create(&path, context, combine_strands).unwrap();
+        for p in positions {
+            writer.write_position(p).unwrap();
+        }
+        writer.finish().unwrap();
+        let lines = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        lines.lines().map(str::to_string).collect()
+    }
+
+    /// The chrom, start, strand, code and modified/canonical counts of each line.
+    fn short(lines: &[String]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|line| {
+                let cols: Vec<&str> = line.split('\t').collect();
+                format!(
+                    "{} {} {} {} {}/{}",
+                    cols[0], cols[1], cols[5], cols[3], cols[11], cols[12]
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn modkit_columns() {
+        let mut c = counts('m', '+', true, 1, 3);
+        c.n_delete = 1;
+        c.n_filtered = 2;
+        c.n_nocall = 1;
+        let lines = write(ModContext::Any, false, &[position("chr20", 10034, vec![c])]);
+        // chrom, start, end, code, score, strand, thick start and end, colour,
+        // valid coverage, percent modified, then the mod, canonical, other mod,
+        // delete, fail, diff and no call counts, as in `modkit pileup` output
+        assert_eq!(
+            lines,
+            ["chr20\t10034\t10035\tm\t4\t+\t10034\t10035\t255,0,0\t4\t25.00\t1\t3\t0\t1\t2\t0\t1"]
+        );
+    }
+
+    #[test]
+    fn cpg_context() {
+        let positions = [
+            position("chr1", 5, vec![counts('m', '+', false, 1, 0)]),
+            position("chr1", 10, vec![counts('m', '+', true, 1, 0)]),
+        ];
+        assert_eq!(short(&write(ModContext::Any, false, &positions)).len(), 2);
+        assert_eq!(
+            short(&write(ModContext::CpG, false, &positions)),
+            ["chr1 10 + m 1/0"]
+        );
+        let err = BedMethylWriter::create(Path::new("/dev/null"), ModContext::Any, true)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Combining strands requires the 'cpg' modification context"
+        );
+    }
+
+    #[test]
+    fn combined_cpg_strands() {
+        // The + strand C is the last base of one parallel chunk, its - strand
+        // partner the first base of the next
+        let positions = [
+            position(
+                "chr1",
+                99_999,
+                vec![counts('h', '+', true, 1, 0), counts('m', '+', true, 2, 1)],
+            ),
+            position(
+                "chr1",
+                100_000,
+                vec![counts('m', '-', true, 3, 0), counts('a', '-', true, 1, 1)],
+            ),
+        ];
+        assert_eq!(
+            short(&write(ModContext::CpG, true, &positions)),
+            [
+                "chr1 99999 . h 1/0",
+                "chr1 99999 . m 5/1",
+                "chr1 99999 . a 1/1"
+            ]
+        );
+        // Without combining, each strand keeps its own record
+        assert_eq!(
+            short(&write(ModContext::CpG, false, &positions)),
+            [
+                "chr1 99999 + h 1/0",
+                "chr1 99999 + m 2/1",
+                "chr1 100000 - m 3/0",
+                "chr1 100000 - a 1/1"
+            ]
+        );
+    }
+
+    #[test]
+    fn unpaired_cpg_strands() {
+        let positions = [
+            // - strand record whose + partner lies before the region
+            position("chr1", 5, vec![counts('m', '-', true, 1, 0)]),
+            // + strand record whose - partner is not covered
+            position("chr1", 10, vec![counts('m', '+', true, 2, 0)]),
+            position("chr1", 20, vec![counts('m', '+', true, 3, 0)]),
+            // Same position on the next contig is not a partner
+            position("chr2", 21, vec![counts('m', '-', true, 4, 0)]),
+            // + strand record at the end of the last contig
+            position("chr2", 30, vec![counts('m', '+', true, 5, 0)]),
+        ];
+        assert_eq!(
+            short(&write(ModContext::CpG, true, &positions)),
+            [
+                "chr1 4 . m 1/0",
+                "chr1 10 . m 2/0",
+                "chr1 20 . m 3/0",
+                "chr2 20 . m 4/0",
+                "chr2 30 . m 5/0"
+            ]
+        );
+    }
+}
@@ -0,0 +1,542 @@
+use crate::dwell::median;
+use crate::nanopileup::{self, PileupOptions, RunSummary};
+use crate::region;
+use anyhow::Result;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Per-position two-sample test applied to dwell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DwellTest {
+    /// Mann–Whitney U, effect size is the rank-biserial correlation
+    #[default]
+    MannWhitney,
+    /// Two-sample Kolmogorov–Smirnov, effect size is the D statistic
+    KolmogorovSmirnov,
+    /// Two-component Gaussian mixture on log dwell, effect size is the log odds
+    /// ratio of treated reads falling in the slower component
+    GaussianMixture,
+}
+
+impl FromStr for DwellTest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "mwu" => Ok(DwellTest::MannWhitney),
+            "ks" => Ok(DwellTest::KolmogorovSmirnov),
+            "gmm" => Ok(DwellTest::GaussianMixture),
+            _ => Err(anyhow::anyhow!(
+                "Invalid dwell test '{}', expected 'mwu', 'ks' or 'gmm'",
+                s
+            )),
+        }
+    }
+}
+
+impl DwellTest {
+    pub fn run(&self, treated: &[f64], control: &[f64]) -> TestResult {
+        match self {
+            DwellTest::MannWhitney => mann_whitney_u(treated, control),
+            DwellTest::KolmogorovSmirnov => kolmogorov_smirnov(treated, control),
+            DwellTest::GaussianMixture => gaussian_mixture(treated, control),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TestResult {
+    pub effect_size: f64,
+    pub p_value: f64,
+}
+
+impl TestResult {
+    /// Result for samples that cannot be compared, e.g. an empty group.
+    pub const NOT_TESTABLE: TestResult = TestResult {
+        effect_size: 0.0,
+        p_value: 1.0,
+    };
+}
+
+/// Outcome of the dwell test at one reference position.
+#[derive(Debug, Clone)]
+pub struct PositionComparison {
+    pub chrom: String,
+    pub pos: usize, // 0-based
+    pub ref_base: char,
+    pub n_treated: usize,
+    pub n_control: usize,
+    pub median_treated: f64,
+    pub median_control: f64,
+    pub effect_size: f64,
+    pub p_value: f64,
+    /// Benjamini–Hochberg adjusted p-value, set by `adjust_q_values`
+    pub q_value: f64,
+}
+
+impl fmt::Display for PositionComparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{:.3}\t{:.3}\t{:.4}\t{:.4e}\t{:.4e}",
+            self.chrom,
+            self.pos + 1, // 1-based output
+            self.ref_base,
+            self.n_treated,
+            self.n_control,
+            self.median_treated,
+            self.median_control,
+            self.effect_size,
+            self.p_value,
+            self.q_value
+        )
+    }
+}
+
+/// Pileup `treated` and `control` over `region` and test the dwell of every
+/// position covered by at least `min_coverage` reads in both samples. Each
+/// sample keeps its own summary, so filtered reads and move table mismatches
+/// can be told apart.
+#[allow(clippy::too_many_arguments)]
+pub fn compare_region(
+    treated: &PathBuf,
+    control: &PathBuf,
+    region: &region::Region,
+    ref_fp: Option<&PathBuf>,
+    options: &PileupOptions,
+    test: DwellTest,
+    min_coverage: usize,
+    treated_summary: &mut RunSummary,
+    control_summary: &mut RunSummary,
+) -> Result<Vec<PositionComparison>> {
+    let options = PileupOptions {
+        keep_read_dwell: true,
+        ..options.clone()
+    };
+    let treated_pileup =
+        nanopileup::nanopileup(treated, region, ref_fp, &options, None, treated_summary)?;
+    let control_pileup =
+        nanopileup::nanopileup(control, region, ref_fp, &options, None, control_summary)?;
+
+    let mut results = Vec::new();
+    for (t, c) in treated_pileup.into_iter().zip(control_pileup) {
+        let (Some(mut t_dwell), Some(mut c_dwell)) = (t.read_dwell, c.read_dwell) else {
+            continue;
+        };
+        if t_dwell.len() < min_coverage.max(1) || c_dwell.len() < min_coverage.max(1) {
+            continue;
+        }
+        let result = test.run(&t_dwell, &c_dwell);
+        t_dwell.sort_by(|a, b| a.total_cmp(b));
+        c_dwell.sort_by(|a, b| a.total_cmp(b));
+        results.push(PositionComparison {
+            chrom: t.chrom,
+            pos: t.pos,
+            ref_base: t.ref_base,
+            n_treated: t_dwell.len(),
+            n_control: c_dwell.len(),
+            median_treated: median(&t_dwell),
+            median_control: median(&c_dwell),
+            effect_size: result.effect_size,
+            p_value: result.p_value,
+            q_value: f64::NAN,
+        });
+    }
+    Ok(results)
+}
+
+/// Fill in Benjamini–Hochberg q-values over all tested positions.
+pub fn adjust_q_values(results: &mut [PositionComparison]) {
+    let p_values: Vec<f64> = results.iter().map(|r| r.p_value).collect();
+    for (r, q) in results.iter_mut().zip(benjamini_hochberg(&p_values)) {
+        r.q_value = q;
+    }
+}
+
+pub fn benjamini_hochberg(p_values: &[f64]) -> Vec<f64> {
+    let m = p_values.len();
+    let mut order: Vec<usize> = (0..m).collect();
+    order.sort_by(|&a, &b| p_values[a].total_cmp(&p_values[b]));
+
+    let mut q_values = vec![1.0; m];
+    let mut running_min: f64 = 1.0;
+    for (rank, &idx) in order.iter().enumerate().rev() {
+        running_min = running_min.min(p_values[idx] * m as f64 / (rank + 1) as f64);
+        q_values[idx] = running_min;
+    }
+    q_values
+}
+
+/// Complementary error function, fractional error below 1.2e-7.
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let ans = t
+        * (-z * z - 1.26551223
+            + t * (1.00002368
+                + t * (0.37409196
+                    + t * (0.09678418
+                        + t * (-0.18628806
+                            + t * (0.27886807
+                                + t * (-1.13520398
+                                    + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+            .exp();
+    if x >= 0.0 { ans } else { 2.0 - ans }
+}
+
+/// Two-sided Mann–Whitney U test with tie correction and the normal approximation.
+pub fn mann_whitney_u(x: &[f64], y: &[f64]) -> TestResult {
+    if x.is_empty() || y.is_empty() {
+        return TestResult::NOT_TESTABLE;
+    }
+    let (n1, n2) = (x.len() as f64, y.len() as f64);
+    let mut pooled: Vec<(f64, bool)> = x
+        .iter()
+        .map(|&v| (v, true))
+        .chain(y.iter().map(|&v| (v, false)))
+        .collect();
+    pooled.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let n = pooled.len();
+    let mut rank_sum_x = 0.0;
+    let mut tie_term = 0.0;
+    let mut i = 0;
+    while i < n {
+        let mut j = i;
+        while j + 1 < n && pooled[j + 1].0 == pooled[i].0 {
+            j += 1;
+        }
+        let rank = (i + j) as f64 / 2.0 + 1.0;
+        rank_sum_x += rank * pooled[i..=j].iter().filter(|(_, is_x)| *is_x).count() as f64;
+        let t = (j - i + 1) as f64;
+        tie_term += t * t * t - t;
+        i = j + 1;
+    }
+
+    let u = rank_sum_x - n1 * (n1 + 1.0) / 2.0;
+    let mean = n1 * n2 / 2.0;
+    let n = n1 + n2;
+    let var = n1 * n2 / 12.0 * ((n + 1.0) - tie_term / (n * (n - 1.0)));
+    let p_value = if var > 0.0 {
+        // Continuity correction towards the mean
+        let z = ((u - mean).abs() - 0.5).max(0.0) / var.sqrt();
+        erfc(z / std::f64::consts::SQRT_2)
+    } else {
+        1.0
+    };
+    TestResult {
+        effect_size: 2.0 * u / (n1 * n2) - 1.0,
+        p_value: p_value.min(1.0),
+    }
+}
+
+/// Two-sample Kolmogorov–Smirnov test with the asymptotic p-value.
+pub fn kolmogorov_smirnov(x: &[f64], y: &[f64]) -> TestResult {
+    if x.is_empty() || y.is_empty() {
+        return TestResult::NOT_TESTABLE;
+    }
+    let mut x = x.to_vec();
+    let mut y = y.to_vec();
+    x.sort_by(|a, b| a.total_cmp(b));
+    y.sort_by(|a, b| a.total_cmp(b));
+    let (n1, n2) = (x.len() as f64, y.len() as f64);
+
+    let (mut i, mut j) = (0, 0);
+    let mut d: f64 = 0.0;
+    while i < x.len() && j < y.len() {
+        let v = x[i].min(y[j]);
+        while i < x.len() && x[i] <= v {
+            i += 1;
+        }
+        while j < y.len() && y[j] <= v {
+            j += 1;
+        }
+        d = d.max((i as f64 / n1 - j as f64 / n2).abs());
+    }
+
+    let en = (n1 * n2 / (n1 + n2)).sqrt();
+    let lambda = (en + 0.12 + 0.11 / en) * d;
+    TestResult {
+        effect_size: d,
+        p_value: kolmogorov_q(lambda),
+    }
+}
+
+/// Survival function of the Kolmogorov distribution.
+fn kolmogorov_q(lambda: f64) -> f64 {
+    if lambda < 1e-3 {
+        return 1.0;
+    }
+    let mut sum = 0.0;
+    let mut sign = 1.0;
+    let mut previous: f64 = 0.0;
+    for k in 1..=100 {
+        let term = sign * 2.0 * (-2.0 * (k * k) as f64 * lambda * lambda).exp();
+        sum += term;
+        if term.abs() <= 1e-3 * previous || term.abs() <= 1e-8 * sum {
+            return sum.clamp(0.0, 1.0);
+        }
+        sign = -sign;
+        previous = term.abs();
+    }
+    // Series did not converge, which only happens for very small lambda
+    1.0
+}
+
+/// Fit a two-component Gaussian mixture to the pooled log dwell, assign every
+/// read to its most likely component and test the sample × component table with
+/// a chi-square test of independence.
+pub fn gaussian_mixture(x: &[f64], y: &[f64]) -> TestResult {
+    const MAX_ITER: usize = 200;
+    const MIN_VAR: f64 = 1e-6;
+
+    let log = |v: &[f64]| -> Vec<f64> { v.iter().filter(|&&d| d > 0.0).map(|d| d.ln()).collect() };
+    let (lx, ly) = (log(x), log(y));
+    let pooled: Vec<f64> = lx.iter().chain(&ly).copied().collect();
+    if lx.is_empty() || ly.is_empty() {
+        return TestResult::NOT_TESTABLE;
+    }
+
+    // Initialise from the lower and upper halves of the pooled values
+    let mut sorted = pooled.clone();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let half = sorted.len() / 2;
+    let mean_of = |v: &[f64]| v.iter().sum::<f64>() / v.len().max(1) as f64;
+    let overall_mean = mean_of(&sorted);
+    let overall_var = (sorted
+        .iter()
+        .map(|v| (v - overall_mean).powi(2))
+        .sum::<f64>()
+        / sorted.len() as f64)
+        .max(MIN_VAR);
+    let mut mu = [mean_of(&sorted[..half.max(1)]), mean_of(&sorted[half..])];
+    let mut var = [overall_var; 2];
+    let mut weight = [0.5; 2];
+
+    let density = |v: f64, mu: f64, var: f64| {
+        (-(v - mu).powi(2) / (2.0 * var)).exp() / (2.0 * std::f64::consts::PI * var).sqrt()
+    };
+    let responsibility = |v: f64, mu: &[f64; 2], var: &[f64; 2], weight: &[f64; 2]| {
+        let a = weight[0] * density(v, mu[0], var[0]);
+        let b = weight[1] * density(v, mu[1], var[1]);
+        if a + b > 0.0 { b / (a + b) } else { 0.5 }
+    };
+
+    let mut previous_ll = f64::NEG_INFINITY;
+    for _ in 0..MAX_ITER {
+        let r: Vec<f64> = pooled
+            .iter()
+            .map(|&v| responsibility(v, &mu, &var, &weight))
+            .collect();
+        let n1: f64 = r.iter().sum();
+        let n0 = pooled.len() as f64 - n1;
+        if n0 < 1e-9 || n1 < 1e-9 {
+            break;
+        }
+        mu[0] = pooled
+            .iter()
+            .zip(&r)
+            .map(|(v, r)| v * (1.0 - r))
+            .sum::<f64>()
+            / n0;
+        mu[1] = pooled.iter().zip(&r).map(|(v, r)| v * r).sum::<f64>() / n1;
+        var[0] = (pooled
+            .iter()
+            .zip(&r)
+            .map(|(v, r)| (1.0 - r) * (v - mu[0]).powi(2))
+            .sum::<f64>()
+            / n0)
+            .max(MIN_VAR);
+        var[1] = (pooled
+            .iter()
+            .zip(&r)
+            .map(|(v, r)| r * (v - mu[1]).powi(2))
+            .sum::<f64>()
+            / n1)
+            .max(MIN_VAR);
+        weight = [n0 / pooled.len() as f64, n1 / pooled.len() as f64];
+
+        let ll: f64 = pooled
+            .iter()
+            .map(|&v| {
+                (weight[0] * density(v, mu[0], var[0]) + weight[1] * density(v, mu[1], var[1]))
+                    .max(f64::MIN_POSITIVE)
+                    .ln()
+            })
+            .sum();
+        if (ll - previous_ll).abs() < 1e-8 {
+            break;
+        }
+        previous_ll = ll;
+    }
+
+    // Component 1 is the slower one
+    let slow = if mu[1] >= mu[0] { 1 } else { 0 };
+    let in_slow = |v: &f64| {
+        let r = responsibility(*v, &mu, &var, &weight);
+        if slow == 1 { r > 0.5 } else { r < 0.5 }
+    };
+    let a = lx.iter().filter(|v| in_slow(v)).count() as f64;
+    let b = lx.len() as f64 - a;
+    let c = ly.iter().filter(|v| in_slow(v)).count() as f64;
+    let d = ly.len() as f64 - c;
+
+    let effect_size = ((a + 0.5) * (d + 0.5) / ((b + 0.5) * (c + 0.5))).ln();
+    let n = a + b + c + d;
+    let denom = (a + b) * (c + d) * (a + c) * (b + d);
+    if denom == 0.0 {
+        return TestResult {
+            effect_size,
+            p_value: 1.0,
+        };
+    }
+    let chi2 = n * (a * d - b * c).powi(2) / denom;
+    TestResult {
+        effect_size,
+        p_value: erfc((chi2 / 2.0).sqrt()).min(1.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reference values are those of scipy.stats (mannwhitneyu with
+    // method="asymptotic", false_discovery_control), scipy.special (erfc,
+    // kolmogorov) and scipy.stats.chi2.sf for the mixture's chi-square test.
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance * expected.abs().max(1e-300),
+            "{} differs from {} by more than {}",
+            actual,
+            expected,
+            tolerance
+        );
+    }
+
+    #[test]
+    fn erfc_reference_values() {
+        for (x, expected) in [
+            (0.0, 1.0),
+            (0.5, 0.4795001221869535),
+            (1.0, 0.15729920705028513),
+            (-1.0, 1.842700792949715),
+            (3.0, 2.2090496998585438e-05),
+        ] {
+            assert_close(erfc(x), expected, 1.2e-7);
+        }
+    }
+
+    #[test]
+    fn mann_whitney_u_reference_values() {
+        // Example of the scipy.stats.mannwhitneyu documentation
+        let result = mann_whitney_u(&[19.0, 22.0, 16.0, 29.0, 24.0], &[20.0, 11.0, 17.0, 12.0]);
+        assert_close(result.p_value, 0.11134688653314041, 1e-6);
+        assert_close(result.effect_size, 0.7, 1e-12);
+
+        // Tied ranks across both samples
+        let result = mann_whitney_u(&[1.0, 2.0, 2.0, 3.0, 3.0], &[2.0, 3.0, 3.0, 4.0, 4.0, 5.0]);
+        assert_close(result.p_value, 0.07236892118078307, 1e-6);
+        assert_close(result.effect_size, -2.0 / 3.0, 1e-12);
+    }
+
+    #[test]
+    fn mann_whitney_u_edge_cases() {
+        // One read per sample: the continuity correction cancels the difference
+        let result = mann_whitney_u(&[1.0], &[2.0]);
+        assert_eq!(result.p_value, 1.0);
+        assert_eq!(result.effect_size, -1.0);
+        // All values tied: zero variance
+        let result = mann_whitney_u(&[3.0; 4], &[3.0; 5]);
+        assert_eq!(result.p_value, 1.0);
+        assert_eq!(result.effect_size, 0.0);
+        assert_eq!(mann_whitney_u(&[], &[1.0, 2.0]), TestResult::NOT_TESTABLE);
+        assert_eq!(mann_whitney_u(&[1.0], &[]), TestResult::NOT_TESTABLE);
+    }
+
+    #[test]
+    fn kolmogorov_q_reference_values() {
+        assert_close(kolmogorov_q(0.5), 0.9639452436648751, 1e-6);
+        assert_close(kolmogorov_q(1.0), 0.26999967167735456, 1e-6);
+        assert_close(kolmogorov_q(2.0), 0.0006709252557796953, 1e-6);
+        assert_eq!(kolmogorov_q(0.0), 1.0);
+    }
+
+    #[test]
+    fn kolmogorov_smirnov_reference_values() {
+        let treated = [
+            0.61, 0.29, 0.06, 0.59, -1.73, -0.74, 0.51, -0.56, 0.39, 1.64, 0.05, -0.06, 0.64,
+            -0.82, 0.37, 1.77, 1.09, -1.28, 2.36, 1.31, 1.05, -0.32, -0.4, 1.06, -2.47,
+        ];
+        let control = [
+            2.20, 1.66, 1.38, 0.20, 1.25, 1.36, 1.25, 1.64, 1.35, 2.59, 0.97, 1.44, 2.13, 2.01,
+            1.98,
+        ];
+        let result = kolmogorov_smirnov(&treated, &control);
+        assert_close(result.effect_size, 0.7066666666666667, 1e-12);
+        // kolmogorov(lambda) with lambda = (sqrt(ne) + 0.12 + 0.11 / sqrt(ne)) * D
+        assert_close(result.p_value, 6.454632104319428e-05, 1e-6);
+
+        // Ties within and across samples
+        let result =
+            kolmogorov_smirnov(&[1.0, 2.0, 2.0, 3.0, 3.0], &[2.0, 3.0, 3.0, 4.0, 4.0, 5.0]);
+        assert_close(result.effect_size, 0.5, 1e-12);
+        assert_close(result.p_value, 0.3670013850902251, 1e-6);
+    }
+
+    #[test]
+    fn kolmogorov_smirnov_edge_cases() {
+        let result = kolmogorov_smirnov(&[1.0], &[2.0]);
+        assert_eq!(result.effect_size, 1.0);
+        assert_close(result.p_value, 0.2890414283708268, 1e-6);
+        let result = kolmogorov_smirnov(&[1.0, 2.0, 3.0], &[3.0, 2.0, 1.0]);
+        assert_eq!(result.effect_size, 0.0);
+        assert_eq!(result.p_value, 1.0);
+        assert_eq!(kolmogorov_smirnov(&[], &[1.0]), TestResult::NOT_TESTABLE);
+        assert_eq!(kolmogorov_smirnov(&[1.0], &[]), TestResult::NOT_TESTABLE);
+    }
+
+    #[test]
+    fn gaussian_mixture_separated_samples() {
+        // Treated reads all in the slow component, control reads all in the fast one
+        let treated: Vec<f64> = (0..10).map(|i| 20.0 + i as f64 * 0.1).collect();
+        let control: Vec<f64> = (0..10).map(|i| 2.0 + i as f64 * 0.01).collect();
+        let result = gaussian_mixture(&treated, &control);
+        // chi2.sf(20, df=1) for the table [[10, 0], [0, 10]]
+        assert_close(result.p_value, 7.744216431044074e-06, 1e-6);
+        assert_close(result.effect_size, 441f64.ln(), 1e-12);
+        // Swapping the samples flips the sign of the log odds ratio
+        let result = gaussian_mixture(&control, &treated);
+        assert_close(result.effect_size, -(441f64.ln()), 1e-12);
+    }
+
+    #[test]
+    fn gaussian_mixture_edge_cases() {
+        // Identical samples cannot be separated into two components
+        let result = gaussian_mixture(&[5.0; 6], &[5.0; 6]);
+        assert_eq!(result.p_value, 1.0);
+        assert_eq!(result.effect_size, 0.0);
+        assert_eq!(gaussian_mixture(&[], &[1.0]), TestResult::NOT_TESTABLE);
+        // Non-positive dwell has no logarithm and is left out
+        assert_eq!(
+            gaussian_mixture(&[0.0, -1.0], &[1.0]),
+            TestResult::NOT_TESTABLE
+        );
+    }
+
+    #[test]
+    fn benjamini_hochberg_reference_values() {
+        let q = benjamini_hochberg(&[0.01, 0.04, 0.03, 0.005]);
+        let expected = [0.02, 0.04, 0.04, 0.02];
+        for (q, expected) in q.iter().zip(expected) {
+            assert_close(*q, expected, 1e-12);
+        }
+        // Tied p-values share a q-value, and q-values are capped at 1
+        assert_eq!(benjamini_hochberg(&[0.5, 0.5, 0.9]), vec![0.75, 0.75, 0.9]);
+        assert_eq!(benjamini_hochberg(&[1.0, 1.0]), vec![1.0, 1.0]);
+        assert_eq!(benjamini_hochberg(&[0.2]), vec![0.2]);
+        assert!(benjamini_hochberg(&[]).is_empty());
+    }
+}
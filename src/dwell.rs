@@ -0,0 +1,664 @@
+use anyhow::Result;
+use rust_htslib::bam::{
+    self,
+    record::{Aux, Cigar},
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Unit used when reporting per-base dwell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DwellUnit {
+    /// Raw signal samples (move count x stride)
+    #[default]
+    Samples,
+    /// Milliseconds, requires a sample rate
+    Millis,
+}
+
+impl DwellUnit {
+    pub fn format(&self, value: f64) -> String {
+        match self {
+            DwellUnit::Samples => format!("{}", value.round() as i64),
+            DwellUnit::Millis => format!("{:.3}", value),
+        }
+    }
+}
+
+impl FromStr for DwellUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "samples" => Ok(DwellUnit::Samples),
+            "ms" => Ok(DwellUnit::Millis),
+            _ => Err(anyhow::anyhow!(
+                "Invalid dwell unit '{}', expected 'samples' or 'ms'",
+                s
+            )),
+        }
+    }
+}
+
+/// Within-read normalisation applied to per-base dwell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DwellNorm {
+    #[default]
+    None,
+    /// (dwell - mean) / std
+    ZScore,
+    /// dwell / median
+    MedianRatio,
+}
+
+impl FromStr for DwellNorm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(DwellNorm::None),
+            "zscore" => Ok(DwellNorm::ZScore),
+            "median" => Ok(DwellNorm::MedianRatio),
+            _ => Err(anyhow::anyhow!(
+                "Invalid dwell normalisation '{}', expected 'none', 'zscore' or 'median'",
+                s
+            )),
+        }
+    }
+}
+
+/// How reads whose move table does not match their bases are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MoveValidation {
+    /// Fail the run
+    Strict,
+    /// Warn and report no move-derived values for the read
+    #[default]
+    Lenient,
+}
+
+impl FromStr for MoveValidation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Ok(MoveValidation::Strict),
+            "lenient" => Ok(MoveValidation::Lenient),
+            _ => Err(anyhow::anyhow!(
+                "Invalid move table validation '{}', expected 'strict' or 'lenient'",
+                s
+            )),
+        }
+    }
+}
+
+/// Move table whose number of called bases does not match the read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MoveTableMismatch {
+    /// Bases called by the move table (number of `1` entries)
+    pub called_bases: usize,
+    /// Bases in `record.seq()`
+    pub read_bases: usize,
+    /// Bases removed by hard clips
+    pub hard_clipped: usize,
+}
+
+impl MoveTableMismatch {
+    /// Read bases, including hard clips, that got no move.
+    pub fn missing_bases(&self) -> usize {
+        (self.read_bases + self.hard_clipped).saturating_sub(self.called_bases)
+    }
+
+    /// Called bases beyond the end of the read, including hard clips.
+    pub fn extra_bases(&self) -> usize {
+        self.called_bases
+            .saturating_sub(self.read_bases + self.hard_clipped)
+    }
+}
+
+impl fmt::Display for MoveTableMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "move table calls {} bases, but the alignment has {} bases",
+            self.called_bases, self.read_bases
+        )?;
+        if self.hard_clipped > 0 {
+            write!(f, " plus {} hard-clipped", self.hard_clipped)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for MoveTableMismatch {}
+
+/// Decoded `mv` tag of a single read.
+#[derive(Debug, Clone)]
+pub struct MoveTable {
+    /// Number of signal samples represented by one move table entry
+    pub stride: i32,
+    /// Move table entries per base, in `record.seq()` orientation
+    pub counts: Vec<i32>,
+    /// Index of the first move table entry of each base, in `record.seq()` orientation
+    pub offsets: Vec<i32>,
+    /// Samples trimmed from the start of the raw signal (`ts` tag)
+    pub trimmed_samples: i64,
+    /// Total number of samples in the raw signal (`ns` tag)
+    pub num_samples: Option<i64>,
+    /// Read the signal was split from (`pi` tag)
+    pub parent_read_id: Option<String>,
+    /// First sample of this read within the parent read's signal (`sp` tag)
+    pub split_offset: i64,
+}
+
+impl MoveTable {
+    /// Parse the `mv` tag of `record`, returns None if the tag is missing or empty.
+    ///
+    /// The move table always covers the whole basecalled read, so bases removed by
+    /// hard clips (supplementary alignments) are skipped when they account for the
+    /// difference. Errors if the move table cannot be lined up with `record.seq()`.
+    ///
+    /// Direct RNA (`rna`) is sequenced 3'->5', so its signal runs against the
+    /// basecalled sequence rather than along it.
+    pub fn from_record(record: &bam::Record, rna: bool) -> Result<Option<Self>, MoveTableMismatch> {
+        let raw: Vec<i32> = match record.aux(b"mv") {
+            Ok(Aux::ArrayU8(val)) => val.iter().map(|x| x as i32).collect(),
+            Ok(Aux::ArrayI8(val)) => val.iter().map(|x| x as i32).collect(),
+            _ => return Ok(None),
+        };
+        let Some((&stride, moves)) = raw.split_first() else {
+            return Ok(None);
+        };
+        if moves.is_empty() {
+            return Ok(None);
+        }
+
+        let qlen = record.seq().len();
+        let called_bases = moves.iter().filter(|&&m| m == 1).count();
+        let (clip_first, clip_last) = hard_clips(record);
+        // seq() runs against the signal for reverse DNA reads and forward RNA reads
+        let signal_reversed = record.is_reverse() != rna;
+        // Hard-clipped bases at the start of the signal: the leading clip when
+        // seq() follows the signal, the trailing clip otherwise
+        let clip_signal_start = if signal_reversed {
+            clip_last
+        } else {
+            clip_first
+        };
+        let skip = if called_bases == qlen {
+            0
+        } else if clip_first + clip_last > 0 && called_bases == qlen + clip_first + clip_last {
+            clip_signal_start
+        } else {
+            return Err(MoveTableMismatch {
+                called_bases,
+                read_bases: qlen,
+                hard_clipped: clip_first + clip_last,
+            });
+        };
+
+        let mut counts = vec![0; qlen];
+        let mut offsets = vec![-1; qlen];
+        let mut base_idx: i64 = -1 - skip as i64;
+
+        for (move_idx, &move_val) in moves.iter().enumerate() {
+            if move_val == 1 {
+                base_idx += 1;
+                if base_idx >= 0 && (base_idx as usize) < qlen {
+                    offsets[base_idx as usize] = move_idx as i32;
+                }
+            }
+            if base_idx >= 0 && (base_idx as usize) < qlen {
+                counts[base_idx as usize] += 1;
+            }
+        }
+
+        // The move table follows the signal
+        if signal_reversed {
+            counts.reverse();
+            offsets.reverse();
+        }
+
+        // Reads split by dorado keep the parent read's signal, starting at sample `sp`
+        let parent_read_id = match record.aux(b"pi") {
+            Ok(Aux::String(pi)) => Some(pi.to_string()),
+            _ => None,
+        };
+        let split_offset = if parent_read_id.is_some() {
+            aux_int(record, b"sp").unwrap_or(0)
+        } else {
+            0
+        };
+
+        Ok(Some(Self {
+            stride,
+            counts,
+            offsets,
+            trimmed_samples: aux_int(record, b"ts").unwrap_or(0),
+            num_samples: aux_int(record, b"ns"),
+            parent_read_id,
+            split_offset,
+        }))
+    }
+
+    pub fn count(&self, query_pos: usize) -> Option<i32> {
+        self.counts.get(query_pos).copied()
+    }
+
+    /// Dwell of the base at `query_pos` in `unit`, None if it cannot be expressed in that unit.
+    pub fn dwell(
+        &self,
+        query_pos: usize,
+        unit: DwellUnit,
+        sample_rate: Option<f64>,
+    ) -> Option<f64> {
+        let samples = (self.count(query_pos)? * self.stride) as f64;
+        match unit {
+            DwellUnit::Samples => Some(samples),
+            DwellUnit::Millis => sample_rate.map(|rate| samples * 1000.0 / rate),
+        }
+    }
+
+    /// Per-base dwell normalised against the whole read, or against the `window`
+    /// bases centred on each base when `window` is non-zero. Bases whose
+    /// reference statistic is zero get None.
+    pub fn normalised(&self, norm: DwellNorm, window: usize) -> Vec<Option<f64>> {
+        let values: Vec<f64> = self.counts.iter().map(|&c| c as f64).collect();
+        let n = values.len();
+        let bounds = |i: usize| {
+            if window == 0 {
+                (0, n)
+            } else {
+                let half = window / 2;
+                (i.saturating_sub(half), (i + half + 1).min(n))
+            }
+        };
+
+        match norm {
+            DwellNorm::None => vec![None; n],
+            DwellNorm::ZScore => {
+                let mut sum = vec![0.0; n + 1];
+                let mut sum_sq = vec![0.0; n + 1];
+                for (i, v) in values.iter().enumerate() {
+                    sum[i + 1] = sum[i] + v;
+                    sum_sq[i + 1] = sum_sq[i] + v * v;
+                }
+                (0..n)
+                    .map(|i| {
+                        let (lo, hi) = bounds(i);
+                        let m = (hi - lo) as f64;
+                        let mean = (sum[hi] - sum[lo]) / m;
+                        let std = ((sum_sq[hi] - sum_sq[lo]) / m - mean * mean)
+                            .max(0.0)
+                            .sqrt();
+                        (std > 0.0).then(|| (values[i] - mean) / std)
+                    })
+                    .collect()
+            }
+            DwellNorm::MedianRatio => {
+                let mut whole_read = None;
+                (0..n)
+                    .map(|i| {
+                        let (lo, hi) = bounds(i);
+                        let med = if window == 0 {
+                            *whole_read.get_or_insert_with(|| {
+                                let mut sorted = values.clone();
+                                sorted.sort_by(|a, b| a.total_cmp(b));
+                                median(&sorted)
+                            })
+                        } else {
+                            let mut sorted = values[lo..hi].to_vec();
+                            sorted.sort_by(|a, b| a.total_cmp(b));
+                            median(&sorted)
+                        };
+                        (med > 0.0).then(|| values[i] / med)
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Half-open range of raw signal samples assigned to the base at `query_pos`,
+    /// in the parent read's signal for split reads.
+    pub fn signal_range(&self, query_pos: usize) -> Option<(u64, u64)> {
+        let offset = *self.offsets.get(query_pos)?;
+        if offset < 0 {
+            return None;
+        }
+        let start = self.split_offset + self.trimmed_samples + (offset * self.stride) as i64;
+        let mut end = start + (self.count(query_pos)? * self.stride) as i64;
+        if let Some(ns) = self.num_samples {
+            end = end.min(self.split_offset + ns);
+        }
+        if start < 0 || end < start {
+            return None;
+        }
+        Some((start as u64, end as u64))
+    }
+}
+
+/// Number of hard-clipped bases at the start and end of the alignment.
+fn hard_clips(record: &bam::Record) -> (usize, usize) {
+    let cigar = record.cigar();
+    let clip = |op: Option<&Cigar>| match op {
+        Some(Cigar::HardClip(len)) => *len as usize,
+        _ => 0,
+    };
+    let first = clip(cigar.first());
+    let last = if cigar.len() > 1 {
+        clip(cigar.last())
+    } else {
+        0
+    };
+    (first, last)
+}
+
+/// Read an integer aux tag regardless of the integer width it was stored with.
+pub fn aux_int(record: &bam::Record, tag: &[u8]) -> Option<i64> {
+    match record.aux(tag).ok()? {
+        Aux::I8(v) => Some(v as i64),
+        Aux::U8(v) => Some(v as i64),
+        Aux::I16(v) => Some(v as i64),
+        Aux::U16(v) => Some(v as i64),
+        Aux::I32(v) => Some(v as i64),
+        Aux::U32(v) => Some(v as i64),
+        _ => None,
+    }
+}
+
+/// Summary statistics of the dwell values observed at one position.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DwellSummary {
+    pub mean: f64,
+    pub median: f64,
+    /// Population standard deviation
+    pub std: f64,
+    /// Median absolute deviation from the median (unscaled)
+    pub mad: f64,
+    pub count: usize,
+}
+
+/// Median of values sorted in ascending order.
+pub(crate) fn median(sorted: &[f64]) -> f64 {
+    let n = sorted.len();
+    if n % 2 == 1 {
+        sorted[n / 2]
+    } else {
+        (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0
+    }
+}
+
+impl DwellSummary {
+    /// Summarise `values`, reordering them in place. None if there are no values.
+    pub fn from_values(values: &mut [f64]) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(|a, b| a.total_cmp(b));
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let var = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+        let med = median(values);
+        let mut deviations: Vec<f64> = values.iter().map(|v| (v - med).abs()).collect();
+        deviations.sort_by(|a, b| a.total_cmp(b));
+        Some(Self {
+            mean,
+            median: med,
+            std: var.sqrt(),
+            mad: median(&deviations),
+            count: values.len(),
+        })
+    }
+}
+
+impl fmt::Display for DwellSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.3}:{:.3}:{:.3}:{:.3}:{}",
+            self.mean, self.median, self.std, self.mad, self.count
+        )
+    }
+}
+
+/// Per-strand dwell summaries of one pileup position.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct StrandDwellSummary {
+    pub forward: Option<DwellSummary>,
+    pub reverse: Option<DwellSummary>,
+}
+
+/// Collect `sample_rate=<Hz>` entries from the DS field of every @RG header line, keyed by RG ID.
+pub fn read_group_sample_rates(header: &bam::HeaderView) -> HashMap<String, f64> {
+    let mut rates = HashMap::new();
+    let text = String::from_utf8_lossy(header.as_bytes());
+    for line in text.lines().filter(|l| l.starts_with("@RG")) {
+        let mut id = None;
+        let mut rate = None;
+        for field in line.split('\t').skip(1) {
+            if let Some(v) = field.strip_prefix("ID:") {
+                id = Some(v.to_string());
+            } else if let Some(ds) = field.strip_prefix("DS:") {
+                rate = ds
+                    .split_whitespace()
+                    .find_map(|kv| kv.strip_prefix("sample_rate="))
+                    .and_then(|v| v.parse::<f64>().ok());
+            }
+        }
+        if let (Some(id), Some(rate)) = (id, rate) {
+            rates.insert(id, rate);
+        }
+    }
+    rates
+}
+
+/// Sample rate of the read group `record` belongs to.
+/// Reads without an RG tag fall back to the header rate if there is exactly one.
+pub fn record_sample_rate(record: &bam::Record, rates: &HashMap<String, f64>) -> Option<f64> {
+    match record.aux(b"RG") {
+        Ok(Aux::String(rg)) => rates.get(rg).copied(),
+        _ if rates.len() == 1 => rates.values().next().copied(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{AuxArray, CigarString};
+
+    /// A read of `qlen` bases with `mv` holding the stride followed by the moves.
+    fn record(qlen: usize, clips: (u32, u32), is_reverse: bool, mv: &[u8]) -> bam::Record {
+        let mut cigar = Vec::new();
+        if clips.0 > 0 {
+            cigar.push(Cigar::HardClip(clips.0));
+        }
+        cigar.push(Cigar::Match(qlen as u32));
+        if clips.1 > 0 {
+            cigar.push(Cigar::HardClip(clips.1));
+        }
+        let mut record = bam::Record::new();
+        record.set(
+            b"read1",
+            Some(&CigarString(cigar)),
+            &vec![b'A'; qlen],
+            &vec![30; qlen],
+        );
+        if is_reverse {
+            record.set_reverse();
+        }
+        record
+            .push_aux(b"mv", Aux::ArrayU8(AuxArray::from(mv)))
+            .unwrap();
+        record
+    }
+
+    fn move_table(record: &bam::Record, rna: bool) -> MoveTable {
+        MoveTable::from_record(record, rna).unwrap().unwrap()
+    }
+
+    #[test]
+    fn counts_and_offsets() {
+        let mt = move_table(&record(3, (0, 0), false, &[5, 1, 0, 1, 1, 0, 0]), false);
+        assert_eq!(mt.stride, 5);
+        assert_eq!(mt.counts, [2, 1, 3]);
+        assert_eq!(mt.offsets, [0, 2, 3]);
+        assert_eq!(mt.dwell(2, DwellUnit::Samples, None), Some(15.0));
+        assert_eq!(mt.dwell(2, DwellUnit::Millis, Some(5000.0)), Some(3.0));
+        assert_eq!(mt.dwell(2, DwellUnit::Millis, None), None);
+        assert_eq!(mt.dwell(3, DwellUnit::Samples, None), None);
+
+        let mut untagged = bam::Record::new();
+        untagged.set(b"read1", None, b"AAA", &[30; 3]);
+        assert!(MoveTable::from_record(&untagged, false).unwrap().is_none());
+        let stride_only = record(3, (0, 0), false, &[5]);
+        assert!(
+            MoveTable::from_record(&stride_only, false)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn signal_orientation() {
+        let moves = [5, 1, 0, 1, 1, 0, 0];
+        // seq() runs against the signal for reverse DNA and forward RNA reads
+        for (is_reverse, rna, reversed) in [
+            (false, false, false),
+            (true, false, true),
+            (false, true, true),
+            (true, true, false),
+        ] {
+            let mt = move_table(&record(3, (0, 0), is_reverse, &moves), rna);
+            let (counts, offsets) = if reversed {
+                ([3, 1, 2], [3, 2, 0])
+            } else {
+                ([2, 1, 3], [0, 2, 3])
+            };
+            assert_eq!(mt.counts, counts, "reverse {} rna {}", is_reverse, rna);
+            assert_eq!(mt.offsets, offsets, "reverse {} rna {}", is_reverse, rna);
+        }
+    }
+
+    #[test]
+    fn hard_clipped_bases_are_skipped() {
+        // Five called bases of 1, 2, 3, 1 and 1 moves; the alignment keeps three
+        let moves = [5, 1, 1, 0, 1, 0, 0, 1, 1];
+        let forward = move_table(&record(3, (2, 0), false, &moves), false);
+        assert_eq!(forward.counts, [3, 1, 1]);
+        assert_eq!(forward.offsets, [3, 6, 7]);
+        // The leading clip of a reverse read is at the end of its signal
+        let reverse = move_table(&record(3, (2, 0), true, &moves), false);
+        assert_eq!(reverse.counts, [3, 2, 1]);
+        assert_eq!(reverse.offsets, [3, 1, 0]);
+        let reverse = move_table(&record(3, (0, 2), true, &moves), false);
+        assert_eq!(reverse.counts, [1, 1, 3]);
+        assert_eq!(reverse.offsets, [7, 6, 3]);
+    }
+
+    #[test]
+    fn mismatched_move_tables() {
+        let err =
+            MoveTable::from_record(&record(3, (0, 0), false, &[5, 1, 1, 1, 1]), false).unwrap_err();
+        assert_eq!(
+            err,
+            MoveTableMismatch {
+                called_bases: 4,
+                read_bases: 3,
+                hard_clipped: 0
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "move table calls 4 bases, but the alignment has 3 bases"
+        );
+        assert_eq!((err.missing_bases(), err.extra_bases()), (0, 1));
+
+        // Clips only explain an exact match
+        let err =
+            MoveTable::from_record(&record(3, (1, 1), false, &[5, 1, 1, 1, 1]), false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "move table calls 4 bases, but the alignment has 3 bases plus 2 hard-clipped"
+        );
+        assert_eq!((err.missing_bases(), err.extra_bases()), (1, 0));
+    }
+
+    #[test]
+    fn signal_ranges() {
+        let moves = [5, 1, 0, 1, 1, 0, 0];
+        let mut record = record(3, (0, 0), false, &moves);
+        record.push_aux(b"ts", Aux::U8(7)).unwrap();
+        // Without a parent read `sp` is ignored
+        record.push_aux(b"sp", Aux::U16(1000)).unwrap();
+        let mt = move_table(&record, false);
+        let ranges: Vec<_> = (0..4).map(|q| mt.signal_range(q)).collect();
+        assert_eq!(
+            ranges,
+            [Some((7, 17)), Some((17, 22)), Some((22, 37)), None]
+        );
+
+        record.push_aux(b"pi", Aux::String("parent")).unwrap();
+        record.push_aux(b"ns", Aux::U8(30)).unwrap();
+        let mt = move_table(&record, false);
+        assert_eq!(mt.parent_read_id.as_deref(), Some("parent"));
+        assert_eq!(mt.signal_range(0), Some((1007, 1017)));
+        // The last base is cut at the end of the read's signal
+        assert_eq!(mt.signal_range(2), Some((1022, 1030)));
+    }
+
+    fn assert_close(actual: &[Option<f64>], expected: &[Option<f64>]) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (a, e) in actual.iter().zip(expected) {
+            match (a, e) {
+                (Some(a), Some(e)) => assert!((a - e).abs() < 1e-12, "{:?}", actual),
+                _ => assert_eq!(a, e, "{:?}", actual),
+            }
+        }
+    }
+
+    #[test]
+    fn normalisation() {
+        let table = |counts: Vec<i32>| MoveTable {
+            stride: 5,
+            offsets: vec![0; counts.len()],
+            counts,
+            trimmed_samples: 0,
+            num_samples: None,
+            parent_read_id: None,
+            split_offset: 0,
+        };
+        let mt = table(vec![1, 2, 3, 6]);
+        assert_eq!(mt.normalised(DwellNorm::None, 0), [None; 4]);
+
+        let std = 3.5f64.sqrt();
+        assert_close(
+            &mt.normalised(DwellNorm::ZScore, 0),
+            &[-2.0, -1.0, 0.0, 3.0].map(|d| Some(d / std)),
+        );
+        assert_close(
+            &mt.normalised(DwellNorm::MedianRatio, 0),
+            &[Some(0.4), Some(0.8), Some(1.2), Some(2.4)],
+        );
+        // Windows of three bases, cut at the ends of the read
+        assert_close(
+            &mt.normalised(DwellNorm::MedianRatio, 3),
+            &[Some(1.0 / 1.5), Some(1.0), Some(1.0), Some(6.0 / 4.5)],
+        );
+        assert_close(
+            &mt.normalised(DwellNorm::ZScore, 3),
+            &[Some(-1.0), Some(0.0), Some(-2.0 / 26f64.sqrt()), Some(1.0)],
+        );
+
+        // No spread or a zero median gives no value
+        assert_eq!(
+            table(vec![2, 2]).normalised(DwellNorm::ZScore, 0),
+            [None; 2]
+        );
+        assert_eq!(
+            table(vec![0, 0, 1]).normalised(DwellNorm::MedianRatio, 0),
+            [None, None, None]
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use crate::dwell::DwellUnit;
+use crate::nanopileup::PileupPos;
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+pub mod arrow;
+
+use arrow::{ArrowWriter, ColumnData, ColumnType};
+
+// Rows buffered per Arrow record batch
+const BATCH_ROWS: usize = 65536;
+
+const COLUMNS: [(&str, ColumnType); 10] = [
+    ("chrom", ColumnType::Utf8),
+    ("pos", ColumnType::Int64),
+    ("read_name", ColumnType::Utf8),
+    ("strand", ColumnType::Utf8),
+    ("query_pos", ColumnType::Int64),
+    ("base", ColumnType::Utf8),
+    ("base_qual", ColumnType::Int64),
+    ("dwell", ColumnType::Float64),
+    ("kmer", ColumnType::Utf8),
+    ("mapq", ColumnType::Int64),
+];
+
+/// File format of the long-format export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    #[default]
+    Tsv,
+    /// Arrow IPC file, readable as Feather v2
+    Arrow,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "tsv" => Ok(ExportFormat::Tsv),
+            "arrow" | "feather" => Ok(ExportFormat::Arrow),
+            _ => Err(anyhow::anyhow!(
+                "Invalid export format '{}', expected 'tsv' or 'arrow'",
+                s
+            )),
+        }
+    }
+}
+
+/// One row per (read, reference position), built from `PileupPos::observations`.
+#[derive(Default)]
+struct Rows {
+    chrom: Vec<Option<String>>,
+    pos: Vec<i64>,
+    read_name: Vec<Option<String>>,
+    strand: Vec<Option<String>>,
+    query_pos: Vec<i64>,
+    base: Vec<Option<String>>,
+    base_qual: Vec<i64>,
+    dwell: Vec<Option<f64>>,
+    kmer: Vec<Option<String>>,
+    mapq: Vec<i64>,
+}
+
+impl Rows {
+    fn len(&self) -> usize {
+        self.pos.len()
+    }
+
+    fn take_columns(&mut self) -> Vec<ColumnData> {
+        let rows = std::mem::take(self);
+        vec![
+            ColumnData::Utf8(rows.chrom),
+            ColumnData::Int64(rows.pos),
+            ColumnData::Utf8(rows.read_name),
+            ColumnData::Utf8(rows.strand),
+            ColumnData::Int64(rows.query_pos),
+            ColumnData::Utf8(rows.base),
+            ColumnData::Int64(rows.base_qual),
+            ColumnData::Float64(rows.dwell),
+            ColumnData::Utf8(rows.kmer),
+            ColumnData::Int64(rows.mapq),
+        ]
+    }
+}
+
+enum Sink {
+    Tsv(BufWriter<File>),
+    Arrow(Box<ArrowWriter<BufWriter<File>>>, Box<Rows>),
+}
+
+/// Writes the per-read, per-position long-format table.
+pub struct ExportWriter {
+    sink: Sink,
+    dwell_unit: DwellUnit,
+}
+
+impl ExportWriter {
+    /// Dwell is written in `dwell_unit`, formatted as in the pileup output for TSV.
+    pub fn create(path: &Path, format: ExportFormat, dwell_unit: DwellUnit) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create export file '{}'", path.display()))?;
+        let mut out = BufWriter::new(file);
+        let sink = match format {
+            ExportFormat::Tsv => {
+                let header: Vec<&str> = COLUMNS.iter().map(|(name, _)| *name).collect();
+                writeln!(out, "{}", header.join("\t"))?;
+                Sink::Tsv(out)
+            }
+            ExportFormat::Arrow => {
+                let schema = COLUMNS
+                    .iter()
+                    .map(|(name, column_type)| (name.to_string(), *column_type))
+                    .collect();
+                Sink::Arrow(Box::new(ArrowWriter::new(out, schema)?), Box::default())
+            }
+        };
+        Ok(Self { sink, dwell_unit })
+    }
+
+    /// Write one row for every read observed at `p`.
+    pub fn write_position(&mut self, p: &PileupPos) -> Result<()> {
+        let Some(observations) = &p.observations else {
+            return Ok(());
+        };
+        for obs in observations {
+            // The k-mer in the orientation the read passed through the pore
+            let kmer = if obs.is_reverse {
+                p.kmer_reverse.as_deref()
+            } else {
+                p.kmer_forward.as_deref()
+            };
+            let strand = if obs.is_reverse { "-" } else { "+" };
+            match &mut self.sink {
+                Sink::Tsv(out) => writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    p.chrom,
+                    p.pos + 1, // 1-based output
+                    obs.read_name,
+                    strand,
+                    obs.query_pos,
+                    obs.base,
+                    obs.qual,
+                    obs.dwell
+                        .map_or("NA".to_string(), |d| self.dwell_unit.format(d)),
+                    kmer.unwrap_or("NA"),
+                    obs.mapq
+                )?,
+                Sink::Arrow(_, rows) => {
+                    rows.chrom.push(Some(p.chrom.clone()));
+                    rows.pos.push(p.pos as i64 + 1);
+                    rows.read_name.push(Some(obs.read_name.clone()));
+                    rows.strand.push(Some(strand.to_string()));
+                    rows.query_pos.push(obs.query_pos as i64);
+                    rows.base.push(Some(obs.base.to_string()));
+                    rows.base_qual.push(obs.qual as i64);
+                    rows.dwell.push(obs.dwell);
+                    rows.kmer.push(kmer.map(str::to_string));
+                    rows.mapq.push(obs.mapq as i64);
+                }
+            }
+        }
+        if let Sink::Arrow(writer, rows) = &mut self.sink
+            && rows.len() >= BATCH_ROWS
+        {
+            writer.write_batch(rows.take_columns())?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self.sink {
+            Sink::Tsv(mut out) => out.flush()?,
+            Sink::Arrow(mut writer, mut rows) => {
+                if rows.len() > 0 {
+                    writer.write_batch(rows.take_columns())?;
+                }
+                writer.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nanopileup::{PileupOptions, ReadObservation};
+
+    fn export_tsv(dwell_unit: DwellUnit) -> Vec<String> {
+        let options = PileupOptions {
+            keep_observations: true,
+            ..PileupOptions::default()
+        };
+        let mut p = PileupPos::new("chr1".to_string(), 9, 'A', &options, false);
+        p.kmer_forward = Some("CAG".to_string());
+        let observation = |read_name: &str, is_reverse, dwell| ReadObservation {
+            read_name: read_name.to_string(),
+            is_reverse,
+            query_pos: 4,
+            base: 'A',
+            qual: 30,
+            dwell,
+            mapq: 60,
+        };
+        p.observations = Some(vec![
+            observation("read1", false, Some(12.4567)),
+            observation("read2", true, None),
+        ]);
+
+        let path = std::env::temp_dir().join(format!(
+            "nanopile-test-{}-{:?}.tsv",
+            std::process::id(),
+            dwell_unit
+        ));
+        let mut writer = ExportWriter::create(&path, ExportFormat::Tsv, dwell_unit).unwrap();
+        writer.write_position(&p).unwrap();
+        writer.finish().unwrap();
+        let lines = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        lines.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn tsv_dwell_follows_the_unit() {
+        let lines = export_tsv(DwellUnit::Samples);
+        assert_eq!(
+            lines,
+            [
+                "chrom\tpos\tread_name\tstrand\tquery_pos\tbase\tbase_qual\tdwell\tkmer\tmapq",
+                "chr1\t10\tread1\t+\t4\tA\t30\t12\tCAG\t60",
+                "chr1\t10\tread2\t-\t4\tA\t30\tNA\tNA\t60",
+            ]
+        );
+        let lines = export_tsv(DwellUnit::Millis);
+        assert_eq!(lines[1], "chr1\t10\tread1\t+\t4\tA\t30\t12.457\tCAG\t60");
+    }
+}
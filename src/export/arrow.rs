@@ -0,0 +1,198 @@
+//! Arrow IPC file (Feather v2) output for the flat int64, float64 and utf8
+//! columns of the long-format export.
+
+use anyhow::{Context, Result};
+use arrow_array::{ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use std::io::Write;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Int64,
+    Float64,
+    Utf8,
+}
+
+impl ColumnType {
+    /// Int64 columns never hold nulls, the others may.
+    fn field(self, name: &str) -> Field {
+        match self {
+            ColumnType::Int64 => Field::new(name, DataType::Int64, false),
+            ColumnType::Float64 => Field::new(name, DataType::Float64, true),
+            ColumnType::Utf8 => Field::new(name, DataType::Utf8, true),
+        }
+    }
+}
+
+/// Values of one column of a record batch; None values are written as nulls.
+pub enum ColumnData {
+    Int64(Vec<i64>),
+    Float64(Vec<Option<f64>>),
+    Utf8(Vec<Option<String>>),
+}
+
+impl ColumnData {
+    fn into_array(self) -> ArrayRef {
+        match self {
+            ColumnData::Int64(v) => Arc::new(Int64Array::from(v)),
+            ColumnData::Float64(v) => Arc::new(Float64Array::from(v)),
+            ColumnData::Utf8(v) => Arc::new(StringArray::from(v)),
+        }
+    }
+}
+
+/// Writes record batches of a fixed schema as an Arrow IPC file.
+pub struct ArrowWriter<W: Write> {
+    writer: FileWriter<W>,
+    schema: SchemaRef,
+}
+
+impl<W: Write> ArrowWriter<W> {
+    pub fn new(out: W, schema: Vec<(String, ColumnType)>) -> Result<Self> {
+        let schema = Arc::new(Schema::new(
+            schema
+                .iter()
+                .map(|(name, column_type)| column_type.field(name))
+                .collect::<Vec<_>>(),
+        ));
+        let writer =
+            FileWriter::try_new(out, &schema).context("Failed to write the Arrow schema")?;
+        Ok(Self { writer, schema })
+    }
+
+    /// Append one record batch; `columns` must follow the schema.
+    pub fn write_batch(&mut self, columns: Vec<ColumnData>) -> Result<()> {
+        if columns.len() != self.schema.fields().len() {
+            return Err(anyhow::anyhow!(
+                "Record batch has {} columns but the schema has {}",
+                columns.len(),
+                self.schema.fields().len()
+            ));
+        }
+        let columns = columns.into_iter().map(ColumnData::into_array).collect();
+        let batch = RecordBatch::try_new(Arc::clone(&self.schema), columns)
+            .context("Record batch does not follow the schema")?;
+        self.writer
+            .write(&batch)
+            .context("Failed to write an Arrow record batch")
+    }
+
+    /// Write the end-of-stream marker and the footer.
+    pub fn finish(mut self) -> Result<W> {
+        self.writer
+            .finish()
+            .context("Failed to write the Arrow footer")?;
+        let mut out = self.writer.into_inner()?;
+        out.flush()?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Array, Float64Array, Int64Array, StringArray};
+    use arrow_ipc::reader::FileReader;
+    use std::io::Cursor;
+
+    fn schema() -> Vec<(String, ColumnType)> {
+        vec![
+            ("chrom".to_string(), ColumnType::Utf8),
+            ("pos".to_string(), ColumnType::Int64),
+            ("dwell".to_string(), ColumnType::Float64),
+        ]
+    }
+
+    fn read(bytes: Vec<u8>) -> FileReader<Cursor<Vec<u8>>> {
+        FileReader::try_new(Cursor::new(bytes), None).expect("valid Arrow IPC file")
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut writer = ArrowWriter::new(Vec::new(), schema()).unwrap();
+        writer
+            .write_batch(vec![
+                ColumnData::Utf8(vec![Some("chr1".to_string()), None, Some(String::new())]),
+                ColumnData::Int64(vec![1, -2, i64::MAX]),
+                ColumnData::Float64(vec![Some(0.5), Some(-1e-3), None]),
+            ])
+            .unwrap();
+        // Nulls past the first byte of the validity bitmap
+        let dwell: Vec<Option<f64>> = (0..11).map(|i| (i % 9 != 0).then_some(i as f64)).collect();
+        writer
+            .write_batch(vec![
+                ColumnData::Utf8((0..11).map(|i| Some(format!("read{}", i))).collect()),
+                ColumnData::Int64((0..11).collect()),
+                ColumnData::Float64(dwell.clone()),
+            ])
+            .unwrap();
+        let reader = read(writer.finish().unwrap());
+
+        let schema = reader.schema();
+        let fields: Vec<(&str, bool)> = schema
+            .fields()
+            .iter()
+            .map(|f| (f.name().as_str(), f.is_nullable()))
+            .collect();
+        assert_eq!(fields, [("chrom", true), ("pos", false), ("dwell", true)]);
+
+        let batches: Vec<_> = reader.map(|b| b.unwrap()).collect();
+        assert_eq!(batches.len(), 2);
+        let column = |b: usize, c: usize| batches[b].column(c).clone();
+
+        let chrom = column(0, 0);
+        let chrom = chrom.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(
+            chrom.iter().collect::<Vec<_>>(),
+            [Some("chr1"), None, Some("")]
+        );
+        let pos = column(0, 1);
+        let pos = pos.as_any().downcast_ref::<Int64Array>().unwrap();
+        assert_eq!(pos.values().as_ref(), [1, -2, i64::MAX]);
+        assert_eq!(pos.null_count(), 0);
+        let dwell_0 = column(0, 2);
+        let dwell_0 = dwell_0.as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(
+            dwell_0.iter().collect::<Vec<_>>(),
+            [Some(0.5), Some(-1e-3), None]
+        );
+
+        let chrom = column(1, 0);
+        let chrom = chrom.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(chrom.value(10), "read10");
+        let dwell_1 = column(1, 2);
+        let dwell_1 = dwell_1.as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(dwell_1.null_count(), 2);
+        assert_eq!(dwell_1.iter().collect::<Vec<_>>(), dwell);
+    }
+
+    #[test]
+    fn no_batches() {
+        let writer = ArrowWriter::new(Vec::new(), schema()).unwrap();
+        let reader = read(writer.finish().unwrap());
+        assert_eq!(reader.schema().fields().len(), 3);
+        assert_eq!(reader.num_batches(), 0);
+    }
+
+    #[test]
+    fn batch_must_follow_schema() {
+        let mut writer = ArrowWriter::new(Vec::new(), schema()).unwrap();
+        let err = writer
+            .write_batch(vec![ColumnData::Int64(vec![1])])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Record batch has 1 columns but the schema has 3"
+        );
+        let err = writer
+            .write_batch(vec![
+                ColumnData::Int64(vec![1]),
+                ColumnData::Int64(vec![2]),
+                ColumnData::Float64(vec![None]),
+            ])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Record batch does not follow the schema");
+    }
+}
@@ -0,0 +1,626 @@
+use anyhow::{Result, bail};
+use regex::Regex;
+use rust_htslib::bam::{self, record::Aux, record::Cigar};
+use std::fmt;
+use std::str::FromStr;
+
+/// Boolean read filter in the style of `samtools view -e`, e.g.
+/// `[qs] >= 10 && !flag.supplementary && [RG] == "run2"`.
+///
+/// Operands are numbers, quoted strings, aux tags written as `[XX]` and the
+/// record fields listed in `Field`. A missing tag is null: comparisons with it
+/// are false, and so is the tag on its own.
+#[derive(Debug, Clone)]
+pub struct FilterExpr {
+    source: String,
+    expr: Expr,
+}
+
+impl FromStr for FilterExpr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let tokens =
+            tokenize(s).map_err(|e| anyhow::anyhow!("Invalid expression '{}': {}", s, e))?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser
+            .parse_expr(0)
+            .and_then(|expr| match parser.peek() {
+                None => Ok(expr),
+                Some(token) => bail!("unexpected {}", token),
+            })
+            .map_err(|e| anyhow::anyhow!("Invalid expression '{}': {}", s, e))?;
+        Ok(Self {
+            source: s.to_string(),
+            expr,
+        })
+    }
+}
+
+impl fmt::Display for FilterExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl FilterExpr {
+    /// Whether `record` passes the expression.
+    pub fn matches(&self, record: &bam::Record) -> bool {
+        self.expr.eval(record).is_true()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(f64),
+    Str(String),
+    Tag([u8; 2]),
+    Ident(String),
+    Op(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "number {}", n),
+            Token::Str(s) => write!(f, "string \"{}\"", s),
+            Token::Tag(t) => write!(f, "tag [{}]", String::from_utf8_lossy(t)),
+            Token::Ident(i) => write!(f, "'{}'", i),
+            Token::Op(op) => write!(f, "'{}'", op),
+        }
+    }
+}
+
+// Longer operators first so that e.g. `<=` is not read as `<`
+const OPERATORS: [&str; 19] = [
+    "||", "&&", "==", "!=", "<=", ">=", "=~", "!~", "<", ">", "+", "-", "*", "/", "!", "&", "|",
+    "(", ")",
+];
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).is_some_and(char::is_ascii_digit))
+        {
+            let start = i;
+            let hex = c == '0' && chars.get(i + 1).is_some_and(|x| matches!(x, 'x' | 'X'));
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric()
+                    || chars[i] == '.'
+                    // Sign of a decimal exponent, as in `1e-3`
+                    || (!hex && matches!(chars[i], '+' | '-') && matches!(chars[i - 1], 'e' | 'E')))
+            {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let value = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+                Some(hex) => i64::from_str_radix(hex, 16).map(|v| v as f64).ok(),
+                None => text.parse::<f64>().ok(),
+            };
+            match value {
+                Some(v) => tokens.push(Token::Num(v)),
+                None => bail!("invalid number '{}'", text),
+            }
+        } else if c == '"' || c == '\'' {
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => bail!("unterminated string"),
+                    Some(&q) if q == c => break,
+                    Some('\\') => {
+                        match chars.get(i + 1) {
+                            Some('t') => value.push('\t'),
+                            Some('n') => value.push('\n'),
+                            Some(&e) => value.push(e),
+                            None => bail!("unterminated string"),
+                        }
+                        i += 1;
+                    }
+                    Some(&ch) => value.push(ch),
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Token::Str(value));
+        } else if c == '[' {
+            match chars.get(i + 1..i + 4) {
+                Some(&[a, b, ']']) if a.is_ascii_alphanumeric() && b.is_ascii_alphanumeric() => {
+                    tokens.push(Token::Tag([a as u8, b as u8]));
+                    i += 4;
+                }
+                _ => bail!("tags must be written as [XX]"),
+            }
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || "_.".contains(chars[i])) {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) else {
+                bail!("unexpected character '{}'", c);
+            };
+            tokens.push(Token::Op(op));
+            i += op.len();
+        }
+    }
+    Ok(tokens)
+}
+
+/// Record fields that can be used in an expression.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    /// `flag`, the whole FLAG value
+    Flag,
+    /// `flag.<name>`, one FLAG bit
+    FlagBit(u16),
+    Mapq,
+    /// 1-based alignment start
+    Pos,
+    /// 1-based alignment end
+    EndPos,
+    /// 1-based mate start
+    MatePos,
+    TLen,
+    /// Query length, i.e. length of SEQ
+    QLen,
+    /// Reference length of the alignment
+    RLen,
+    QName,
+    Seq,
+    NCigar,
+    /// Soft-clipped bases
+    SClen,
+    /// Hard-clipped bases
+    HClen,
+}
+
+impl FromStr for Field {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let field = match s {
+            "flag" => Field::Flag,
+            "mapq" => Field::Mapq,
+            "pos" => Field::Pos,
+            "endpos" => Field::EndPos,
+            "mpos" => Field::MatePos,
+            "tlen" => Field::TLen,
+            "qlen" => Field::QLen,
+            "rlen" => Field::RLen,
+            "qname" => Field::QName,
+            "seq" => Field::Seq,
+            "ncigar" => Field::NCigar,
+            "sclen" => Field::SClen,
+            "hclen" => Field::HClen,
+            _ => {
+                let bit = match s.strip_prefix("flag.") {
+                    Some("paired") => 0x1,
+                    Some("proper_pair") => 0x2,
+                    Some("unmap") => 0x4,
+                    Some("munmap") => 0x8,
+                    Some("reverse") => 0x10,
+                    Some("mreverse") => 0x20,
+                    Some("read1") => 0x40,
+                    Some("read2") => 0x80,
+                    Some("secondary") => 0x100,
+                    Some("qcfail") => 0x200,
+                    Some("dup") => 0x400,
+                    Some("supplementary") => 0x800,
+                    _ => bail!("unknown field '{}'", s),
+                };
+                Field::FlagBit(bit)
+            }
+        };
+        Ok(field)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinOp {
+    Or,
+    And,
+    BitOr,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+// Binary operators from lowest to highest precedence, as in C
+const PRECEDENCE: [&[(&str, BinOp)]; 8] = [
+    &[("||", BinOp::Or)],
+    &[("&&", BinOp::And)],
+    &[("|", BinOp::BitOr)],
+    &[("&", BinOp::BitAnd)],
+    &[("==", BinOp::Eq), ("!=", BinOp::Ne)],
+    &[
+        ("<", BinOp::Lt),
+        ("<=", BinOp::Le),
+        (">", BinOp::Gt),
+        (">=", BinOp::Ge),
+    ],
+    &[("+", BinOp::Add), ("-", BinOp::Sub)],
+    &[("*", BinOp::Mul), ("/", BinOp::Div)],
+];
+
+// Regex matches bind like equality
+const MATCH_LEVEL: usize = 4;
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Num(f64),
+    Str(String),
+    Tag([u8; 2]),
+    Field(Field),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Match {
+        expr: Box<Expr>,
+        regex: Regex,
+        negate: bool,
+    },
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token.ok_or_else(|| anyhow::anyhow!("unexpected end of expression"))
+    }
+
+    fn parse_expr(&mut self, level: usize) -> Result<Expr> {
+        if level == PRECEDENCE.len() {
+            return self.parse_unary();
+        }
+        let mut lhs = self.parse_expr(level + 1)?;
+        while let Some(&Token::Op(op)) = self.peek() {
+            if level == MATCH_LEVEL && (op == "=~" || op == "!~") {
+                self.pos += 1;
+                let Token::Str(pattern) = self.next()? else {
+                    bail!("'{}' must be followed by a quoted regular expression", op);
+                };
+                let regex = Regex::new(&pattern)
+                    .map_err(|e| anyhow::anyhow!("invalid regular expression: {}", e))?;
+                lhs = Expr::Match {
+                    expr: Box::new(lhs),
+                    regex,
+                    negate: op == "!~",
+                };
+                continue;
+            }
+            let Some(&(_, bin_op)) = PRECEDENCE[level].iter().find(|(s, _)| *s == op) else {
+                break;
+            };
+            self.pos += 1;
+            let rhs = self.parse_expr(level + 1)?;
+            lhs = Expr::Binary(bin_op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        match self.next()? {
+            Token::Op("!") => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Token::Op("-") => Ok(Expr::Neg(Box::new(self.parse_unary()?))),
+            Token::Op("(") => {
+                let expr = self.parse_expr(0)?;
+                match self.next()? {
+                    Token::Op(")") => Ok(expr),
+                    token => bail!("expected ')' but found {}", token),
+                }
+            }
+            Token::Num(n) => Ok(Expr::Num(n)),
+            Token::Str(s) => Ok(Expr::Str(s)),
+            Token::Tag(tag) => Ok(Expr::Tag(tag)),
+            Token::Ident(name) => Ok(Expr::Field(name.parse()?)),
+            token => bail!("unexpected {}", token),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Num(f64),
+    Str(String),
+}
+
+impl Value {
+    fn is_true(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Num(n) => *n != 0.0,
+            Value::Str(_) => true,
+        }
+    }
+
+    fn from_bool(b: bool) -> Self {
+        Value::Num(if b { 1.0 } else { 0.0 })
+    }
+}
+
+fn tag_value(record: &bam::Record, tag: &[u8; 2]) -> Value {
+    match record.aux(tag) {
+        Ok(Aux::Char(c)) => Value::Str((c as char).to_string()),
+        Ok(Aux::String(s)) | Ok(Aux::HexByteArray(s)) => Value::Str(s.to_string()),
+        Ok(Aux::I8(v)) => Value::Num(v as f64),
+        Ok(Aux::U8(v)) => Value::Num(v as f64),
+        Ok(Aux::I16(v)) => Value::Num(v as f64),
+        Ok(Aux::U16(v)) => Value::Num(v as f64),
+        Ok(Aux::I32(v)) => Value::Num(v as f64),
+        Ok(Aux::U32(v)) => Value::Num(v as f64),
+        Ok(Aux::Float(v)) => Value::Num(v as f64),
+        Ok(Aux::Double(v)) => Value::Num(v),
+        _ => Value::Null,
+    }
+}
+
+fn clipped_bases(record: &bam::Record, soft: bool) -> u32 {
+    record
+        .cigar()
+        .iter()
+        .map(|op| match op {
+            Cigar::SoftClip(n) if soft => *n,
+            Cigar::HardClip(n) if !soft => *n,
+            _ => 0,
+        })
+        .sum()
+}
+
+fn field_value(record: &bam::Record, field: Field) -> Value {
+    let num = match field {
+        Field::Flag => record.flags() as f64,
+        Field::FlagBit(bit) => (record.flags() & bit != 0) as u8 as f64,
+        Field::Mapq => record.mapq() as f64,
+        Field::Pos => (record.pos() + 1) as f64,
+        Field::EndPos => record.cigar().end_pos() as f64,
+        Field::MatePos => (record.mpos() + 1) as f64,
+        Field::TLen => record.insert_size() as f64,
+        Field::QLen => record.seq_len() as f64,
+        Field::RLen => (record.cigar().end_pos() - record.pos()) as f64,
+        Field::NCigar => record.cigar_len() as f64,
+        Field::SClen => clipped_bases(record, true) as f64,
+        Field::HClen => clipped_bases(record, false) as f64,
+        Field::QName => return Value::Str(String::from_utf8_lossy(record.qname()).to_string()),
+        Field::Seq => {
+            return Value::Str(String::from_utf8_lossy(&record.seq().as_bytes()).to_string());
+        }
+    };
+    Value::Num(num)
+}
+
+impl Expr {
+    fn eval(&self, record: &bam::Record) -> Value {
+        match self {
+            Expr::Num(n) => Value::Num(*n),
+            Expr::Str(s) => Value::Str(s.clone()),
+            Expr::Tag(tag) => tag_value(record, tag),
+            Expr::Field(field) => field_value(record, *field),
+            Expr::Not(expr) => Value::from_bool(!expr.eval(record).is_true()),
+            Expr::Neg(expr) => match expr.eval(record) {
+                Value::Num(n) => Value::Num(-n),
+                _ => Value::Null,
+            },
+            Expr::Match {
+                expr,
+                regex,
+                negate,
+            } => match expr.eval(record) {
+                Value::Str(s) => Value::from_bool(regex.is_match(&s) != *negate),
+                _ => Value::from_bool(false),
+            },
+            Expr::Binary(BinOp::Or, lhs, rhs) => {
+                Value::from_bool(lhs.eval(record).is_true() || rhs.eval(record).is_true())
+            }
+            Expr::Binary(BinOp::And, lhs, rhs) => {
+                Value::from_bool(lhs.eval(record).is_true() && rhs.eval(record).is_true())
+            }
+            Expr::Binary(op, lhs, rhs) => binary(*op, lhs.eval(record), rhs.eval(record)),
+        }
+    }
+}
+
+fn binary(op: BinOp, lhs: Value, rhs: Value) -> Value {
+    use std::cmp::Ordering;
+
+    let ordering = match (&lhs, &rhs) {
+        (Value::Num(a), Value::Num(b)) => a.partial_cmp(b),
+        (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+        _ => None,
+    };
+    let compare = |accept: fn(Ordering) -> bool| Value::from_bool(ordering.is_some_and(accept));
+    match op {
+        BinOp::Eq => compare(|o| o == Ordering::Equal),
+        BinOp::Ne => compare(|o| o != Ordering::Equal),
+        BinOp::Lt => compare(|o| o == Ordering::Less),
+        BinOp::Le => compare(|o| o != Ordering::Greater),
+        BinOp::Gt => compare(|o| o == Ordering::Greater),
+        BinOp::Ge => compare(|o| o != Ordering::Less),
+        _ => {
+            let (Value::Num(a), Value::Num(b)) = (lhs, rhs) else {
+                return Value::Null;
+            };
+            Value::Num(match op {
+                BinOp::BitOr => ((a as i64) | (b as i64)) as f64,
+                BinOp::BitAnd => ((a as i64) & (b as i64)) as f64,
+                BinOp::Add => a + b,
+                BinOp::Sub => a - b,
+                BinOp::Mul => a * b,
+                BinOp::Div => a / b,
+                BinOp::Or
+                | BinOp::And
+                | BinOp::Eq
+                | BinOp::Ne
+                | BinOp::Lt
+                | BinOp::Le
+                | BinOp::Gt
+                | BinOp::Ge => unreachable!(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::CigarString;
+
+    fn record() -> bam::Record {
+        let mut record = bam::Record::new();
+        let cigar = CigarString(vec![Cigar::SoftClip(2), Cigar::Match(6), Cigar::Del(1)]);
+        record.set(b"read1", Some(&cigar), b"ACGTACGT", &[30; 8]);
+        record.set_pos(99);
+        record.set_mapq(60);
+        record.set_flags(0x10);
+        record.push_aux(b"NM", Aux::U8(2)).unwrap();
+        record.push_aux(b"de", Aux::Float(0.0005)).unwrap();
+        record.push_aux(b"RG", Aux::String("run2")).unwrap();
+        record
+    }
+
+    fn eval(expr: &str) -> bool {
+        expr.parse::<FilterExpr>().unwrap().matches(&record())
+    }
+
+    fn error(expr: &str) -> String {
+        expr.parse::<FilterExpr>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn fields_and_tags() {
+        assert!(eval("pos == 100 && endpos == 106 && rlen == 7"));
+        assert!(eval("qlen == 8 && sclen == 2 && hclen == 0 && ncigar == 3"));
+        assert!(eval("flag.reverse && !flag.supplementary && mapq >= 60"));
+        assert!(eval("[RG] == \"run2\" && [NM] == 2 && qname == 'read1'"));
+    }
+
+    #[test]
+    fn numbers() {
+        assert!(eval("[de] < 1e-3"));
+        assert!(eval("[de] > 5E-4 - 1e-9 && [de] < 5.0e-04 + 1e-9"));
+        assert!(eval("2.5e+2 == 250 && .5 == 0.5"));
+        // Hex numbers have no exponent: 0x1e - 3
+        assert!(eval("0x1e-3 == 27 && 0X10 == 16"));
+        assert!(error("1e").contains("invalid number '1e'"));
+    }
+
+    #[test]
+    fn precedence() {
+        assert!(eval("1 + 2 * 3 == 7"));
+        assert!(eval("(1 + 2) * 3 == 9"));
+        assert!(eval("10 - 4 - 3 == 3 && 12 / 3 / 2 == 2"));
+        assert!(eval("1 || 0 && 0"));
+        assert!(!eval("(1 || 0) && 0"));
+        assert!(eval("-2 * 3 == -6 && !0 == 1"));
+        // As in C, `==` binds tighter than `&`: flag & (16 == 16)
+        assert!(!eval("flag & 16 == 16"));
+        assert!(eval("(flag & 16) == 16 && (flag | 1) == 17"));
+        // Comparisons bind tighter than equality
+        assert!(eval("1 < 2 == 1"));
+    }
+
+    #[test]
+    fn null_semantics() {
+        assert!(!eval("[XX]"));
+        assert!(eval("![XX]"));
+        assert!(!eval("[XX] == 0"));
+        assert!(!eval("[XX] != 0"));
+        assert!(!eval("[XX] < 1 || [XX] >= 1"));
+        assert!(!eval("[XX] + 1 == 1"));
+        assert!(!eval("-[XX] == 0"));
+        assert!(!eval("[XX] =~ \".*\""));
+        assert!(!eval("[XX] !~ \"x\""));
+        assert!(eval("[XX] || [NM]"));
+        // Strings and numbers are never equal
+        assert!(!eval("[RG] == 2") && !eval("[RG] != 2"));
+    }
+
+    #[test]
+    fn regex_matches() {
+        assert!(eval("qname =~ \"^read[0-9]$\""));
+        assert!(!eval("qname !~ \"^read\""));
+        assert!(eval("[RG] !~ \"run1\" && seq =~ 'CGTA'"));
+        // Only strings match, numbers do not
+        assert!(!eval("[NM] =~ \"2\"") && !eval("[NM] !~ \"2\""));
+        // Binds like equality, below `&&` and above `&`
+        assert!(eval("qname =~ \"read\" == 1"));
+    }
+
+    #[test]
+    fn error_messages() {
+        assert_eq!(
+            error("mapq >"),
+            "Invalid expression 'mapq >': unexpected end of expression"
+        );
+        assert_eq!(
+            error("foo > 1"),
+            "Invalid expression 'foo > 1': unknown field 'foo'"
+        );
+        assert_eq!(
+            error("flag.bar"),
+            "Invalid expression 'flag.bar': unknown field 'flag.bar'"
+        );
+        assert_eq!(
+            error("qname == \"abc"),
+            "Invalid expression 'qname == \"abc': unterminated string"
+        );
+        assert_eq!(
+            error("[N] == 1"),
+            "Invalid expression '[N] == 1': tags must be written as [XX]"
+        );
+        assert_eq!(
+            error("mapq # 1"),
+            "Invalid expression 'mapq # 1': unexpected character '#'"
+        );
+        assert_eq!(
+            error("(mapq > 1"),
+            "Invalid expression '(mapq > 1': unexpected end of expression"
+        );
+        assert_eq!(
+            error("(mapq 1)"),
+            "Invalid expression '(mapq 1)': expected ')' but found number 1"
+        );
+        assert_eq!(
+            error("mapq 1"),
+            "Invalid expression 'mapq 1': unexpected number 1"
+        );
+        assert_eq!(
+            error("mapq > )"),
+            "Invalid expression 'mapq > )': unexpected ')'"
+        );
+        assert_eq!(
+            error("qname =~ 1"),
+            "Invalid expression 'qname =~ 1': '=~' must be followed by a quoted regular expression"
+        );
+        assert!(
+            error("qname =~ \"(\"")
+                .starts_with("Invalid expression 'qname =~ \"(\"': invalid regular expression:")
+        );
+    }
+}
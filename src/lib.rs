@@ -1,5 +1,17 @@
+pub mod alleles;
+pub mod bedmethyl;
+pub mod compare;
+pub mod dwell;
+pub mod export;
+pub mod filterexpr;
+pub mod modbase;
 pub mod nanopileup;
+pub mod readfilter;
 pub mod region;
+pub mod signal;
+
+#[cfg(test)]
+mod testutil;
 
 #[cfg(feature = "python")]
 pub mod python;
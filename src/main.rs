@@ -1,19 +1,98 @@
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
+mod alleles;
+mod bedmethyl;
+mod compare;
+mod dwell;
+mod export;
+mod filterexpr;
+mod modbase;
 mod nanopileup;
+mod readfilter;
 mod region;
+mod signal;
+
+#[cfg(test)]
+mod testutil;
+
+/// Read-level filters shared by the pileup and compare modes.
+#[derive(clap::Args, Debug)]
+struct ReadFilterArgs {
+    #[clap(
+        long = "min_read_length",
+        default_value_t = 0,
+        help = "Minimum read length, including hard-clipped bases"
+    )]
+    min_read_length: u32,
+
+    #[clap(
+        long = "min_aligned_length",
+        default_value_t = 0,
+        help = "Minimum number of read bases within the alignment"
+    )]
+    min_aligned_length: u32,
+
+    #[clap(
+        long = "min_aligned_fraction",
+        default_value_t = 0.0,
+        help = "Minimum fraction of the read within the alignment"
+    )]
+    min_aligned_fraction: f64,
+
+    #[clap(
+        long = "max_divergence",
+        help = "Maximum gap-compressed divergence, from the de tag or derived from NM"
+    )]
+    max_divergence: Option<f64>,
+
+    #[clap(
+        long = "min_qscore",
+        help = "Minimum mean read quality, from the qs tag or the base qualities"
+    )]
+    min_qscore: Option<f64>,
+
+    #[clap(
+        long = "include_tag",
+        help = "Keep only reads with this tag, as TAG or TAG:VALUE[,VALUE...]. Can be specified multiple times."
+    )]
+    include_tag: Vec<readfilter::TagFilter>,
+
+    #[clap(
+        long = "exclude_tag",
+        help = "Drop reads with this tag, as TAG or TAG:VALUE[,VALUE...]. Can be specified multiple times."
+    )]
+    exclude_tag: Vec<readfilter::TagFilter>,
+
+    #[clap(
+        short = 'e',
+        long = "expr",
+        help = "Keep only reads for which this samtools-style expression is true, e.g. '[qs] >= 10 && !flag.supplementary'"
+    )]
+    expr: Option<filterexpr::FilterExpr>,
+}
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[clap(
         short = 'i',
         long = "bam_fp",
-        help = "Input BAM file, must be sorted and indexed"
+        help = "Input BAM file, must be sorted and indexed",
+        required = true
     )]
-    bam_fp: PathBuf,
+    bam_fp: Option<PathBuf>,
 
     #[clap(short = 'f', long = "ref_fp", help = "Input reference FASTA file")]
     ref_fp: Option<PathBuf>,
@@ -23,7 +102,7 @@ struct Args {
         long = "bed_fp",
         help = "Input BED file (zero-based and half-open interval)",
         conflicts_with = "region",
-        required_unless_present = "region"
+        required_unless_present_any = ["region", "whole_genome", "contig", "contig_regex"]
     )]
     bed_fp: Option<PathBuf>,
 
@@ -32,10 +111,32 @@ struct Args {
         long = "region",
         help = "Input region (1-based and inclusive at both ends, e.g. chr1:100-200). Can be specified multiple times.",
         conflicts_with = "bed_fp",
-        required_unless_present = "bed_fp"
+        required_unless_present_any = ["bed_fp", "whole_genome", "contig", "contig_regex"]
     )]
     region: Option<Vec<String>>,
 
+    #[clap(
+        long = "whole_genome",
+        default_value_t = false,
+        conflicts_with_all = ["bed_fp", "region", "contig", "contig_regex"],
+        help = "Pile up every contig in the BAM header that has mapped reads"
+    )]
+    whole_genome: bool,
+
+    #[clap(
+        long = "contig",
+        conflicts_with_all = ["bed_fp", "region"],
+        help = "Pile up this whole contig. Can be specified multiple times."
+    )]
+    contig: Option<Vec<String>>,
+
+    #[clap(
+        long = "contig_regex",
+        conflicts_with_all = ["bed_fp", "region"],
+        help = "Pile up every contig whose whole name matches this regular expression"
+    )]
+    contig_regex: Option<String>,
+
     #[clap(
         long = "buffer_size",
         default_value_t = 10000,
@@ -69,6 +170,16 @@ struct Args {
     #[clap(long = "flag_filter", default_value_t = 0, help = "Flag filter")]
     flag_filter: u32,
 
+    #[clap(
+        long = "count_molecule_once",
+        default_value_t = false,
+        help = "Count each molecule once per position when several of its alignments (same read name or pi parent) overlap it"
+    )]
+    count_molecule_once: bool,
+
+    #[command(flatten)]
+    read_filters: ReadFilterArgs,
+
     #[clap(
         long = "output_mv",
         default_value_t = false,
@@ -96,48 +207,536 @@ struct Args {
         help = "Output Read Names"
     )]
     output_read_name: bool,
+
+    #[clap(
+        long = "output_dwell",
+        default_value_t = false,
+        help = "Output dwell per base (move count x stride)"
+    )]
+    output_dwell: bool,
+
+    #[clap(
+        long = "dwell_unit",
+        default_value = "samples",
+        help = "Unit of the dwell output: samples or ms"
+    )]
+    dwell_unit: dwell::DwellUnit,
+
+    #[clap(
+        long = "sample_rate",
+        help = "Signal sample rate in Hz, overrides sample_rate= in the @RG DS header field"
+    )]
+    sample_rate: Option<f64>,
+
+    #[clap(
+        long = "output_signal_coords",
+        default_value_t = false,
+        help = "Output raw signal sample range (start-end) per base, from the ts/ns/mv tags"
+    )]
+    output_signal_coords: bool,
+
+    #[clap(
+        long = "output_dwell_summary",
+        default_value_t = false,
+        help = "Output per-strand dwell mean:median:std:MAD:count per position, in --dwell_unit"
+    )]
+    output_dwell_summary: bool,
+
+    #[clap(
+        long = "dwell_norm",
+        default_value = "none",
+        help = "Output dwell normalised within each read: none, zscore or median (ratio to read median)"
+    )]
+    dwell_norm: dwell::DwellNorm,
+
+    #[clap(
+        long = "dwell_norm_window",
+        default_value_t = 0,
+        help = "Bases in the rolling window used by --dwell_norm, 0 uses the whole read"
+    )]
+    dwell_norm_window: usize,
+
+    #[clap(
+        long = "mv_validation",
+        default_value = "lenient",
+        help = "Reads whose move table does not match their bases: strict fails the run, lenient warns and reports no move-derived values for them"
+    )]
+    mv_validation: dwell::MoveValidation,
+
+    #[clap(
+        long = "rna",
+        default_value_t = false,
+        help = "Direct RNA reads: orient moves 3'->5', read U as T and report reverse strand bases on the transcript strand"
+    )]
+    rna: bool,
+
+    #[clap(
+        long = "kmer_size",
+        default_value_t = 0,
+        requires = "ref_fp",
+        help = "Output the reference k-mer of this length around each position, forward and reverse complement (0 disables)"
+    )]
+    kmer_size: usize,
+
+    #[clap(
+        long = "kmer_offset",
+        help = "Index of the position within the k-mer, in read orientation [default: kmer_size / 2]"
+    )]
+    kmer_offset: Option<usize>,
+
+    #[clap(
+        long = "output_mods",
+        default_value_t = false,
+        help = "Output base modification probabilities per read from the MM/ML tags"
+    )]
+    output_mods: bool,
+
+    #[clap(
+        long = "export",
+        help = "Write one row per read and position (read name, strand, query position, base, base quality, dwell, k-mer, mapq) to this file instead of the pileup"
+    )]
+    export: Option<PathBuf>,
+
+    #[clap(
+        long = "export_format",
+        default_value = "tsv",
+        requires = "export",
+        help = "Format of --export: tsv or arrow (Arrow IPC / Feather v2)"
+    )]
+    export_format: export::ExportFormat,
+
+    #[clap(
+        long = "split_haplotypes",
+        default_value_t = false,
+        help = "Report every position separately for reads with HP=1, HP=2 and without an HP tag. Reads with other HP values are filtered out and counted in the run summary."
+    )]
+    split_haplotypes: bool,
+
+    #[clap(
+        long = "allele_counts",
+        help = "Write per-strand A/C/G/T/N, deletion and insertion counts with mean base quality per allele to this file instead of the pileup"
+    )]
+    allele_counts: Option<PathBuf>,
+
+    #[clap(
+        long = "bedmethyl",
+        help = "Write per-position modification frequencies from the MM/ML tags as bedMethyl to this file instead of the pileup"
+    )]
+    bedmethyl: Option<PathBuf>,
+
+    #[clap(
+        long = "mod_context",
+        default_value = "any",
+        requires = "bedmethyl",
+        help = "Modified bases written to --bedmethyl: any, or cpg for the C of reference CpGs"
+    )]
+    mod_context: bedmethyl::ModContext,
+
+    #[clap(
+        long = "combine_strands",
+        default_value_t = false,
+        requires = "bedmethyl",
+        help = "Combine the two strands of each CpG into one --bedmethyl record (requires --mod_context cpg)"
+    )]
+    combine_strands: bool,
+
+    #[clap(
+        long = "mod_threshold",
+        default_value_t = 0.0,
+        value_parser = parse_probability,
+        help = "Minimum probability of a read's most likely modification call for --bedmethyl; lower calls are counted as filtered"
+    )]
+    mod_threshold: f64,
+
+    #[clap(
+        long = "threads",
+        default_value_t = 1,
+        help = "Number of threads; regions are split into chunks that are piled up in parallel"
+    )]
+    threads: usize,
+
+    #[clap(
+        long = "pod5",
+        help = "POD5 file or directory of POD5 files; attaches per-base signal mean, std and sample count",
+        conflicts_with = "slow5"
+    )]
+    pod5: Option<PathBuf>,
+
+    #[clap(
+        long = "slow5",
+        help = "SLOW5 or BLOW5 file; attaches per-base signal mean, std and sample count",
+        conflicts_with = "pod5"
+    )]
+    slow5: Option<PathBuf>,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Test per-position dwell differences between a treated and a control BAM
+    Compare(CompareArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct CompareArgs {
+    #[clap(
+        short = 't',
+        long = "treated",
+        help = "Treated BAM file, must be sorted and indexed"
+    )]
+    treated: PathBuf,
+
+    #[clap(
+        short = 'c',
+        long = "control",
+        help = "Control BAM file, must be sorted and indexed"
+    )]
+    control: PathBuf,
+
+    #[clap(short = 'f', long = "ref_fp", help = "Input reference FASTA file")]
+    ref_fp: Option<PathBuf>,
+
+    #[clap(
+        short = 'l',
+        long = "bed_fp",
+        help = "Input BED file (zero-based and half-open interval)",
+        conflicts_with = "region",
+        required_unless_present = "region"
+    )]
+    bed_fp: Option<PathBuf>,
+
+    #[clap(
+        short = 'r',
+        long = "region",
+        help = "Input region (1-based and inclusive at both ends, e.g. chr1:100-200). Can be specified multiple times.",
+        conflicts_with = "bed_fp",
+        required_unless_present = "bed_fp"
+    )]
+    region: Option<Vec<String>>,
+
+    #[clap(
+        long = "test",
+        default_value = "mwu",
+        help = "Dwell test: mwu (Mann-Whitney U), ks (Kolmogorov-Smirnov) or gmm (Gaussian mixture)"
+    )]
+    test: compare::DwellTest,
+
+    #[clap(
+        long = "min_coverage",
+        default_value_t = 5,
+        help = "Minimum number of reads with dwell in each sample for a position to be tested"
+    )]
+    min_coverage: usize,
+
+    #[clap(
+        long = "buffer_size",
+        default_value_t = 10000,
+        help = "Buffer size used when reading BAM file"
+    )]
+    buffer_size: usize,
+
+    #[clap(
+        long = "margin",
+        default_value_t = 500,
+        help = "Margin used when reading BAM file"
+    )]
+    margin: usize,
+
+    #[clap(
+        short = 'q',
+        long = "min_mapq",
+        default_value_t = 0,
+        help = "Minimum mapping quality"
+    )]
+    min_mapq: u8,
+
+    #[clap(
+        short = 'Q',
+        long = "min_baseq",
+        default_value_t = 13,
+        help = "Minimum base quality"
+    )]
+    min_baseq: u8,
+
+    #[clap(long = "flag_filter", default_value_t = 0, help = "Flag filter")]
+    flag_filter: u32,
+
+    #[clap(
+        long = "count_molecule_once",
+        default_value_t = false,
+        help = "Count each molecule once per position when several of its alignments (same read name or pi parent) overlap it"
+    )]
+    count_molecule_once: bool,
+
+    #[command(flatten)]
+    read_filters: ReadFilterArgs,
 
-    // Parse regions
-    let regions = if let Some(bed_fp) = args.bed_fp {
-        region::parse_bed_file(&bed_fp).with_context(|| {
-            format!("Failed to parse BED file located at '{}'", bed_fp.display())
-        })?
-    } else if let Some(region_strs) = args.region {
+    #[clap(
+        long = "dwell_unit",
+        default_value = "samples",
+        help = "Unit of the tested dwell: samples or ms"
+    )]
+    dwell_unit: dwell::DwellUnit,
+
+    #[clap(
+        long = "sample_rate",
+        help = "Signal sample rate in Hz, overrides sample_rate= in the @RG DS header field"
+    )]
+    sample_rate: Option<f64>,
+
+    #[clap(
+        long = "mv_validation",
+        default_value = "lenient",
+        help = "Reads whose move table does not match their bases: strict fails the run, lenient warns and ignores their dwell"
+    )]
+    mv_validation: dwell::MoveValidation,
+
+    #[clap(
+        long = "rna",
+        default_value_t = false,
+        help = "Direct RNA reads: orient moves 3'->5' and read U as T"
+    )]
+    rna: bool,
+}
+
+fn parse_probability(s: &str) -> Result<f64> {
+    let value: f64 = s
+        .parse()
+        .with_context(|| format!("Invalid probability '{}'", s))?;
+    if !(0.0..=1.0).contains(&value) {
+        return Err(anyhow::anyhow!(
+            "Probability {} is not within 0 and 1",
+            value
+        ));
+    }
+    Ok(value)
+}
+
+fn parse_regions(
+    bed_fp: Option<PathBuf>,
+    region_strs: Option<Vec<String>>,
+) -> Result<Vec<region::Region>> {
+    if let Some(bed_fp) = bed_fp {
+        region::parse_bed_file(&bed_fp)
+            .with_context(|| format!("Failed to parse BED file located at '{}'", bed_fp.display()))
+    } else if let Some(region_strs) = region_strs {
         region_strs
             .iter()
             .map(|s| {
                 s.parse()
                     .with_context(|| format!("Failed to parse region string '{}'", s))
             })
-            .collect::<Result<Vec<region::Region>>>()?
+            .collect::<Result<Vec<region::Region>>>()
     } else {
         unreachable!("Either region or bed_fp must be provided");
+    }
+}
+
+/// `out.bed` becomes `out.hp1.bed` for haplotype 1.
+fn haplotype_path(path: &std::path::Path, haplotype: nanopileup::Haplotype) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, haplotype, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, haplotype),
     };
+    path.with_file_name(name)
+}
 
+fn run_compare(args: CompareArgs) -> Result<()> {
+    let regions = parse_regions(args.bed_fp, args.region)?;
+    let options = nanopileup::PileupOptions {
+        min_mapq: args.min_mapq,
+        min_baseq: args.min_baseq,
+        flag_filter: args.flag_filter,
+        buffer_size: args.buffer_size,
+        margin: args.margin,
+        dwell_unit: args.dwell_unit,
+        sample_rate: args.sample_rate,
+        mv_validation: args.mv_validation,
+        rna: args.rna,
+        count_molecule_once: args.count_molecule_once,
+        min_read_length: args.read_filters.min_read_length,
+        min_aligned_length: args.read_filters.min_aligned_length,
+        min_aligned_fraction: args.read_filters.min_aligned_fraction,
+        max_divergence: args.read_filters.max_divergence,
+        min_qscore: args.read_filters.min_qscore,
+        include_tags: args.read_filters.include_tag,
+        exclude_tags: args.read_filters.exclude_tag,
+        filter_expr: args.read_filters.expr,
+        ..Default::default()
+    };
+
+    let mut treated_summary = nanopileup::RunSummary::default();
+    let mut control_summary = nanopileup::RunSummary::default();
+    let mut results = Vec::new();
     for region in regions {
-        // println!("Region: {:?}", region);
         let region_label = format!("{}:{}-{}", region.chromosome, region.start + 1, region.end);
-        let results = nanopileup::nanopileup(
-            &args.bam_fp,
-            &region,
-            args.ref_fp.as_ref(),
-            args.min_mapq,
-            args.min_baseq,
-            args.flag_filter,
-            args.buffer_size,
-            args.margin,
-            args.output_bq,
-            args.output_mapq,
-            args.output_read_name,
-            args.output_mv,
-        )
-        .with_context(|| format!("Failed to run nanopileup for {}", region_label))?;
-
-        for p in results {
+        results.extend(
+            compare::compare_region(
+                &args.treated,
+                &args.control,
+                &region,
+                args.ref_fp.as_ref(),
+                &options,
+                args.test,
+                args.min_coverage,
+                &mut treated_summary,
+                &mut control_summary,
+            )
+            .with_context(|| format!("Failed to compare dwell for {}", region_label))?,
+        );
+    }
+    // q-values are adjusted over every tested position of the run
+    compare::adjust_q_values(&mut results);
+    for r in results {
+        println!("{}", r);
+    }
+
+    for (sample, summary) in [("Treated", treated_summary), ("Control", control_summary)] {
+        if !summary.is_empty() {
+            eprintln!("{} sample:\n{}", sample, summary);
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    if let Some(Command::Compare(compare_args)) = args.command {
+        return run_compare(compare_args);
+    }
+    let bam_fp = args
+        .bam_fp
+        .expect("--bam_fp is required unless a subcommand is given");
+
+    let regions = if args.whole_genome || args.contig.is_some() || args.contig_regex.is_some() {
+        let pattern = args
+            .contig_regex
+            .as_deref()
+            .map(|re| {
+                regex::Regex::new(&format!("^(?:{})$", re))
+                    .with_context(|| format!("Invalid contig regular expression '{}'", re))
+            })
+            .transpose()?;
+        region::contig_regions(&bam_fp, &args.contig.unwrap_or_default(), pattern.as_ref())?
+    } else {
+        parse_regions(args.bed_fp, args.region)?
+    };
+
+    let options = nanopileup::PileupOptions {
+        min_mapq: args.min_mapq,
+        min_baseq: args.min_baseq,
+        flag_filter: args.flag_filter,
+        buffer_size: args.buffer_size,
+        margin: args.margin,
+        output_bq: args.output_bq,
+        output_mapq: args.output_mapq,
+        output_read_name: args.output_read_name,
+        output_mv: args.output_mv,
+        output_dwell: args.output_dwell,
+        dwell_unit: args.dwell_unit,
+        sample_rate: args.sample_rate,
+        output_signal_coords: args.output_signal_coords,
+        output_dwell_summary: args.output_dwell_summary,
+        dwell_norm: args.dwell_norm,
+        dwell_norm_window: args.dwell_norm_window,
+        mv_validation: args.mv_validation,
+        keep_read_dwell: false,
+        rna: args.rna,
+        kmer_size: args.kmer_size,
+        kmer_offset: args.kmer_offset,
+        keep_observations: args.export.is_some(),
+        output_mods: args.output_mods,
+        keep_mod_counts: args.bedmethyl.is_some(),
+        mod_threshold: args.mod_threshold,
+        count_molecule_once: args.count_molecule_once,
+        min_read_length: args.read_filters.min_read_length,
+        min_aligned_length: args.read_filters.min_aligned_length,
+        min_aligned_fraction: args.read_filters.min_aligned_fraction,
+        max_divergence: args.read_filters.max_divergence,
+        min_qscore: args.read_filters.min_qscore,
+        include_tags: args.read_filters.include_tag,
+        exclude_tags: args.read_filters.exclude_tag,
+        filter_expr: args.read_filters.expr,
+        split_haplotypes: args.split_haplotypes,
+        keep_allele_counts: args.allele_counts.is_some(),
+    };
+
+    let signal_source = signal::open_signal_source(args.pod5.as_deref(), args.slow5.as_deref())?;
+
+    let mut exporter = args
+        .export
+        .as_deref()
+        .map(|path| export::ExportWriter::create(path, args.export_format, args.dwell_unit))
+        .transpose()?;
+    let mut allele_writer = args
+        .allele_counts
+        .as_deref()
+        .map(|path| alleles::AlleleCountWriter::create(path, args.split_haplotypes))
+        .transpose()?;
+    // One bedMethyl file per haplotype group when splitting by haplotype
+    let mut bedmethyl_writers = Vec::new();
+    if let Some(path) = args.bedmethyl.as_deref() {
+        if args.mod_context == bedmethyl::ModContext::CpG && args.ref_fp.is_none() {
+            return Err(anyhow::anyhow!(
+                "--mod_context cpg requires --ref_fp to find reference CpGs"
+            ));
+        }
+        let haplotypes = if args.split_haplotypes {
+            nanopileup::HAPLOTYPE_GROUPS.map(Some).to_vec()
+        } else {
+            vec![None]
+        };
+        for haplotype in haplotypes {
+            let path = match haplotype {
+                Some(h) => haplotype_path(path, h),
+                None => path.to_path_buf(),
+            };
+            let writer =
+                bedmethyl::BedMethylWriter::create(&path, args.mod_context, args.combine_strands)?;
+            bedmethyl_writers.push((haplotype, writer));
+        }
+    }
+
+    let mut run_summary = nanopileup::RunSummary::default();
+    nanopileup::nanopileup_regions(
+        &bam_fp,
+        &regions,
+        args.ref_fp.as_ref(),
+        &options,
+        signal_source.as_deref(),
+        args.threads,
+        &mut run_summary,
+        |p| {
+            if exporter.is_some() || allele_writer.is_some() || !bedmethyl_writers.is_empty() {
+                if let Some(exporter) = exporter.as_mut() {
+                    exporter.write_position(&p).with_context(|| {
+                        format!("Failed to write export rows for {}:{}", p.chrom, p.pos + 1)
+                    })?;
+                }
+                if let Some(writer) = allele_writer.as_mut() {
+                    writer.write_position(&p).with_context(|| {
+                        format!(
+                            "Failed to write allele counts for {}:{}",
+                            p.chrom,
+                            p.pos + 1
+                        )
+                    })?;
+                }
+                if let Some((_, writer)) = bedmethyl_writers
+                    .iter_mut()
+                    .find(|(haplotype, _)| *haplotype == p.haplotype)
+                {
+                    writer.write_position(&p).with_context(|| {
+                        format!(
+                            "Failed to write bedMethyl records for {}:{}",
+                            p.chrom,
+                            p.pos + 1
+                        )
+                    })?;
+                }
+                return Ok(());
+            }
+
             let bases_str = p.bases.join("");
             // let quals_str = ".".repeat(bases_str.len()); // Placeholder
             let mut output = format!(
@@ -173,12 +772,129 @@ fn main() -> Result<()> {
             }
             if let Some(mvs) = p.mv_values {
                 output.push('\t');
-                output.push_str(&mvs.join(";"));
+                output.push_str(
+                    &mvs.iter()
+                        .map(|v| v.format("0", |x| x.to_string()))
+                        .collect::<Vec<_>>()
+                        .join(";"),
+                );
+            }
+            if let Some(dwells) = p.dwell_values {
+                output.push('\t');
+                output.push_str(
+                    &dwells
+                        .iter()
+                        .map(|v| v.format(".", |x| options.dwell_unit.format(*x)))
+                        .collect::<Vec<_>>()
+                        .join(";"),
+                );
+            }
+            if let Some(norms) = p.dwell_norm_values {
+                output.push('\t');
+                output.push_str(
+                    &norms
+                        .iter()
+                        .map(|v| v.format(".", |x| format!("{:.3}", x)))
+                        .collect::<Vec<_>>()
+                        .join(";"),
+                );
+            }
+            if let Some(coords) = p.signal_coords {
+                output.push('\t');
+                output.push_str(
+                    &coords
+                        .iter()
+                        .map(|c| match c {
+                            Some((s, e)) => format!("{}-{}", s, e),
+                            None => ".".to_string(),
+                        })
+                        .collect::<Vec<_>>()
+                        .join(";"),
+                );
+            }
+            if let Some(stats) = p.signal_stats {
+                output.push('\t');
+                output.push_str(
+                    &stats
+                        .iter()
+                        .map(|s| match s {
+                            Some(s) => s.to_string(),
+                            None => ".".to_string(),
+                        })
+                        .collect::<Vec<_>>()
+                        .join(";"),
+                );
+            }
+            if let Some(mods) = p.mod_probs {
+                output.push('\t');
+                output.push_str(
+                    &mods
+                        .iter()
+                        .map(|m| {
+                            if m.is_empty() {
+                                ".".to_string()
+                            } else {
+                                m.iter()
+                                    .map(|p| p.to_string())
+                                    .collect::<Vec<_>>()
+                                    .join(",")
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                        .join(";"),
+                );
+            }
+            if let Some(summary) = p.dwell_summary {
+                for strand in [summary.forward, summary.reverse] {
+                    output.push('\t');
+                    match strand {
+                        Some(s) => output.push_str(&s.to_string()),
+                        None => output.push('.'),
+                    }
+                }
+            }
+
+            if args.kmer_size > 0 {
+                for kmer in [p.kmer_forward, p.kmer_reverse] {
+                    output.push('\t');
+                    output.push_str(kmer.as_deref().unwrap_or("."));
+                }
+            }
+            if let Some(haplotype) = p.haplotype {
+                output.push('\t');
+                output.push_str(&haplotype.to_string());
+            }
+            if let Some(phase_sets) = p.phase_sets {
+                output.push('\t');
+                if phase_sets.is_empty() {
+                    output.push('.');
+                } else {
+                    output.push_str(
+                        &phase_sets
+                            .iter()
+                            .map(|ps| ps.to_string())
+                            .collect::<Vec<_>>()
+                            .join(","),
+                    );
+                }
             }
 
             println!("{}", output);
-        }
-    }
+            Ok(())
+        },
+    )?;
 
+    if let Some(exporter) = exporter {
+        exporter.finish()?;
+    }
+    if let Some(writer) = allele_writer {
+        writer.finish()?;
+    }
+    for (_, writer) in bedmethyl_writers {
+        writer.finish()?;
+    }
+    if !run_summary.is_empty() {
+        eprintln!("{}", run_summary);
+    }
     Ok(())
 }
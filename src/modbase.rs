@@ -0,0 +1,568 @@
+use anyhow::{Result, bail};
+use rust_htslib::bam::{self, record::Aux};
+use std::fmt;
+
+use crate::dwell::aux_int;
+
+/// Modification code of an `MM` entry: a single-letter code such as `m` or `h`,
+/// or a ChEBI identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ModCode {
+    Letter(char),
+    ChEBI(u32),
+}
+
+impl fmt::Display for ModCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModCode::Letter(c) => write!(f, "{}", c),
+            ModCode::ChEBI(id) => write!(f, "{}", id),
+        }
+    }
+}
+
+/// Probability of one modification on one query base.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModProb {
+    /// Unmodified base the `MM` entry counts, in the orientation the read was basecalled
+    pub canonical: char,
+    /// `+` if the modification is on the basecalled strand, `-` if on its complement
+    pub strand: char,
+    pub code: ModCode,
+    pub prob: f64,
+}
+
+impl fmt::Display for ModProb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}:{:.3}",
+            self.canonical, self.strand, self.code, self.prob
+        )
+    }
+}
+
+/// Per-base modification probabilities decoded from the `MM`/`ML` tags, indexed
+/// like `record.seq()`.
+///
+/// Bases skipped by an entry with the `.` (or absent) skip mode are reported as
+/// unmodified with probability 0; with `?` their state is unknown and they get no
+/// value.
+pub fn mod_probs(record: &bam::Record) -> Result<Option<Vec<Vec<ModProb>>>> {
+    let mm = match record.aux(b"MM").or_else(|_| record.aux(b"Mm")) {
+        Ok(Aux::String(mm)) => mm,
+        Ok(_) => bail!("MM tag is not a string"),
+        Err(_) => return Ok(None),
+    };
+    let ml: Vec<u8> = match record.aux(b"ML").or_else(|_| record.aux(b"Ml")) {
+        Ok(Aux::ArrayU8(ml)) => ml.iter().collect(),
+        Ok(_) => bail!("ML tag is not a uint8 array"),
+        Err(_) => Vec::new(),
+    };
+
+    let seq = record.seq().as_bytes();
+    // MM positions count bases of the full basecalled read, which hard clips have removed
+    let has_hard_clips = record
+        .cigar()
+        .iter()
+        .any(|op| matches!(op, bam::record::Cigar::HardClip(_)));
+    match aux_int(record, b"MN") {
+        Some(mn) if mn as usize != seq.len() => return Ok(None),
+        None if has_hard_clips => return Ok(None),
+        _ => {}
+    }
+
+    // Bases in the orientation the read was basecalled
+    let is_reverse = record.is_reverse();
+    let original: Vec<u8> = if is_reverse {
+        seq.iter().rev().map(|&b| complement(b)).collect()
+    } else {
+        seq.clone()
+    };
+    let to_query_pos = |i: usize| if is_reverse { seq.len() - 1 - i } else { i };
+
+    let mut probs = vec![Vec::new(); seq.len()];
+    let mut ml_idx = 0;
+    for entry in mm.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let mut fields = entry.split(',');
+        let header = fields.next().unwrap_or_default();
+        let (canonical, strand, codes, implicit) = parse_header(header)?;
+
+        let matches = |b: u8| canonical == 'N' || b.to_ascii_uppercase() == canonical as u8;
+        let mut called = vec![false; original.len()];
+        let mut pos = 0;
+        for delta in fields {
+            let mut skip: usize = delta
+                .trim()
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid MM delta '{}' in '{}'", delta, header))?;
+            loop {
+                if pos >= original.len() {
+                    bail!("MM entry '{}' runs past the end of the read", header);
+                }
+                if matches(original[pos]) {
+                    if skip == 0 {
+                        break;
+                    }
+                    skip -= 1;
+                }
+                pos += 1;
+            }
+            let Some(values) = ml.get(ml_idx..ml_idx + codes.len()) else {
+                bail!("ML tag has fewer values than MM calls");
+            };
+            ml_idx += codes.len();
+            for (&code, &value) in codes.iter().zip(values) {
+                probs[to_query_pos(pos)].push(ModProb {
+                    canonical,
+                    strand,
+                    code,
+                    prob: ml_probability(value),
+                });
+            }
+            called[pos] = true;
+            pos += 1;
+        }
+
+        if implicit {
+            for (i, _) in original
+                .iter()
+                .enumerate()
+                .filter(|&(i, &b)| matches(b) && !called[i])
+            {
+                for &code in &codes {
+                    probs[to_query_pos(i)].push(ModProb {
+                        canonical,
+                        strand,
+                        code,
+                        prob: 0.0,
+                    });
+                }
+            }
+        }
+    }
+    Ok(Some(probs))
+}
+
+/// Split an `MM` entry header such as `C+mh?` into base, strand, codes and
+/// whether skipped bases are implicitly unmodified.
+fn parse_header(header: &str) -> Result<(char, char, Vec<ModCode>, bool)> {
+    let mut chars = header.chars();
+    let (Some(canonical), Some(strand)) = (chars.next(), chars.next()) else {
+        bail!("Invalid MM entry '{}'", header);
+    };
+    let canonical = canonical.to_ascii_uppercase();
+    if !"ACGTUN".contains(canonical) || !"+-".contains(strand) {
+        bail!("Invalid MM entry '{}'", header);
+    }
+    let mut rest = chars.as_str();
+    let implicit = match rest.chars().last() {
+        Some('?') => {
+            rest = &rest[..rest.len() - 1];
+            false
+        }
+        Some('.') => {
+            rest = &rest[..rest.len() - 1];
+            true
+        }
+        _ => true,
+    };
+    let codes = if !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit()) {
+        vec![ModCode::ChEBI(rest.parse()?)]
+    } else if !rest.is_empty() && rest.chars().all(|c| c.is_ascii_alphabetic()) {
+        rest.chars().map(ModCode::Letter).collect()
+    } else {
+        bail!("Invalid modification codes in MM entry '{}'", header);
+    };
+    // U is stored as T in SEQ
+    let canonical = if canonical == 'U' { 'T' } else { canonical };
+    Ok((canonical, strand, codes, implicit))
+}
+
+/// `ML` values encode the probability range [x/256, (x+1)/256); take its midpoint.
+fn ml_probability(value: u8) -> f64 {
+    (value as f64 + 0.5) / 256.0
+}
+
+fn complement(base: u8) -> u8 {
+    match base.to_ascii_uppercase() {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        other => other,
+    }
+}
+
+/// One read at a pileup position, as seen by `count_mods`.
+#[derive(Debug, Clone, Copy)]
+pub struct ModObservation<'a> {
+    pub is_reverse: bool,
+    /// Aligned base in the orientation the read was basecalled, None if deleted
+    pub base: Option<char>,
+    pub mods: &'a [ModProb],
+}
+
+/// Read counts of one modification code on one reference strand at a position,
+/// in the categories of the bedMethyl format.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModCounts {
+    pub code: ModCode,
+    /// Unmodified base in the orientation of `strand`
+    pub canonical: char,
+    /// Reference strand the modified base lies on
+    pub strand: char,
+    /// The base is the C of a reference CpG
+    pub cpg: bool,
+    pub n_mod: u32,
+    pub n_canonical: u32,
+    /// Reads called as a different modification of the same base
+    pub n_other_mod: u32,
+    pub n_delete: u32,
+    /// Reads whose most likely call is below the probability threshold
+    pub n_filtered: u32,
+    /// Reads without a call whose base differs from the canonical base
+    pub n_diff: u32,
+    /// Reads without a call whose base is the canonical base
+    pub n_nocall: u32,
+}
+
+impl ModCounts {
+    pub fn valid_coverage(&self) -> u32 {
+        self.n_mod + self.n_canonical + self.n_other_mod
+    }
+
+    pub fn percent_modified(&self) -> f64 {
+        match self.valid_coverage() {
+            0 => 0.0,
+            n => 100.0 * self.n_mod as f64 / n as f64,
+        }
+    }
+
+    /// Add the counts of `other`, e.g. the opposite strand of the same CpG.
+    pub fn merge(&mut self, other: &ModCounts) {
+        self.n_mod += other.n_mod;
+        self.n_canonical += other.n_canonical;
+        self.n_other_mod += other.n_other_mod;
+        self.n_delete += other.n_delete;
+        self.n_filtered += other.n_filtered;
+        self.n_diff += other.n_diff;
+        self.n_nocall += other.n_nocall;
+    }
+}
+
+/// Count the modification calls of `reads` per reference strand and code.
+///
+/// Each read is called as the most likely of its codes and the canonical base
+/// (probability 1 minus the sum of its codes); calls below `threshold` are
+/// counted as filtered. `context` holds the reference bases before, at and after
+/// the position.
+pub fn count_mods(reads: &[ModObservation], threshold: f64, context: [char; 3]) -> Vec<ModCounts> {
+    let ref_strand = |is_reverse: bool, m: &ModProb| {
+        if is_reverse != (m.strand == '-') {
+            '-'
+        } else {
+            '+'
+        }
+    };
+
+    let mut keys: Vec<(char, char, ModCode)> = reads
+        .iter()
+        .flat_map(|r| {
+            r.mods
+                .iter()
+                .map(move |m| (ref_strand(r.is_reverse, m), m.canonical, m.code))
+        })
+        .collect();
+    keys.sort();
+    keys.dedup();
+
+    let [prev, cur, next] = context.map(|b| b.to_ascii_uppercase());
+    keys.into_iter()
+        .map(|(strand, canonical, code)| {
+            let mut counts = ModCounts {
+                code,
+                canonical,
+                strand,
+                cpg: canonical == 'C'
+                    && match strand {
+                        '+' => cur == 'C' && next == 'G',
+                        _ => cur == 'G' && prev == 'C',
+                    },
+                n_mod: 0,
+                n_canonical: 0,
+                n_other_mod: 0,
+                n_delete: 0,
+                n_filtered: 0,
+                n_diff: 0,
+                n_nocall: 0,
+            };
+            for read in reads {
+                let calls: Vec<&ModProb> = read
+                    .mods
+                    .iter()
+                    .filter(|m| {
+                        m.canonical == canonical && ref_strand(read.is_reverse, m) == strand
+                    })
+                    .collect();
+                if calls.is_empty() {
+                    if (strand == '-') != read.is_reverse {
+                        continue;
+                    }
+                    match read.base {
+                        None => counts.n_delete += 1,
+                        Some(b) if b == canonical => counts.n_nocall += 1,
+                        Some(_) => counts.n_diff += 1,
+                    }
+                    continue;
+                }
+                let canonical_prob = 1.0 - calls.iter().map(|m| m.prob).sum::<f64>();
+                let best = calls
+                    .iter()
+                    .max_by(|a, b| a.prob.total_cmp(&b.prob))
+                    .filter(|m| m.prob > canonical_prob);
+                let best_prob = best.map_or(canonical_prob, |m| m.prob);
+                if best_prob < threshold {
+                    counts.n_filtered += 1;
+                } else {
+                    match best {
+                        None => counts.n_canonical += 1,
+                        Some(m) if m.code == code => counts.n_mod += 1,
+                        Some(_) => counts.n_other_mod += 1,
+                    }
+                }
+            }
+            counts
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Aux, AuxArray, Cigar, CigarString};
+
+    fn record(seq: &[u8], cigar: Vec<Cigar>, is_reverse: bool, mm: &str, ml: &[u8]) -> bam::Record {
+        let mut record = bam::Record::new();
+        record.set(
+            b"read1",
+            Some(&CigarString(cigar)),
+            seq,
+            &vec![30; seq.len()],
+        );
+        if is_reverse {
+            record.set_reverse();
+        }
+        record.push_aux(b"MM", Aux::String(mm)).unwrap();
+        record
+            .push_aux(b"ML", Aux::ArrayU8(AuxArray::from(ml)))
+            .unwrap();
+        record
+    }
+
+    fn forward(seq: &[u8], mm: &str, ml: &[u8]) -> bam::Record {
+        record(seq, vec![Cigar::Match(seq.len() as u32)], false, mm, ml)
+    }
+
+    /// The calls of every query base, as `C+m:0.783` strings.
+    fn calls(record: &bam::Record) -> Vec<Vec<String>> {
+        mod_probs(record)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|probs| probs.iter().map(|p| p.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn ml_values_are_bin_midpoints() {
+        assert_eq!(ml_probability(0), 0.5 / 256.0);
+        assert_eq!(ml_probability(128), 128.5 / 256.0);
+        assert_eq!(ml_probability(255), 255.5 / 256.0);
+        let probs = mod_probs(&forward(b"AC", "C+m,0;", &[255]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(probs[1][0].prob, 255.5 / 256.0);
+    }
+
+    #[test]
+    fn skip_modes() {
+        // Cs at query positions 1, 3, 4 and 6; the delta skips the first
+        let explicit = [
+            vec![],
+            vec!["C+m:0.000"],
+            vec![],
+            vec!["C+m:0.783"],
+            vec!["C+m:0.000"],
+            vec![],
+            vec!["C+m:0.000"],
+        ];
+        assert_eq!(calls(&forward(b"ACGCCGC", "C+m.,1;", &[200])), explicit);
+        assert_eq!(calls(&forward(b"ACGCCGC", "C+m,1;", &[200])), explicit);
+        let probs = mod_probs(&forward(b"ACGCCGC", "C+m.,1;", &[200]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(probs[1][0].prob, 0.0);
+
+        let unknown = calls(&forward(b"ACGCCGC", "C+m?,1;", &[200]));
+        assert_eq!(unknown.iter().filter(|c| !c.is_empty()).count(), 1);
+        assert_eq!(unknown[3], ["C+m:0.783"]);
+    }
+
+    #[test]
+    fn ml_values_follow_codes_and_entries() {
+        // Two codes per C call, then one value for the second A
+        let record = forward(b"ACGACTC", "C+hm?,0,1;A+a?,1;", &[10, 20, 30, 40, 50]);
+        let probs = mod_probs(&record).unwrap().unwrap();
+        let values = |i: usize| -> Vec<(ModCode, f64)> {
+            probs[i].iter().map(|p| (p.code, p.prob)).collect()
+        };
+        assert_eq!(
+            values(1),
+            [
+                (ModCode::Letter('h'), ml_probability(10)),
+                (ModCode::Letter('m'), ml_probability(20))
+            ]
+        );
+        assert_eq!(values(4), []);
+        assert_eq!(
+            values(6),
+            [
+                (ModCode::Letter('h'), ml_probability(30)),
+                (ModCode::Letter('m'), ml_probability(40))
+            ]
+        );
+        assert_eq!(values(3), [(ModCode::Letter('a'), ml_probability(50))]);
+        assert_eq!(values(0), []);
+
+        let chebi = mod_probs(&forward(b"AC", "C+76792?,0;", &[1]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(chebi[1][0].code, ModCode::ChEBI(76792));
+
+        let err =
+            mod_probs(&forward(b"ACGACTC", "C+hm?,0,1;A+a?,1;", &[10, 20, 30, 40])).unwrap_err();
+        assert_eq!(err.to_string(), "ML tag has fewer values than MM calls");
+        let err = mod_probs(&forward(b"ACGC", "C+m?,2;", &[1])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "MM entry 'C+m?' runs past the end of the read"
+        );
+    }
+
+    #[test]
+    fn reverse_strand_reads() {
+        // Basecalled as GTACC: the second C is the first base of SEQ
+        let record = record(b"GGTAC", vec![Cigar::Match(5)], true, "C+m?,1;", &[200]);
+        let calls = calls(&record);
+        assert_eq!(calls[0], ["C+m:0.783"]);
+        assert!(calls[1..].iter().all(|c| c.is_empty()));
+    }
+
+    #[test]
+    fn sequence_must_be_the_full_read() {
+        let cigar = vec![Cigar::HardClip(3), Cigar::Match(4)];
+        let mut clipped = record(b"ACGC", cigar.clone(), false, "C+m?,0;", &[200]);
+        assert_eq!(mod_probs(&clipped).unwrap(), None);
+        // MN says the tags were written for this sequence
+        clipped.push_aux(b"MN", Aux::I32(4)).unwrap();
+        assert_eq!(calls(&clipped)[1], ["C+m:0.783"]);
+
+        let mut mismatched = forward(b"ACGC", "C+m?,0;", &[200]);
+        mismatched.push_aux(b"MN", Aux::U16(7)).unwrap();
+        assert_eq!(mod_probs(&mismatched).unwrap(), None);
+
+        let mut untagged = bam::Record::new();
+        untagged.set(b"read1", Some(&CigarString(cigar)), b"ACGC", &[30; 4]);
+        assert_eq!(mod_probs(&untagged).unwrap(), None);
+    }
+
+    fn prob(strand: char, code: char, prob: f64) -> ModProb {
+        ModProb {
+            canonical: 'C',
+            strand,
+            code: ModCode::Letter(code),
+            prob,
+        }
+    }
+
+    /// (mod, canonical, other mod, delete, filtered, diff, nocall)
+    fn tally(c: &ModCounts) -> [u32; 7] {
+        [
+            c.n_mod,
+            c.n_canonical,
+            c.n_other_mod,
+            c.n_delete,
+            c.n_filtered,
+            c.n_diff,
+            c.n_nocall,
+        ]
+    }
+
+    fn read(is_reverse: bool, base: Option<char>, mods: &[ModProb]) -> ModObservation<'_> {
+        ModObservation {
+            is_reverse,
+            base,
+            mods,
+        }
+    }
+
+    #[test]
+    fn counts_by_call() {
+        let (likely, unlikely, unsure) = (
+            [prob('+', 'm', 0.9)],
+            [prob('+', 'm', 0.1)],
+            [prob('+', 'm', 0.45)],
+        );
+        let reads = [
+            read(false, Some('C'), &likely),
+            read(false, Some('C'), &unlikely),
+            // Canonical at 0.55 is below the threshold
+            read(false, Some('C'), &unsure),
+            read(false, None, &[]),
+            read(false, Some('T'), &[]),
+            read(false, Some('C'), &[]),
+            // Reverse reads without calls are not counted on the + strand
+            read(true, Some('C'), &[]),
+        ];
+        let counts = count_mods(&reads, 0.6, ['A', 'C', 'G']);
+        assert_eq!(counts.len(), 1);
+        let c = &counts[0];
+        assert_eq!(
+            (c.strand, c.canonical, c.code, c.cpg),
+            ('+', 'C', ModCode::Letter('m'), true)
+        );
+        assert_eq!(tally(c), [1, 1, 0, 1, 1, 1, 1]);
+        assert_eq!(c.valid_coverage(), 2);
+        assert_eq!(c.percent_modified(), 50.0);
+
+        let counts = count_mods(&reads, 0.6, ['A', 'C', 'A']);
+        assert!(!counts[0].cpg);
+    }
+
+    #[test]
+    fn other_modifications_and_strands() {
+        let hm = [prob('+', 'h', 0.6), prob('+', 'm', 0.3)];
+        let reads = [
+            read(false, Some('C'), &hm),
+            // Basecalled from the - strand, so its C+m call is on the reference - strand
+            read(true, Some('C'), &hm[1..]),
+        ];
+        let counts = count_mods(&reads, 0.0, ['C', 'G', 'T']);
+        let keys: Vec<(char, ModCode)> = counts.iter().map(|c| (c.strand, c.code)).collect();
+        assert_eq!(
+            keys,
+            [
+                ('+', ModCode::Letter('h')),
+                ('+', ModCode::Letter('m')),
+                ('-', ModCode::Letter('m'))
+            ]
+        );
+        assert_eq!(tally(&counts[0]), [1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(tally(&counts[1]), [0, 0, 1, 0, 0, 0, 0]);
+        // 0.3 against canonical 0.7
+        assert_eq!(tally(&counts[2]), [0, 1, 0, 0, 0, 0, 0]);
+        // The reference G of a CpG is the C on the - strand
+        assert!(!counts[0].cpg && counts[2].cpg);
+    }
+}
@@ -1,113 +1,383 @@
+use crate::alleles::AlleleCounts;
+use crate::dwell::{
+    self, DwellNorm, DwellSummary, DwellUnit, MoveTable, MoveTableMismatch, MoveValidation,
+    StrandDwellSummary, aux_int,
+};
+use crate::filterexpr::FilterExpr;
+use crate::modbase::{self, ModCounts, ModObservation, ModProb};
+use crate::readfilter::{self, FilterReason, TagFilter};
 use crate::region;
+use crate::signal::{SignalSource, SignalStats};
 use anyhow::{Context, Result};
-use rust_htslib::bam::{self, Read};
+use rayon::prelude::*;
+use rust_htslib::bam::{self, Read, record::Aux};
 use rust_htslib::faidx;
-use std::collections::HashMap;
+use rust_htslib::htslib::{BAM_FSECONDARY, BAM_FSUPPLEMENTARY};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-#[derive(Debug, Clone)]
-pub struct BaseInfo {
+// Reads with a broken move table beyond this many are only counted in the run summary
+const MAX_MISMATCH_WARNINGS: u64 = 10;
+
+// Regions are split into chunks of at most this many bases for parallel processing
+const PARALLEL_CHUNK_SIZE: usize = 100_000;
+
+/// View of the aligned base of one read at a reference position, decoded from
+/// the columns of its `CachedRead`.
+#[derive(Debug, Clone, Copy)]
+pub struct BaseInfo<'a> {
     pub base: char,
+    /// Index of the base in `record.seq()`
+    pub query_pos: usize,
     pub qual: u8,
     pub is_reverse: bool,
-    pub insertion: Option<String>,
+    /// Bases inserted after this one, in query order
+    pub insertion: Option<&'a str>,
     pub deletion_len: Option<u32>,
+    /// Indels are reported along the transcript, i.e. this is an RNA read on
+    /// the reverse strand
+    pub transcript_order: bool,
     pub is_head: bool,
     pub is_tail: bool,
     pub mapq: u8,
-    pub mv_value: Option<Vec<i32>>,
+    pub moves: MoveValues,
+    /// Modification probabilities from the `MM`/`ML` tags, empty if none were called
+    pub mods: &'a [ModProb],
+    pub signal_start: Option<u64>,
+    pub signal_end: Option<u64>,
+    pub signal_stats: Option<SignalStats>,
+    read: &'a CachedRead,
+}
+
+impl<'a> BaseInfo<'a> {
+    /// Query positions of the bases in `insertion`, in reported order
+    fn inserted_query_positions(&self) -> impl Iterator<Item = usize> + use<> {
+        let start = self.query_pos + 1;
+        let len = self.insertion.map_or(0, str::len);
+        let transcript_order = self.transcript_order;
+        (0..len).map(move |i| {
+            if transcript_order {
+                start + len - 1 - i
+            } else {
+                start + i
+            }
+        })
+    }
+
+    /// The inserted bases in reported order, empty without an insertion
+    pub fn inserted_bases(&self) -> String {
+        let seq = self.read.seq.as_bytes();
+        self.inserted_query_positions()
+            .map(|q| seq[q] as char)
+            .collect()
+    }
+
+    /// Move-derived values of every inserted base, in the order of `inserted_bases`
+    pub fn inserted_moves(&self) -> impl Iterator<Item = MoveValues> + 'a {
+        let read = self.read;
+        self.inserted_query_positions()
+            .map(move |q| read.values.moves(q))
+    }
 }
 
+/// Move-derived values of one query base; each is None if it was not requested
+/// or the read has no usable move table.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MoveValues {
+    pub mv: Option<i32>,
+    pub dwell: Option<f64>,
+    pub dwell_norm: Option<f64>,
+}
+
+/// Optional per-query-base columns of a read, only filled when requested.
+#[derive(Debug, Default)]
+struct QueryValues {
+    mv: Option<Vec<i32>>,
+    dwell: Option<Vec<Option<f64>>>,
+    dwell_norm: Option<Vec<Option<f64>>>,
+    signal_ranges: Option<Vec<Option<(u64, u64)>>>,
+    signal_stats: Option<Vec<Option<SignalStats>>>,
+    mods: Option<Vec<Vec<ModProb>>>,
+}
+
+impl QueryValues {
+    fn moves(&self, query_pos: usize) -> MoveValues {
+        let at = |v: &Option<Vec<Option<f64>>>| {
+            v.as_ref().and_then(|v| v.get(query_pos).copied().flatten())
+        };
+        MoveValues {
+            mv: self.mv.as_ref().and_then(|v| v.get(query_pos).copied()),
+            dwell: at(&self.dwell),
+            dwell_norm: at(&self.dwell_norm),
+        }
+    }
+}
+
+// Marks reference positions without an aligned read base
+const NO_BASE: u32 = u32::MAX;
+
+/// Haplotype of a read, from the `HP` tag written by haplotagging tools such as
+/// whatshap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Haplotype {
+    Tagged(i64),
+    Untagged,
+}
+
+impl Haplotype {
+    pub fn from_record(record: &bam::Record) -> Self {
+        aux_int(record, b"HP").map_or(Haplotype::Untagged, Haplotype::Tagged)
+    }
+}
+
+impl fmt::Display for Haplotype {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Haplotype::Tagged(hp) => write!(f, "hp{}", hp),
+            Haplotype::Untagged => write!(f, "untagged"),
+        }
+    }
+}
+
+/// Groups reported per position when splitting by haplotype. Reads tagged with
+/// other haplotypes are filtered out, see `FilterReason::Haplotype`.
+pub const HAPLOTYPE_GROUPS: [Haplotype; 3] = [
+    Haplotype::Tagged(1),
+    Haplotype::Tagged(2),
+    Haplotype::Untagged,
+];
+
+/// Identity of one alignment record. A read with supplementary or secondary
+/// alignments has one key per alignment.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AlignmentKey {
+    pub name: String,
+    pub flags: u16,
+    pub pos: i64,
+    pub cigar_hash: u64,
+}
+
+impl AlignmentKey {
+    pub fn from_record(record: &bam::Record) -> Self {
+        let mut hasher = DefaultHasher::new();
+        record.raw_cigar().hash(&mut hasher);
+        Self {
+            name: String::from_utf8_lossy(record.qname()).to_string(),
+            flags: record.flags(),
+            pos: record.pos(),
+            cigar_hash: hasher.finish(),
+        }
+    }
+
+    fn is_primary(&self) -> bool {
+        self.flags as u32 & (BAM_FSECONDARY | BAM_FSUPPLEMENTARY) == 0
+    }
+}
+
+/// A read kept in column form: one query index per reference position, the read
+/// bases and qualities, and the insertions, deletions and requested per-base
+/// values as separate arrays.
 #[derive(Debug)]
 pub struct CachedRead {
     pub _read_id: String,
+    pub key: AlignmentKey,
+    /// Read the alignment was split from by dorado (`pi` tag)
+    pub parent_read_id: Option<String>,
+    pub haplotype: Haplotype,
+    /// Phase set of the haplotype (`PS` tag)
+    pub phase_set: Option<i64>,
     pub ref_start: i64,
     pub ref_end: i64,
-    pub seq_data: Vec<Option<BaseInfo>>,
+    pub is_reverse: bool,
+    /// RNA read on the reverse strand, reported along the transcript
+    transcript_order: bool,
+    pub mapq: u8,
+    /// Reference ranges removed by deletions, half-open
+    pub deletions: Vec<(i64, i64)>,
+    /// Query position aligned to each reference position from `ref_start`
+    ref_to_query: Vec<u32>,
+    /// Read bases as reported, indexed by query position
+    seq: String,
+    qual: Vec<u8>,
+    /// (reference index of the anchor base, length) of each insertion; the
+    /// inserted bases follow the anchor in the query
+    insertions: Vec<(u32, u32)>,
+    /// Reference indexes of the first and last aligned bases
+    head: Option<usize>,
+    tail: Option<usize>,
+    values: QueryValues,
 }
 
 impl CachedRead {
-    pub fn new(record: &bam::Record, output_mv: bool) -> Result<Self> {
-        //check if read seq is in the record if no skip this read
-        if record.seq().len() == 0 {
-            return Ok(Self {
-                _read_id: String::from_utf8_lossy(record.qname()).to_string(),
-                ref_start: record.pos(),
-                ref_end: record.cigar().end_pos(),
-                seq_data: vec![],
-            });
-        }
+    pub fn new(
+        record: &bam::Record,
+        options: &PileupOptions,
+        sample_rate: Option<f64>,
+        signal: Option<&dyn SignalSource>,
+        summary: Option<&mut RunSummary>,
+    ) -> Result<Self> {
         let read_id = String::from_utf8_lossy(record.qname()).to_string();
         let ref_start = record.pos();
         let ref_end = record.cigar().end_pos();
-        let mapq = record.mapq();
         let is_reverse = record.is_reverse();
+        let parent_read_id = match record.aux(b"pi") {
+            Ok(Aux::String(pi)) => Some(pi.to_string()),
+            _ => None,
+        };
+        let mut read = Self {
+            _read_id: read_id,
+            key: AlignmentKey::from_record(record),
+            parent_read_id,
+            haplotype: Haplotype::from_record(record),
+            phase_set: aux_int(record, b"PS"),
+            ref_start,
+            ref_end,
+            is_reverse,
+            transcript_order: options.rna && is_reverse,
+            mapq: record.mapq(),
+            deletions: Vec::new(),
+            ref_to_query: Vec::new(),
+            seq: String::new(),
+            qual: Vec::new(),
+            insertions: Vec::new(),
+            head: None,
+            tail: None,
+            values: QueryValues::default(),
+        };
+        //check if read seq is in the record if no skip this read
+        if record.seq().is_empty() {
+            return Ok(read);
+        }
+        let read_id = read._read_id.as_str();
 
-        // Initialize Vec with None
         let len = (ref_end - ref_start).max(0) as usize;
-        let mut seq_data = vec![None; len];
+        let mut ref_to_query = vec![NO_BASE; len];
 
         let qseq = record.seq();
-        let qual = record.qual();
         let cigar = record.cigar();
         let mut ref_pos = ref_start;
         let mut query_pos = 0;
-        let mut mv_per_query_base: Option<Vec<i32>> = None;
-        if output_mv {
-            if let Ok(mv_tag) = record.aux(b"mv") {
-                let raw_mv_values: Vec<u8> = match mv_tag {
-                    bam::record::Aux::ArrayU8(val) => val.iter().skip(1).map(|x| x as u8).collect(),
-                    bam::record::Aux::ArrayI8(val) => val.iter().skip(1).map(|x| x as u8).collect(),
-                    _ => Vec::new(),
-                };
-
-                if !raw_mv_values.is_empty() {
-                    let qlen = qseq.len();
-                    let mut counts = vec![0; qlen];
-                    let mut base_idx: i32 = -1;
-
-                    for move_val in raw_mv_values {
-                        if move_val == 1 {
-                            base_idx += 1;
+        let move_table = if options.output_mv
+            || options.needs_dwell()
+            || options.dwell_norm != DwellNorm::None
+            || options.output_signal_coords
+            || signal.is_some()
+        {
+            match MoveTable::from_record(record, options.rna) {
+                Ok(move_table) => {
+                    if move_table.is_some()
+                        && let Some(summary) = summary
+                    {
+                        summary.mv_checked_reads += 1;
+                    }
+                    move_table
+                }
+                Err(mismatch) => {
+                    if options.mv_validation == MoveValidation::Strict {
+                        return Err(anyhow::anyhow!(
+                            "Move table of read '{}' does not match its bases: {}",
+                            read_id,
+                            mismatch
+                        ));
+                    }
+                    // Reads reported by another chunk are not counted or warned about again
+                    if let Some(summary) = summary {
+                        summary.add_mismatch(&mismatch);
+                        let warnings = summary.count_mismatch_warning();
+                        if warnings <= MAX_MISMATCH_WARNINGS {
+                            eprintln!(
+                                "Warning: cannot map the move table of read '{}' to its bases ({}); no move-derived values are reported for it.",
+                                read_id, mismatch
+                            );
                         }
-                        if base_idx >= 0 && (base_idx as usize) < qlen {
-                            counts[base_idx as usize] += 1;
+                        if warnings == MAX_MISMATCH_WARNINGS {
+                            eprintln!(
+                                "Warning: further move table mismatches are only counted in the run summary."
+                            );
                         }
                     }
-
-                    if is_reverse {
-                        counts.reverse();
-                    }
-                    mv_per_query_base = Some(counts);
+                    None
                 }
             }
+        } else {
+            None
+        };
+        let raw_signal = match (signal, &move_table) {
+            (Some(source), Some(mt)) => {
+                source.fetch_signal(mt.parent_read_id.as_deref().unwrap_or(read_id))?
+            }
+            _ => None,
+        };
+        let qlen = qseq.len();
+        let mut values = QueryValues::default();
+        if let Some(mt) = &move_table {
+            if options.needs_dwell() {
+                values.dwell = Some(
+                    (0..qlen)
+                        .map(|q| mt.dwell(q, options.dwell_unit, sample_rate))
+                        .collect(),
+                );
+            }
+            if options.dwell_norm != DwellNorm::None {
+                values.dwell_norm =
+                    Some(mt.normalised(options.dwell_norm, options.dwell_norm_window));
+            }
+            if options.output_signal_coords {
+                values.signal_ranges = Some(vec![None; qlen]);
+            }
+            if raw_signal.is_some() {
+                values.signal_stats = Some(vec![None; qlen]);
+            }
+        }
+        if options.needs_mods() {
+            values.mods = modbase::mod_probs(record).unwrap_or_else(|e| {
+                eprintln!(
+                    "Warning: cannot parse the MM/ML tags of read '{}' ({}); no modifications are reported for it.",
+                    read_id, e
+                );
+                None
+            });
         }
-        // println!("Seq data mv: {:?}", mv_per_query_base);
+        // RNA reads on the reverse strand are reported as the transcript base
+        let seq: String = (0..qlen)
+            .map(|q| {
+                let base = b"=ACMGRSVTWYHKDBN"[qseq.encoded_base(q) as usize] as char;
+                if read.transcript_order {
+                    complement(base)
+                } else {
+                    base
+                }
+            })
+            .collect();
         for cigar_entry in cigar.iter() {
             match cigar_entry {
                 bam::record::Cigar::Match(len)
                 | bam::record::Cigar::Equal(len)
                 | bam::record::Cigar::Diff(len) => {
                     for _ in 0..*len {
-                        let base_char =
-                            b"=ACMGRSVTWYHKDBN"[qseq.encoded_base(query_pos) as usize] as char;
-                        let q = qual[query_pos];
-
                         let idx = (ref_pos - ref_start) as usize;
-                        if idx < seq_data.len() {
-                            seq_data[idx] = Some(BaseInfo {
-                                base: base_char,
-                                qual: q,
-                                is_reverse,
-                                insertion: None,
-                                deletion_len: None,
-                                is_head: false,
-                                is_tail: false,
-                                mapq,
-                                mv_value: mv_per_query_base
-                                    .as_ref()
-                                    .and_then(|v| v.get(query_pos).map(|&x| vec![x])),
-                            });
+                        if idx < ref_to_query.len() {
+                            ref_to_query[idx] = query_pos as u32;
+                            let signal_range = move_table
+                                .as_ref()
+                                .and_then(|mt| mt.signal_range(query_pos));
+                            if let Some(ranges) = values.signal_ranges.as_mut() {
+                                ranges[query_pos] = signal_range;
+                            }
+                            if let Some(stats) = values.signal_stats.as_mut() {
+                                stats[query_pos] = raw_signal.as_ref().zip(signal_range).and_then(
+                                    |(signal, (s, e))| {
+                                        let end = (e as usize).min(signal.len());
+                                        SignalStats::from_samples(signal.get(s as usize..end)?)
+                                    },
+                                );
+                            }
                         }
 
                         ref_pos += 1;
@@ -115,50 +385,18 @@ impl CachedRead {
                     }
                 }
                 bam::record::Cigar::Ins(len) => {
-                    if let Some(last_pos) = ref_pos.checked_sub(1) {
-                        let idx = (last_pos - ref_start) as usize;
-                        if idx < seq_data.len() {
-                            if let Some(info) = seq_data[idx].as_mut() {
-                                let mut ins_seq = String::new();
-                                for _ in 0..*len {
-                                    let base_char = b"=ACMGRSVTWYHKDBN"
-                                        [qseq.encoded_base(query_pos) as usize]
-                                        as char;
-                                    ins_seq.push(base_char);
-
-                                    if let Some(mvs) = &mv_per_query_base {
-                                        if let Some(mv_vec) = info.mv_value.as_mut() {
-                                            if let Some(&val) = mvs.get(query_pos) {
-                                                mv_vec.push(val);
-                                            }
-                                        }
-                                    }
-
-                                    query_pos += 1;
-                                }
-                                info.insertion = Some(ins_seq);
-                            } else {
-                                query_pos += *len as usize;
-                            }
-                        } else {
-                            query_pos += *len as usize;
-                        }
-                    } else {
-                        query_pos += *len as usize;
+                    let anchor = ref_pos.checked_sub(ref_start + 1).filter(|&idx| {
+                        ref_to_query
+                            .get(idx as usize)
+                            .is_some_and(|&q| q != NO_BASE)
+                    });
+                    if let Some(idx) = anchor {
+                        read.insertions.push((idx as u32, *len));
                     }
+                    query_pos += *len as usize;
                 }
                 bam::record::Cigar::Del(len) => {
-                    if let Some(last_pos) = ref_pos.checked_sub(1) {
-                        let idx = (last_pos - ref_start) as usize;
-                        if idx < seq_data.len() {
-                            if let Some(info) = seq_data[idx].as_mut() {
-                                info.deletion_len = Some(*len);
-                                if info.mv_value.is_some() {
-                                    info.mv_value.as_mut().unwrap().push(0);
-                                }
-                            }
-                        }
-                    }
+                    read.deletions.push((ref_pos, ref_pos + *len as i64));
                     ref_pos += *len as i64;
                 }
                 bam::record::Cigar::RefSkip(len) => {
@@ -173,40 +411,238 @@ impl CachedRead {
         }
 
         // Mark head and tail
-        // Find first non-None
-        if let Some(info) = seq_data.iter_mut().find_map(|x| x.as_mut()) {
-            info.is_head = true;
-        }
-        // Find last non-None
-        if let Some(info) = seq_data.iter_mut().rev().find_map(|x| x.as_mut()) {
-            info.is_tail = true;
+        read.head = ref_to_query.iter().position(|&q| q != NO_BASE);
+        read.tail = ref_to_query.iter().rposition(|&q| q != NO_BASE);
+        if options.output_mv {
+            values.mv = move_table.map(|mt| mt.counts);
         }
+        read.ref_to_query = ref_to_query;
+        read.seq = seq;
+        read.qual = record.qual().to_vec();
+        read.values = values;
+        Ok(read)
+    }
 
-        Ok(Self {
-            _read_id: read_id,
-            ref_start,
-            ref_end,
-            seq_data,
+    /// Whether reference position `pos` lies within one of the read's deletions.
+    pub fn is_deleted(&self, pos: i64) -> bool {
+        let idx = self.deletions.partition_point(|&(s, _)| s <= pos);
+        idx > 0 && self.deletions[idx - 1].1 > pos
+    }
+
+    /// The aligned base at reference index `idx` (position `ref_start + idx`), if any.
+    pub fn base_info(&self, idx: usize) -> Option<BaseInfo<'_>> {
+        let query_pos = *self.ref_to_query.get(idx).filter(|&&q| q != NO_BASE)? as usize;
+        let insertion = self
+            .insertions
+            .binary_search_by_key(&(idx as u32), |&(anchor, _)| anchor)
+            .ok()
+            .map(|i| &self.seq[query_pos + 1..query_pos + 1 + self.insertions[i].1 as usize]);
+        let anchor_end = self.ref_start + idx as i64 + 1;
+        let deletion_len = self
+            .deletions
+            .binary_search_by_key(&anchor_end, |&(s, _)| s)
+            .ok()
+            .map(|i| (self.deletions[i].1 - self.deletions[i].0) as u32);
+        let signal_range = self
+            .values
+            .signal_ranges
+            .as_ref()
+            .and_then(|v| v[query_pos]);
+        Some(BaseInfo {
+            base: self.seq.as_bytes()[query_pos] as char,
+            query_pos,
+            qual: self.qual[query_pos],
+            is_reverse: self.is_reverse,
+            insertion,
+            deletion_len,
+            transcript_order: self.transcript_order,
+            is_head: self.head == Some(idx),
+            is_tail: self.tail == Some(idx),
+            mapq: self.mapq,
+            moves: self.values.moves(query_pos),
+            mods: self
+                .values
+                .mods
+                .as_ref()
+                .and_then(|m| m.get(query_pos))
+                .map_or(&[], Vec::as_slice),
+            signal_start: signal_range.map(|(s, _)| s),
+            signal_end: signal_range.map(|(_, e)| e),
+            signal_stats: self.values.signal_stats.as_ref().and_then(|v| v[query_pos]),
+            read: self,
+        })
+    }
+}
+
+/// Reverse complement of `seq`, keeping the case of each base.
+fn reverse_complement(seq: &str) -> String {
+    seq.chars()
+        .rev()
+        .map(|b| {
+            let c = complement(b.to_ascii_uppercase());
+            if b.is_ascii_lowercase() {
+                c.to_ascii_lowercase()
+            } else {
+                c
+            }
         })
+        .collect()
+}
+
+fn complement(base: char) -> char {
+    match base {
+        'A' => 'T',
+        'C' => 'G',
+        'G' => 'C',
+        'T' => 'A',
+        'M' => 'K',
+        'K' => 'M',
+        'R' => 'Y',
+        'Y' => 'R',
+        'V' => 'B',
+        'B' => 'V',
+        'H' => 'D',
+        'D' => 'H',
+        other => other,
     }
 }
 
+/// A per-base value of one read at a pileup position, for the aligned base, each
+/// base inserted after it, and the deletion that follows it, if any.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadValues<T> {
+    pub base: Option<T>,
+    pub inserted: Vec<Option<T>>,
+    pub deletion_len: Option<u32>,
+}
+
+impl<T> ReadValues<T> {
+    fn from_base(info: &BaseInfo, value: impl Fn(&MoveValues) -> Option<T>) -> Self {
+        Self {
+            base: value(&info.moves),
+            inserted: info.inserted_moves().map(|m| value(&m)).collect(),
+            deletion_len: info.deletion_len,
+        }
+    }
+
+    /// `base[,+inserted...][,-deletion_len]`, with `missing` for absent values.
+    pub fn format(&self, missing: &str, fmt: impl Fn(&T) -> String) -> String {
+        let value = |v: &Option<T>| v.as_ref().map_or(missing.to_string(), &fmt);
+        let mut out = value(&self.base);
+        for v in &self.inserted {
+            out.push_str(",+");
+            out.push_str(&value(v));
+        }
+        if let Some(len) = self.deletion_len {
+            out.push_str(&format!(",-{}", len));
+        }
+        out
+    }
+}
+
+/// The aligned base of one read at a pileup position.
+#[derive(Debug, Clone)]
+pub struct ReadObservation {
+    pub read_name: String,
+    pub is_reverse: bool,
+    pub query_pos: usize,
+    pub base: char,
+    pub qual: u8,
+    /// Dwell of the base in `dwell_unit`
+    pub dwell: Option<f64>,
+    pub mapq: u8,
+}
+
+/// Cached alignments of a region, kept in (start, name) order so that each
+/// position only visits the alignments overlapping it.
+#[derive(Default)]
 pub struct ReadCache {
-    pub reads: HashMap<String, CachedRead>,
+    /// Reference end of every alignment cached so far, to skip alignments that
+    /// overlapping fetch windows return again
+    ends: HashMap<AlignmentKey, i64>,
+    /// Reads starting after the current position
+    pending: VecDeque<CachedRead>,
+    /// Reads overlapping the current position
+    active: Vec<CachedRead>,
 }
 
 impl ReadCache {
     pub fn new() -> Self {
-        Self {
-            reads: HashMap::new(),
-        }
+        Self::default()
+    }
+
+    pub fn contains(&self, key: &AlignmentKey) -> bool {
+        self.ends.contains_key(key)
+    }
+
+    /// Remember a filtered alignment so that later fetches skip it.
+    pub fn reject(&mut self, key: AlignmentKey, ref_end: i64) {
+        self.ends.insert(key, ref_end);
+    }
+
+    pub fn insert(&mut self, read: CachedRead) {
+        self.ends.insert(read.key.clone(), read.ref_end);
+        let idx = self
+            .pending
+            .partition_point(|r| (r.ref_start, &r.key) <= (read.ref_start, &read.key));
+        self.pending.insert(idx, read);
     }
 
+    /// Forget reads that end at or before `min_ref_pos`.
     pub fn prune(&mut self, min_ref_pos: i64) {
-        self.reads.retain(|_, read| read.ref_end > min_ref_pos);
+        self.ends.retain(|_, ref_end| *ref_end > min_ref_pos);
+    }
+
+    /// Move to reference position `pos` and return the reads overlapping it, in
+    /// order of start and name. Positions must not decrease between calls.
+    pub fn advance(&mut self, pos: i64) -> &[CachedRead] {
+        self.active.retain(|read| read.ref_end > pos);
+        while self
+            .pending
+            .front()
+            .is_some_and(|read| read.ref_start <= pos)
+        {
+            let read = self.pending.pop_front().unwrap();
+            if read.ref_end <= pos {
+                continue;
+            }
+            // Reads cached after the position passed their start go back in order
+            let idx = self
+                .active
+                .partition_point(|r| (r.ref_start, &r.key) <= (read.ref_start, &read.key));
+            self.active.insert(idx, read);
+        }
+        &self.active
     }
 }
 
+/// Indices of the reads that repeat a molecule already covered by another of
+/// `reads`. Only reads for which `contributes` holds compete; per molecule the
+/// primary alignment is kept, then the one with the highest mapping quality,
+/// then the first in cache order.
+fn molecule_duplicates(
+    reads: &[CachedRead],
+    contributes: impl Fn(&CachedRead) -> bool,
+) -> HashSet<usize> {
+    let mut best: HashMap<&str, usize> = HashMap::new();
+    for (i, read) in reads.iter().enumerate() {
+        if !contributes(read) {
+            continue;
+        }
+        let molecule = read.parent_read_id.as_deref().unwrap_or(&read._read_id);
+        let rank = |r: &CachedRead| (r.key.is_primary(), r.mapq);
+        best.entry(molecule)
+            .and_modify(|kept| {
+                if rank(read) > rank(&reads[*kept]) {
+                    *kept = i;
+                }
+            })
+            .or_insert(i);
+    }
+    let kept: HashSet<usize> = best.into_values().collect();
+    (0..reads.len()).filter(|i| !kept.contains(i)).collect()
+}
+
 #[derive(Debug)]
 pub struct PileupPos {
     pub chrom: String,
@@ -217,7 +653,30 @@ pub struct PileupPos {
     pub read_names: Option<Vec<String>>,
     pub map_qualities: Option<Vec<u8>>,
     pub quality_scores: Option<Vec<u8>>,
-    pub mv_values: Option<Vec<String>>,
+    pub mv_values: Option<Vec<ReadValues<i32>>>,
+    pub dwell_values: Option<Vec<ReadValues<f64>>>,
+    pub dwell_norm_values: Option<Vec<ReadValues<f64>>>,
+    pub signal_coords: Option<Vec<Option<(u64, u64)>>>,
+    pub signal_stats: Option<Vec<Option<SignalStats>>>,
+    /// Modification probabilities of the aligned base of every read
+    pub mod_probs: Option<Vec<Vec<ModProb>>>,
+    /// Per-strand modification counts, for bedMethyl output
+    pub mod_counts: Option<Vec<ModCounts>>,
+    pub dwell_summary: Option<StrandDwellSummary>,
+    /// Dwell of the aligned base of every read that has one, in `dwell_unit`
+    pub read_dwell: Option<Vec<f64>>,
+    /// Reference k-mer around the position as read by forward strand reads
+    pub kmer_forward: Option<String>,
+    /// Reverse complement reference k-mer as read by reverse strand reads
+    pub kmer_reverse: Option<String>,
+    /// Per-read view of the position, for long-format export
+    pub observations: Option<Vec<ReadObservation>>,
+    /// Haplotype group of the reads, when splitting by haplotype
+    pub haplotype: Option<Haplotype>,
+    /// Distinct phase sets (`PS` tag) of the reads, when splitting by haplotype
+    pub phase_sets: Option<Vec<i64>>,
+    /// Per-strand allele counts, for the allele count table
+    pub allele_counts: Option<AlleleCounts>,
 }
 
 impl PileupPos {
@@ -225,10 +684,8 @@ impl PileupPos {
         chrom: String,
         pos: usize,
         ref_base: char,
-        output_bq: bool,
-        output_mapq: bool,
-        output_read_name: bool,
-        output_mv: bool,
+        options: &PileupOptions,
+        output_signal_stats: bool,
     ) -> Self {
         Self {
             chrom,
@@ -236,39 +693,387 @@ impl PileupPos {
             ref_base,
             depth: 0,
             bases: Vec::new(),
-            read_names: if output_read_name {
+            read_names: if options.output_read_name {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            map_qualities: if options.output_mapq {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            quality_scores: if options.output_bq {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            mv_values: if options.output_mv {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            dwell_values: if options.output_dwell {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            dwell_norm_values: if options.dwell_norm != DwellNorm::None {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            signal_coords: if options.output_signal_coords {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            signal_stats: if output_signal_stats {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            mod_probs: if options.output_mods {
                 Some(Vec::new())
             } else {
                 None
             },
-            map_qualities: if output_mapq { Some(Vec::new()) } else { None },
-            quality_scores: if output_bq { Some(Vec::new()) } else { None },
-            mv_values: if output_mv { Some(Vec::new()) } else { None },
+            mod_counts: None,
+            dwell_summary: None,
+            read_dwell: if options.keep_read_dwell {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            kmer_forward: None,
+            kmer_reverse: None,
+            observations: if options.keep_observations {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            haplotype: None,
+            phase_sets: if options.split_haplotypes {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            allele_counts: if options.keep_allele_counts {
+                Some(AlleleCounts::default())
+            } else {
+                None
+            },
         }
     }
 }
 
+/// Counters accumulated over all regions of a run, reported once it finishes.
+#[derive(Debug, Clone, Default)]
+pub struct RunSummary {
+    /// Reads whose move table was checked against their bases
+    pub mv_checked_reads: u64,
+    /// Checked reads whose move table did not match their bases
+    pub mv_mismatched_reads: u64,
+    /// Read bases without a move, summed over mismatched reads
+    pub mv_missing_bases: u64,
+    /// Moves called beyond the last read base, summed over mismatched reads
+    pub mv_extra_bases: u64,
+    /// Reads left out of the pileup, by the first filter they failed
+    pub filtered_reads: BTreeMap<FilterReason, u64>,
+    /// Mismatch warnings printed so far, shared with the summaries of parallel chunks
+    mv_warnings: Arc<AtomicU64>,
+}
+
+impl RunSummary {
+    /// An empty summary for one parallel chunk of this run, sharing its warning count.
+    pub fn chunk(&self) -> RunSummary {
+        RunSummary {
+            mv_warnings: Arc::clone(&self.mv_warnings),
+            ..RunSummary::default()
+        }
+    }
+
+    /// Count a move table mismatch warning over the whole run, returning the new total.
+    fn count_mismatch_warning(&self) -> u64 {
+        self.mv_warnings.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn merge(&mut self, other: &RunSummary) {
+        self.mv_checked_reads += other.mv_checked_reads;
+        self.mv_mismatched_reads += other.mv_mismatched_reads;
+        self.mv_missing_bases += other.mv_missing_bases;
+        self.mv_extra_bases += other.mv_extra_bases;
+        for (&reason, &count) in &other.filtered_reads {
+            *self.filtered_reads.entry(reason).or_default() += count;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mv_checked_reads == 0 && self.filtered_reads.is_empty()
+    }
+
+    pub fn add_mismatch(&mut self, mismatch: &MoveTableMismatch) {
+        self.mv_checked_reads += 1;
+        self.mv_mismatched_reads += 1;
+        self.mv_missing_bases += mismatch.missing_bases() as u64;
+        self.mv_extra_bases += mismatch.extra_bases() as u64;
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines = Vec::new();
+        if self.mv_checked_reads > 0 {
+            lines.push(format!(
+                "Move table check: {} reads checked, {} mismatched ({} bases without moves, {} surplus called bases)",
+                self.mv_checked_reads,
+                self.mv_mismatched_reads,
+                self.mv_missing_bases,
+                self.mv_extra_bases
+            ));
+        }
+        if !self.filtered_reads.is_empty() {
+            let counts: Vec<String> = self
+                .filtered_reads
+                .iter()
+                .map(|(reason, count)| format!("{} {}", count, reason))
+                .collect();
+            lines.push(format!(
+                "Filtered reads: {} total ({})",
+                self.filtered_reads.values().sum::<u64>(),
+                counts.join(", ")
+            ));
+        }
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+/// Filtering and output settings shared by the CLI and the Python binding.
+#[derive(Debug, Clone)]
+pub struct PileupOptions {
+    pub min_mapq: u8,
+    pub min_baseq: u8,
+    pub flag_filter: u32,
+    pub buffer_size: usize,
+    pub margin: usize,
+    pub output_bq: bool,
+    pub output_mapq: bool,
+    pub output_read_name: bool,
+    pub output_mv: bool,
+    pub output_dwell: bool,
+    pub dwell_unit: DwellUnit,
+    /// Overrides any sample rate found in the BAM header
+    pub sample_rate: Option<f64>,
+    pub output_signal_coords: bool,
+    /// Report per-strand dwell mean, median, std, MAD and count per position
+    pub output_dwell_summary: bool,
+    /// Within-read normalisation of move-derived dwell, reported as its own column
+    pub dwell_norm: DwellNorm,
+    /// Bases in the rolling normalisation window, 0 normalises against the whole read
+    pub dwell_norm_window: usize,
+    /// Handling of reads whose move table does not match their bases
+    pub mv_validation: MoveValidation,
+    /// Keep the numeric per-read dwell of each position for downstream statistics
+    pub keep_read_dwell: bool,
+    /// Direct RNA reads: moves run 3'->5', U is read as T and reverse strand
+    /// bases are reported on the transcript strand
+    pub rna: bool,
+    /// Length of the reference k-mer reported per position, 0 disables it
+    pub kmer_size: usize,
+    /// Index of the position within the k-mer, defaults to the centre (k / 2)
+    pub kmer_offset: Option<usize>,
+    /// Keep a `ReadObservation` per read and position
+    pub keep_observations: bool,
+    /// Report base modification probabilities from the `MM`/`ML` tags
+    pub output_mods: bool,
+    /// Count modification calls per strand and position
+    pub keep_mod_counts: bool,
+    /// Minimum probability of a read's most likely modification call to count it
+    pub mod_threshold: f64,
+    /// Count each molecule (read name, or `pi` parent read) at most once per
+    /// position when several of its alignments overlap it
+    pub count_molecule_once: bool,
+    /// Minimum read length, including hard-clipped bases
+    pub min_read_length: u32,
+    /// Minimum number of read bases within the alignment
+    pub min_aligned_length: u32,
+    /// Minimum fraction of the read within the alignment
+    pub min_aligned_fraction: f64,
+    /// Maximum gap-compressed divergence, from the `de` or `NM` tag
+    pub max_divergence: Option<f64>,
+    /// Minimum mean read quality, from the `qs` tag or the base qualities
+    pub min_qscore: Option<f64>,
+    /// Tags every read must carry
+    pub include_tags: Vec<TagFilter>,
+    /// Tags that exclude a read
+    pub exclude_tags: Vec<TagFilter>,
+    /// Expression every read must satisfy
+    pub filter_expr: Option<FilterExpr>,
+    /// Report every position once per haplotype group (`HP` tag)
+    pub split_haplotypes: bool,
+    /// Count alleles, deletions and insertions per strand and position
+    pub keep_allele_counts: bool,
+}
+
+impl Default for PileupOptions {
+    fn default() -> Self {
+        Self {
+            min_mapq: 0,
+            min_baseq: 13,
+            flag_filter: 0,
+            buffer_size: 10000,
+            margin: 500,
+            output_bq: false,
+            output_mapq: false,
+            output_read_name: false,
+            output_mv: false,
+            output_dwell: false,
+            dwell_unit: DwellUnit::Samples,
+            sample_rate: None,
+            output_signal_coords: false,
+            output_dwell_summary: false,
+            dwell_norm: DwellNorm::None,
+            dwell_norm_window: 0,
+            mv_validation: MoveValidation::Lenient,
+            keep_read_dwell: false,
+            rna: false,
+            kmer_size: 0,
+            kmer_offset: None,
+            keep_observations: false,
+            output_mods: false,
+            keep_mod_counts: false,
+            mod_threshold: 0.0,
+            count_molecule_once: false,
+            min_read_length: 0,
+            min_aligned_length: 0,
+            min_aligned_fraction: 0.0,
+            max_divergence: None,
+            min_qscore: None,
+            include_tags: Vec::new(),
+            exclude_tags: Vec::new(),
+            filter_expr: None,
+            split_haplotypes: false,
+            keep_allele_counts: false,
+        }
+    }
+}
+
+impl PileupOptions {
+    /// Whether per-base dwell has to be computed for any of the requested outputs.
+    pub fn needs_dwell(&self) -> bool {
+        self.output_dwell
+            || self.output_dwell_summary
+            || self.keep_read_dwell
+            || self.keep_observations
+    }
+
+    /// Whether the `MM`/`ML` tags have to be parsed for any of the requested outputs.
+    pub fn needs_mods(&self) -> bool {
+        self.output_mods || self.keep_mod_counts
+    }
+}
+
+/// Forward and reverse complement k-mers of `seq` that place `idx` at `offset`
+/// in strand orientation. None where the k-mer runs off the sequence.
+fn reference_kmers(
+    seq: &str,
+    idx: usize,
+    k: usize,
+    offset: usize,
+) -> (Option<String>, Option<String>) {
+    let forward = idx
+        .checked_sub(offset)
+        .and_then(|s| seq.get(s..s + k))
+        .map(|kmer| kmer.to_ascii_uppercase());
+    let reverse = idx
+        .checked_sub(k - 1 - offset)
+        .and_then(|s| seq.get(s..s + k))
+        .map(|kmer| {
+            kmer.chars()
+                .rev()
+                .map(|c| complement(c.to_ascii_uppercase()))
+                .collect()
+        });
+    (forward, reverse)
+}
+
 pub fn nanopileup(
     bam_path: &PathBuf,
     region: &region::Region,
     ref_fp: Option<&PathBuf>,
-    min_mapq: u8,
-    min_baseq: u8,
-    flag_filter: u32,
-    buffer_size: usize,
-    margin: usize,
-    output_bq: bool,
-    output_mapq: bool,
-    output_read_name: bool,
-    output_mv: bool,
+    options: &PileupOptions,
+    signal: Option<&dyn SignalSource>,
+    summary: &mut RunSummary,
 ) -> Result<Vec<PileupPos>> {
+    let mut results = Vec::new();
+    nanopileup_stream(bam_path, region, ref_fp, options, signal, summary, |p| {
+        results.push(p);
+        Ok(())
+    })?;
+    Ok(results)
+}
+
+/// Like `nanopileup`, but passes each position to `emit` as soon as its window
+/// is piled up instead of collecting the region, so memory stays bounded by the
+/// reads of one window. An error from `emit` stops the pileup.
+pub fn nanopileup_stream(
+    bam_path: &PathBuf,
+    region: &region::Region,
+    ref_fp: Option<&PathBuf>,
+    options: &PileupOptions,
+    signal: Option<&dyn SignalSource>,
+    summary: &mut RunSummary,
+    mut emit: impl FnMut(PileupPos) -> Result<()>,
+) -> Result<()> {
+    pileup_chunk(
+        bam_path,
+        region,
+        ref_fp,
+        options,
+        signal,
+        summary,
+        i64::MIN..i64::MAX,
+        &mut emit,
+    )
+}
+
+/// Pileup of `region`, adding to `summary` only reads whose alignment starts in
+/// `counted`, so that chunks of one region report each read once.
+#[allow(clippy::too_many_arguments)]
+fn pileup_chunk(
+    bam_path: &PathBuf,
+    region: &region::Region,
+    ref_fp: Option<&PathBuf>,
+    options: &PileupOptions,
+    signal: Option<&dyn SignalSource>,
+    summary: &mut RunSummary,
+    counted: Range<i64>,
+    emit: &mut dyn FnMut(PileupPos) -> Result<()>,
+) -> Result<()> {
+    let min_baseq = options.min_baseq;
+    let buffer_size = options.buffer_size;
+    let margin = options.margin;
     let mut bam = bam::IndexedReader::from_path(bam_path).with_context(|| {
         format!(
             "Failed to open indexed BAM file located at '{}'",
             bam_path.display()
         )
     })?;
-    // let _header = bam.header().clone(); // Clone needed?
+    let header_sample_rates = if options.needs_dwell() && options.sample_rate.is_none() {
+        dwell::read_group_sample_rates(bam.header())
+    } else {
+        HashMap::new()
+    };
+    if options.needs_dwell()
+        && options.dwell_unit == DwellUnit::Millis
+        && options.sample_rate.is_none()
+        && header_sample_rates.is_empty()
+    {
+        return Err(anyhow::anyhow!(
+            "Dwell in milliseconds needs a sample rate, but none was given and the BAM header of '{}' does not provide one",
+            bam_path.display()
+        ));
+    }
 
     let start = region.start;
     let end = region.end;
@@ -281,7 +1086,19 @@ pub fn nanopileup(
         ));
     }
 
-    // Load reference sequence for the region
+    let kmer_offset = options.kmer_offset.unwrap_or(options.kmer_size / 2);
+    if options.kmer_size > 0 && kmer_offset >= options.kmer_size {
+        return Err(anyhow::anyhow!(
+            "K-mer offset {} must be smaller than the k-mer size {}",
+            kmer_offset,
+            options.kmer_size
+        ));
+    }
+
+    // Load reference sequence for the region, with enough flank for k-mers and
+    // CpG contexts at its edges
+    let ref_flank = options.kmer_size.max(1);
+    let ref_start = start.saturating_sub(ref_flank);
     let ref_seq = if let Some(path) = ref_fp {
         if path.exists() {
             let fa_reader = faidx::Reader::from_path(path).with_context(|| {
@@ -292,7 +1109,7 @@ pub fn nanopileup(
             })?;
             Some(
                 fa_reader
-                    .fetch_seq_string(&region.chromosome, start, end - 1)
+                    .fetch_seq_string(&region.chromosome, ref_start, end - 1 + ref_flank)
                     .with_context(|| {
                         format!(
                             "Failed to fetch reference subsequence for {} from '{}'",
@@ -312,11 +1129,24 @@ pub fn nanopileup(
         None
     };
 
+    // RNA references may spell uracil as U, while BAM sequences always use T
+    let ref_seq = ref_seq.map(|seq| {
+        if options.rna {
+            seq.replace('U', "T").replace('u', "t")
+        } else {
+            seq
+        }
+    });
+
     let mut cache = ReadCache::new();
-    let mut results = Vec::new();
+    // Positions are reported once per haplotype group, or once for all reads
+    let haplotypes: Vec<Option<Haplotype>> = if options.split_haplotypes {
+        HAPLOTYPE_GROUPS.iter().copied().map(Some).collect()
+    } else {
+        vec![None]
+    };
 
     for window_start in (start..end).step_by(buffer_size) {
-        // println!("Window start: {}", window_start);
         let window_end = (window_start + buffer_size).min(end);
 
         let fetch_start = window_start.saturating_sub(margin);
@@ -338,7 +1168,6 @@ pub fn nanopileup(
         })?;
 
         for result in bam.records() {
-            // println!("Record: {:?}", result);
             let record = result.with_context(|| {
                 format!(
                     "Failed to read BAM record while processing window {}:{}-{}",
@@ -347,9 +1176,10 @@ pub fn nanopileup(
                     window_end
                 )
             })?;
-            // Skip if already in cache
+            // Skip if already cached or filtered
             let read_id = String::from_utf8_lossy(record.qname()).to_string();
-            if cache.reads.contains_key(&read_id) {
+            let key = AlignmentKey::from_record(&record);
+            if cache.contains(&key) {
                 continue;
             }
 
@@ -361,33 +1191,47 @@ pub fn nanopileup(
                 continue;
             }
 
-            if record.mapq() < min_mapq {
-                continue;
-            }
-            if (record.flags() as u32) & flag_filter != 0 {
+            let is_counted = counted.contains(&record.pos());
+            if let Some(reason) = readfilter::filter_read(&record, options) {
+                if is_counted {
+                    *summary.filtered_reads.entry(reason).or_default() += 1;
+                }
+                cache.reject(key, record.cigar().end_pos());
                 continue;
             }
 
-            let cached_read = CachedRead::new(&record, output_mv).with_context(|| {
-                format!(
-                    "Failed to cache read '{}' while processing region {}",
-                    read_id, region_label
-                )
-            })?;
-            // println!("Cached read: {:?}", cached_read);
-            cache.reads.insert(read_id, cached_read);
+            let sample_rate = options
+                .sample_rate
+                .or_else(|| dwell::record_sample_rate(&record, &header_sample_rates));
+            if sample_rate.is_none()
+                && options.needs_dwell()
+                && options.dwell_unit == DwellUnit::Millis
+            {
+                return Err(anyhow::anyhow!(
+                    "Dwell in milliseconds needs a sample rate, but read '{}' belongs to no read group with sample_rate= in its @RG DS field; pass --sample_rate",
+                    read_id
+                ));
+            }
+            let read_summary = is_counted.then_some(&mut *summary);
+            let cached_read = CachedRead::new(&record, options, sample_rate, signal, read_summary)
+                .with_context(|| {
+                    format!(
+                        "Failed to cache read '{}' while processing region {}",
+                        read_id, region_label
+                    )
+                })?;
+            cache.insert(cached_read);
         }
 
-        // Prune cache: remove reads that end before this window starts
-        // actually we need to keep reads that overlap the window.
-        // If read.ref_end <= window_start, it's done.
-        cache.prune(window_start as i64);
+        // Reads ending before the next window's fetch margin can no longer be
+        // fetched again
+        cache.prune(window_end.saturating_sub(margin) as i64);
 
         // Generate pileup for [window_start, window_end)
         for pos in window_start..window_end {
             // Get ref base
             let ref_base = if let Some(seq) = &ref_seq {
-                let offset = (pos - start) as usize;
+                let offset = pos - ref_start;
                 if offset < seq.len() {
                     seq.as_bytes()[offset] as char
                 } else {
@@ -396,30 +1240,59 @@ pub fn nanopileup(
             } else {
                 'N'
             };
-            let mut p = PileupPos::new(
-                region.chromosome.clone(),
-                pos as usize,
-                ref_base,
-                output_bq,
-                output_mapq,
-                output_read_name,
-                output_mv,
-            );
-
-            // Collect active reads for this position
-            // We need deterministic order.
-            // Sorting all reads every time is slow.
-            // Better: get all keys, sort them, then iterate.
-            let mut active_read_ids: Vec<&String> = cache.reads.keys().collect();
-            active_read_ids.sort(); // Deterministic
+            let reads = cache.advance(pos as i64);
+            let duplicates = if options.count_molecule_once {
+                let counts_deletions = options.keep_mod_counts || options.keep_allele_counts;
+                molecule_duplicates(reads, |read| {
+                    let idx = (pos as i64 - read.ref_start) as usize;
+                    read.base_info(idx)
+                        .is_some_and(|info| info.qual >= min_baseq)
+                        || (counts_deletions && read.is_deleted(pos as i64))
+                })
+            } else {
+                HashSet::new()
+            };
+            for &haplotype in &haplotypes {
+                let mut p = PileupPos::new(
+                    region.chromosome.clone(),
+                    pos,
+                    ref_base,
+                    options,
+                    signal.is_some(),
+                );
+                p.haplotype = haplotype;
+                if options.kmer_size > 0
+                    && let Some(seq) = &ref_seq
+                {
+                    (p.kmer_forward, p.kmer_reverse) =
+                        reference_kmers(seq, pos - ref_start, options.kmer_size, kmer_offset);
+                }
 
-            for read_id in active_read_ids {
-                let read = &cache.reads[read_id];
-                if pos as i64 >= read.ref_start && (pos as i64) < read.ref_end {
+                let mut forward_dwell = Vec::new();
+                let mut reverse_dwell = Vec::new();
+                let mut mod_reads = Vec::new();
+                for (i, read) in reads.iter().enumerate() {
+                    if duplicates.contains(&i) || haplotype.is_some_and(|h| h != read.haplotype) {
+                        continue;
+                    }
                     // Check if we have base info
                     // Calculate index
                     let idx = (pos as i64 - read.ref_start) as usize;
-                    if let Some(Some(info)) = read.seq_data.get(idx) {
+                    if (options.keep_mod_counts || options.keep_allele_counts)
+                        && read.is_deleted(pos as i64)
+                    {
+                        if options.keep_mod_counts {
+                            mod_reads.push(ModObservation {
+                                is_reverse: read.is_reverse,
+                                base: None,
+                                mods: &[],
+                            });
+                        }
+                        if let Some(counts) = p.allele_counts.as_mut() {
+                            counts.strand_mut(read.is_reverse).deletions += 1;
+                        }
+                    }
+                    if let Some(info) = read.base_info(idx) {
                         if info.qual < min_baseq {
                             continue;
                         }
@@ -432,7 +1305,6 @@ pub fn nanopileup(
                             // mapq as char, usually +33
                             base_str.push((info.mapq + 33) as char);
                         }
-                        // println!("Info base: {}", info.base);
                         // The base itself
                         let b = if info.is_reverse {
                             match info.base {
@@ -448,9 +1320,11 @@ pub fn nanopileup(
                         base_str.push(b);
 
                         // Insertion
-                        if let Some(ins) = &info.insertion {
+                        if let Some(ins) = info.insertion {
                             base_str.push('+');
                             base_str.push_str(&ins.len().to_string());
+                            // Reverse strand RNA insertions read along the transcript
+                            let ins = info.inserted_bases();
                             //positive strand uppercase
                             if info.is_reverse {
                                 base_str.push_str(&ins.to_ascii_lowercase());
@@ -464,10 +1338,15 @@ pub fn nanopileup(
                             base_str.push('-');
                             base_str.push_str(&del_len.to_string());
                             if let Some(seq) = &ref_seq {
-                                let start_del = (pos + 1 - start) as usize;
+                                let start_del = pos + 1 - ref_start;
                                 let end_del = start_del + del_len as usize;
                                 if end_del <= seq.len() {
-                                    base_str.push_str(&seq[start_del..end_del]);
+                                    let deleted = &seq[start_del..end_del];
+                                    if info.transcript_order {
+                                        base_str.push_str(&reverse_complement(deleted));
+                                    } else {
+                                        base_str.push_str(deleted);
+                                    }
                                 }
                             }
                         }
@@ -480,43 +1359,804 @@ pub fn nanopileup(
                         p.bases.push(base_str);
                         p.depth += 1;
 
-                        if output_read_name {
-                            if let Some(rn) = p.read_names.as_mut() {
-                                rn.push(read._read_id.clone());
-                            }
+                        if let Some(phase_sets) = p.phase_sets.as_mut()
+                            && let Some(ps) = read.phase_set
+                        {
+                            phase_sets.push(ps);
                         }
-                        if output_mapq {
-                            if let Some(mq) = p.map_qualities.as_mut() {
-                                mq.push(info.mapq);
-                            }
+
+                        if let Some(rn) = p.read_names.as_mut() {
+                            rn.push(read._read_id.clone());
+                        }
+                        if let Some(mq) = p.map_qualities.as_mut() {
+                            mq.push(info.mapq);
+                        }
+                        if let Some(qs) = p.quality_scores.as_mut() {
+                            qs.push(info.qual);
+                        }
+                        if let Some(mvs) = p.mv_values.as_mut() {
+                            mvs.push(ReadValues::from_base(&info, |m| m.mv));
+                        }
+                        if let Some(dwells) = p.dwell_values.as_mut() {
+                            dwells.push(ReadValues::from_base(&info, |m| m.dwell));
+                        }
+                        if let Some(norms) = p.dwell_norm_values.as_mut() {
+                            norms.push(ReadValues::from_base(&info, |m| m.dwell_norm));
+                        }
+                        if let Some(coords) = p.signal_coords.as_mut() {
+                            coords.push(info.signal_start.zip(info.signal_end));
+                        }
+                        if let Some(stats) = p.signal_stats.as_mut() {
+                            stats.push(info.signal_stats);
+                        }
+                        if let Some(mods) = p.mod_probs.as_mut() {
+                            mods.push(info.mods.to_vec());
                         }
-                        if output_bq {
-                            if let Some(qs) = p.quality_scores.as_mut() {
-                                qs.push(info.qual);
+                        if let Some(observations) = p.observations.as_mut() {
+                            observations.push(ReadObservation {
+                                read_name: read._read_id.clone(),
+                                is_reverse: info.is_reverse,
+                                query_pos: info.query_pos,
+                                base: info.base,
+                                qual: info.qual,
+                                dwell: info.moves.dwell,
+                                mapq: info.mapq,
+                            });
+                        }
+                        if options.keep_mod_counts {
+                            // Bases are stored on the reference strand unless reoriented for RNA
+                            let base = if info.is_reverse && !options.rna {
+                                complement(info.base)
+                            } else {
+                                info.base
+                            };
+                            mod_reads.push(ModObservation {
+                                is_reverse: info.is_reverse,
+                                base: Some(base),
+                                mods: info.mods,
+                            });
+                        }
+                        if let Some(counts) = p.allele_counts.as_mut() {
+                            // Reverse strand RNA bases were reoriented to the transcript strand
+                            let base = match info.base {
+                                '=' => ref_base,
+                                b if info.transcript_order => complement(b),
+                                b => b,
+                            };
+                            let strand = counts.strand_mut(info.is_reverse);
+                            strand.add_base(base, info.qual);
+                            if info.insertion.is_some() {
+                                strand.insertions += 1;
                             }
                         }
-                        if output_mv {
-                            if let Some(mvs) = p.mv_values.as_mut() {
-                                if let Some(vals) = &info.mv_value {
-                                    let mut s = String::new();
-                                    for (i, v) in vals.iter().enumerate() {
-                                        if i > 0 {
-                                            s.push_str(",+");
-                                        }
-                                        s.push_str(&v.to_string());
-                                    }
-                                    mvs.push(s);
-                                } else {
-                                    mvs.push("0".to_string());
-                                }
+                        if let Some(read_dwell) = p.read_dwell.as_mut()
+                            && let Some(dwell) = info.moves.dwell
+                        {
+                            read_dwell.push(dwell);
+                        }
+                        if options.output_dwell_summary
+                            && let Some(dwell) = info.moves.dwell
+                        {
+                            if info.is_reverse {
+                                reverse_dwell.push(dwell);
+                            } else {
+                                forward_dwell.push(dwell);
                             }
                         }
                     }
                 }
+                if let Some(phase_sets) = p.phase_sets.as_mut() {
+                    phase_sets.sort_unstable();
+                    phase_sets.dedup();
+                }
+                if options.output_dwell_summary {
+                    p.dwell_summary = Some(StrandDwellSummary {
+                        forward: DwellSummary::from_values(&mut forward_dwell),
+                        reverse: DwellSummary::from_values(&mut reverse_dwell),
+                    });
+                }
+                if options.keep_mod_counts {
+                    let ref_at = |i: Option<usize>| {
+                        ref_seq
+                            .as_ref()
+                            .zip(i)
+                            .and_then(|(seq, i)| seq.as_bytes().get(i))
+                            .map_or('N', |&b| b as char)
+                    };
+                    let offset = pos - ref_start;
+                    let context = [
+                        ref_at(offset.checked_sub(1)),
+                        ref_at(Some(offset)),
+                        ref_at(Some(offset + 1)),
+                    ];
+                    p.mod_counts = Some(modbase::count_mods(
+                        &mod_reads,
+                        options.mod_threshold,
+                        context,
+                    ));
+                }
+                emit(p)?;
             }
-            results.push(p);
         }
     }
 
-    Ok(results)
+    Ok(())
+}
+
+/// Split `region` into consecutive pieces of at most `chunk_size` bases.
+pub fn split_region(region: &region::Region, chunk_size: usize) -> Vec<region::Region> {
+    (region.start..region.end)
+        .step_by(chunk_size.max(1))
+        .map(|start| region::Region {
+            chromosome: region.chromosome.clone(),
+            start,
+            end: (start + chunk_size.max(1)).min(region.end),
+        })
+        .collect()
+}
+
+/// Run `nanopileup` over `regions` and pass the positions to `emit` in region
+/// order. A single thread streams positions as they are piled up; with more
+/// threads, regions are split into chunks that are piled up in parallel, each
+/// with its own BAM reader, and emitted once their batch completes.
+#[allow(clippy::too_many_arguments)]
+pub fn nanopileup_regions(
+    bam_path: &PathBuf,
+    regions: &[region::Region],
+    ref_fp: Option<&PathBuf>,
+    options: &PileupOptions,
+    signal: Option<&dyn SignalSource>,
+    threads: usize,
+    summary: &mut RunSummary,
+    mut emit: impl FnMut(PileupPos) -> Result<()>,
+) -> Result<()> {
+    let run = |region: &region::Region,
+               summary: &mut RunSummary,
+               counted: Range<i64>,
+               emit: &mut dyn FnMut(PileupPos) -> Result<()>| {
+        pileup_chunk(
+            bam_path, region, ref_fp, options, signal, summary, counted, emit,
+        )
+        .with_context(|| {
+            format!(
+                "Failed to run nanopileup for {}:{}-{}",
+                region.chromosome,
+                region.start + 1,
+                region.end
+            )
+        })
+    };
+
+    if threads <= 1 {
+        for region in regions {
+            run(region, summary, i64::MIN..i64::MAX, &mut emit)?;
+        }
+        return Ok(());
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .context("Failed to start the thread pool")?;
+    // Each read is counted in the chunk it starts in; the outer chunks of a region
+    // also count the reads starting in its margins, as a single pass would
+    let chunks: Vec<(region::Region, Range<i64>)> = regions
+        .iter()
+        .flat_map(|region| {
+            let pieces = split_region(region, PARALLEL_CHUNK_SIZE);
+            let last = pieces.len().saturating_sub(1);
+            pieces.into_iter().enumerate().map(move |(i, chunk)| {
+                let from = if i == 0 { i64::MIN } else { chunk.start as i64 };
+                let to = if i == last {
+                    i64::MAX
+                } else {
+                    chunk.end as i64
+                };
+                (chunk, from..to)
+            })
+        })
+        .collect();
+    // Bound memory by only keeping a few chunks per thread in flight
+    for batch in chunks.chunks(threads * 2) {
+        let results: Vec<Result<(Vec<PileupPos>, RunSummary)>> = pool.install(|| {
+            batch
+                .par_iter()
+                .map(|(chunk, counted)| {
+                    let mut chunk_summary = summary.chunk();
+                    let mut results = Vec::new();
+                    run(chunk, &mut chunk_summary, counted.clone(), &mut |p| {
+                        results.push(p);
+                        Ok(())
+                    })?;
+                    Ok((results, chunk_summary))
+                })
+                .collect()
+        });
+        for result in results {
+            let (results, chunk_summary) = result?;
+            summary.merge(&chunk_summary);
+            results.into_iter().try_for_each(&mut emit)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, TempDir};
+    use rust_htslib::bam::record::{AuxArray, Cigar, CigarString};
+
+    fn record(name: &str, pos: i64, len: usize, flags: u16, mapq: u8) -> bam::Record {
+        let mut record = bam::Record::new();
+        let cigar = CigarString(vec![Cigar::Match(len as u32)]);
+        record.set(
+            name.as_bytes(),
+            Some(&cigar),
+            &vec![b'A'; len],
+            &vec![30; len],
+        );
+        record.set_pos(pos);
+        record.set_flags(flags);
+        record.set_mapq(mapq);
+        record
+    }
+
+    fn cached(record: &bam::Record) -> CachedRead {
+        CachedRead::new(record, &PileupOptions::default(), None, None, None).unwrap()
+    }
+
+    fn names(reads: &[CachedRead]) -> Vec<&str> {
+        reads.iter().map(|r| r.key.name.as_str()).collect()
+    }
+
+    /// Soft clip, 3 bases, 2 inserted, 2 bases, 3 deleted, 2 bases, soft clip,
+    /// aligned from position 100, with base quality 10 + query position.
+    fn indel_record() -> bam::Record {
+        let cigar = CigarString(vec![
+            Cigar::SoftClip(2),
+            Cigar::Match(3),
+            Cigar::Ins(2),
+            Cigar::Match(2),
+            Cigar::Del(3),
+            Cigar::Match(2),
+            Cigar::SoftClip(1),
+        ]);
+        let qual: Vec<u8> = (10..22).collect();
+        let mut record = bam::Record::new();
+        record.set(b"read1", Some(&cigar), b"TTACGCCGATAG", &qual);
+        record.set_pos(100);
+        record.set_flags(0);
+        record.set_mapq(60);
+        record
+    }
+
+    #[test]
+    fn base_info_columns() {
+        let read = cached(&indel_record());
+        assert_eq!((read.ref_start, read.ref_end), (100, 110));
+        assert_eq!(read.deletions, [(105, 108)]);
+        let summary = |idx: usize| {
+            read.base_info(idx).map(|info| {
+                format!(
+                    "{} {} {} {:?} {:?} {} {}",
+                    info.base,
+                    info.query_pos,
+                    info.qual,
+                    info.insertion,
+                    info.deletion_len,
+                    info.is_head,
+                    info.is_tail
+                )
+            })
+        };
+        // Soft-clipped bases are skipped; the first aligned base is query position 2
+        assert_eq!(summary(0).unwrap(), "A 2 12 None None true false");
+        assert_eq!(summary(1).unwrap(), "C 3 13 None None false false");
+        // The insertion follows its anchor base in the query
+        assert_eq!(summary(2).unwrap(), r#"G 4 14 Some("CC") None false false"#);
+        assert_eq!(read.base_info(2).unwrap().inserted_bases(), "CC");
+        assert_eq!(summary(3).unwrap(), "G 7 17 None None false false");
+        // The deletion is reported on the base before it
+        assert_eq!(summary(4).unwrap(), "A 8 18 None Some(3) false false");
+        for idx in 5..8 {
+            assert!(read.base_info(idx).is_none());
+            assert!(read.is_deleted(100 + idx as i64));
+        }
+        assert!(!read.is_deleted(104));
+        assert!(!read.is_deleted(108));
+        assert_eq!(summary(8).unwrap(), "T 9 19 None None false false");
+        assert_eq!(summary(9).unwrap(), "A 10 20 None None false true");
+        assert!(read.base_info(10).is_none());
+    }
+
+    #[test]
+    fn read_values_of_a_base() {
+        let mut record = indel_record();
+        let mv = growing_moves(12);
+        record
+            .push_aux(b"mv", Aux::ArrayU8(AuxArray::from(&mv[..])))
+            .unwrap();
+        let options = PileupOptions {
+            output_mv: true,
+            ..PileupOptions::default()
+        };
+        let read = CachedRead::new(&record, &options, None, None, None).unwrap();
+        let mv = |idx| ReadValues::from_base(&read.base_info(idx).unwrap(), |m| m.mv);
+        let fmt = |v: &ReadValues<i32>| v.format(".", i32::to_string);
+        assert_eq!(
+            mv(2),
+            ReadValues {
+                base: Some(5),
+                inserted: vec![Some(6), Some(7)],
+                deletion_len: None,
+            }
+        );
+        assert_eq!(fmt(&mv(2)), "5,+6,+7");
+        assert_eq!(fmt(&mv(4)), "9,-3");
+        assert_eq!(fmt(&mv(9)), "11");
+
+        // Values that were not requested or cannot be derived are missing
+        let dwell = ReadValues::from_base(&read.base_info(2).unwrap(), |m| m.dwell);
+        assert_eq!(dwell.format("NA", f64::to_string), "NA,+NA,+NA");
+        let read = cached(&indel_record());
+        let mv = ReadValues::from_base(&read.base_info(4).unwrap(), |m| m.mv);
+        assert_eq!(mv.format(".", i32::to_string), ".,-3");
+    }
+
+    #[test]
+    fn advance_keeps_start_and_name_order() {
+        let mut cache = ReadCache::new();
+        for (name, pos, len) in [("b", 5, 10), ("c", 0, 3), ("a", 5, 2), ("d", 1, 3)] {
+            cache.insert(cached(&record(name, pos, len, 0, 60)));
+        }
+        assert_eq!(names(cache.advance(0)), ["c"]);
+        assert_eq!(names(cache.advance(2)), ["c", "d"]);
+        assert_eq!(names(cache.advance(5)), ["a", "b"]);
+        assert_eq!(names(cache.advance(7)), ["b"]);
+        // A read fetched late, after the position passed its start, goes back in order
+        cache.insert(cached(&record("e", 3, 10, 0, 60)));
+        cache.insert(cached(&record("f", 4, 2, 0, 60)));
+        assert_eq!(names(cache.advance(8)), ["e", "b"]);
+        assert!(cache.advance(15).is_empty());
+    }
+
+    #[test]
+    fn reads_across_window_boundaries() {
+        let dir = TempDir::new();
+        let bam = dir.join("windows.bam");
+        // (name, start, length, mapq) around windows of 10 bases fetched with a
+        // margin of 2, so most reads are fetched by more than one window
+        let reads = [
+            ("long", 3, 40, 60),
+            ("edge", 10, 10, 60),
+            ("margin", 18, 3, 60),
+            ("short", 21, 2, 60),
+            ("late", 29, 12, 60),
+            ("low", 6, 20, 1),
+        ];
+        let records = reads
+            .iter()
+            .map(|&(name, pos, len, mapq)| {
+                let mut record = testutil::read(
+                    name,
+                    0,
+                    pos,
+                    &[Cigar::Match(len)],
+                    &vec![b'A'; len as usize],
+                );
+                record.set_mapq(mapq);
+                record
+            })
+            .collect();
+        testutil::write_bam(&bam, &[("chr1", 60)], records);
+
+        let options = PileupOptions {
+            min_mapq: 10,
+            buffer_size: 10,
+            margin: 2,
+            output_read_name: true,
+            ..PileupOptions::default()
+        };
+        let (positions, summary) = pileup(&bam, None, "chr1:1-60", &options);
+        assert_eq!(positions.len(), 60);
+        for p in &positions {
+            // Reads are listed in start order, as they are reported
+            let expected: Vec<&str> = reads
+                .iter()
+                .filter(|&&(_, start, len, mapq)| {
+                    mapq >= 10 && (start..start + len as i64).contains(&(p.pos as i64))
+                })
+                .map(|&(name, ..)| name)
+                .collect();
+            assert_eq!(
+                p.read_names.as_ref().unwrap(),
+                &expected,
+                "position {}",
+                p.pos
+            );
+            assert_eq!(p.depth, expected.len());
+        }
+        // The filtered read is fetched by three windows but counted once
+        assert_eq!(summary.filtered_reads[&FilterReason::MapQ], 1);
+    }
+
+    #[test]
+    fn prune_forgets_ended_reads_only() {
+        let mut cache = ReadCache::new();
+        let rejected = record("rejected", 0, 10, 0, 60);
+        let kept = record("kept", 0, 20, 0, 60);
+        cache.reject(AlignmentKey::from_record(&rejected), 10);
+        cache.insert(cached(&kept));
+        // Pruning runs before the window is piled up, so it must not drop reads
+        // that are still to be visited
+        cache.prune(10);
+        assert!(!cache.contains(&AlignmentKey::from_record(&rejected)));
+        assert!(cache.contains(&AlignmentKey::from_record(&kept)));
+        assert_eq!(names(cache.advance(5)), ["kept"]);
+        cache.prune(20);
+        assert!(!cache.contains(&AlignmentKey::from_record(&kept)));
+        assert_eq!(names(cache.advance(19)), ["kept"]);
+    }
+
+    #[test]
+    fn alignment_keys() {
+        let primary = record("read1", 10, 20, 0, 60);
+        let secondary = record("read1", 10, 20, BAM_FSECONDARY as u16, 60);
+        let mut supplementary = record("read1", 40, 8, BAM_FSUPPLEMENTARY as u16, 60);
+        let cigar = CigarString(vec![Cigar::HardClip(12), Cigar::Match(8)]);
+        supplementary.set(b"read1", Some(&cigar), &[b'A'; 8], &[30; 8]);
+        let mut other_secondary = secondary.clone();
+        let cigar = CigarString(vec![Cigar::Match(10), Cigar::Del(1), Cigar::Match(10)]);
+        other_secondary.set(b"read1", Some(&cigar), &[b'A'; 20], &[30; 20]);
+
+        let keys: Vec<AlignmentKey> = [&primary, &secondary, &supplementary, &other_secondary]
+            .into_iter()
+            .map(AlignmentKey::from_record)
+            .collect();
+        // Every alignment of the read has its own key; secondaries at the same
+        // position differ by their CIGAR
+        for (i, a) in keys.iter().enumerate() {
+            for b in &keys[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+        assert_eq!(keys[3].flags, keys[1].flags);
+        assert_eq!(keys[3].pos, keys[1].pos);
+        assert_eq!(AlignmentKey::from_record(&primary.clone()), keys[0]);
+        let primary_flags: Vec<bool> = keys.iter().map(AlignmentKey::is_primary).collect();
+        assert_eq!(primary_flags, [true, false, false, false]);
+    }
+
+    #[test]
+    fn one_alignment_per_molecule() {
+        let mut split = record("child2", 0, 10, 0, 50);
+        split.push_aux(b"pi", Aux::String("parent")).unwrap();
+        let mut other_split = record("child1", 0, 10, 0, 40);
+        other_split.push_aux(b"pi", Aux::String("parent")).unwrap();
+        let reads: Vec<CachedRead> = [
+            record("read1", 0, 10, BAM_FSUPPLEMENTARY as u16, 60),
+            record("read1", 0, 10, 0, 5),
+            record("read1", 0, 10, BAM_FSECONDARY as u16, 30),
+            other_split,
+            split,
+            record("read2", 0, 10, BAM_FSUPPLEMENTARY as u16, 20),
+            record("read2", 0, 10, BAM_FSUPPLEMENTARY as u16 | 0x10, 20),
+        ]
+        .iter()
+        .map(cached)
+        .collect();
+
+        let mut duplicates: Vec<usize> =
+            molecule_duplicates(&reads, |_| true).into_iter().collect();
+        duplicates.sort();
+        // The primary over higher mapping qualities, the best mapped split read,
+        // the first of equally ranked alignments
+        assert_eq!(duplicates, [0, 2, 3, 6]);
+
+        // Alignments that do not contribute at the position do not compete, and
+        // are left out with the duplicates
+        let mut duplicates: Vec<usize> =
+            molecule_duplicates(&reads, |r| !(r.key.name == "read1" && r.key.is_primary()))
+                .into_iter()
+                .collect();
+        duplicates.sort();
+        assert_eq!(duplicates, [1, 2, 3, 6]);
+    }
+
+    #[test]
+    fn chunks_share_the_mismatch_warning_count() {
+        let mut summary = RunSummary::default();
+        let chunks: Vec<RunSummary> = (0..MAX_MISMATCH_WARNINGS * 2)
+            .map(|_| summary.chunk())
+            .collect();
+        let counts: Vec<u64> = chunks
+            .par_iter()
+            .map(|chunk| chunk.count_mismatch_warning())
+            .collect();
+        let warned = counts
+            .iter()
+            .filter(|&&n| n <= MAX_MISMATCH_WARNINGS)
+            .count();
+        assert_eq!(warned as u64, MAX_MISMATCH_WARNINGS);
+        assert_eq!(
+            counts
+                .iter()
+                .filter(|&&n| n == MAX_MISMATCH_WARNINGS)
+                .count(),
+            1
+        );
+        for chunk in &chunks {
+            summary.merge(chunk);
+        }
+        assert_eq!(
+            summary.count_mismatch_warning(),
+            MAX_MISMATCH_WARNINGS * 2 + 1
+        );
+    }
+
+    /// Pile up `region` of `bam` with `ref_fp` as the reference.
+    fn pileup(
+        bam: &PathBuf,
+        fasta: Option<&PathBuf>,
+        region: &str,
+        options: &PileupOptions,
+    ) -> (Vec<PileupPos>, RunSummary) {
+        let mut summary = RunSummary::default();
+        let region = region.parse().unwrap();
+        let positions = nanopileup(bam, &region, fasta, options, None, &mut summary).unwrap();
+        (positions, summary)
+    }
+
+    /// Move table with stride 1 in which base `i` of the signal gets `i + 1` moves.
+    fn growing_moves(qlen: usize) -> Vec<u8> {
+        let mut mv = vec![1];
+        for i in 0..qlen {
+            mv.push(1);
+            mv.extend(std::iter::repeat_n(0, i));
+        }
+        mv
+    }
+
+    #[test]
+    fn reverse_rna_indels_follow_the_transcript() {
+        let dir = TempDir::new();
+        let (bam, fasta) = (dir.join("rna.bam"), dir.join("ref.fa"));
+        testutil::write_fasta(&fasta, &[("tx", "AAAAAAAAAACGTACGTAAAA")]);
+        // CG, TTG inserted, T, AC deleted, GT
+        let cigar = [
+            Cigar::Match(2),
+            Cigar::Ins(3),
+            Cigar::Match(1),
+            Cigar::Del(2),
+            Cigar::Match(2),
+        ];
+        let mut read = testutil::read("r1", 0, 10, &cigar, b"CGTTGTGT");
+        read.set_reverse();
+        read.push_aux(b"mv", Aux::ArrayU8(AuxArray::from(&growing_moves(8)[..])))
+            .unwrap();
+        testutil::write_bam(&bam, &[("tx", 21)], vec![read]);
+
+        let options = PileupOptions {
+            rna: true,
+            output_mv: true,
+            output_dwell: true,
+            ..PileupOptions::default()
+        };
+        let (positions, _) = pileup(&bam, Some(&fasta), "tx:11-17", &options);
+        let mv = |p: &PileupPos| p.mv_values.as_ref().unwrap()[0].format(".", i32::to_string);
+        let dwell = |p: &PileupPos| p.dwell_values.as_ref().unwrap()[0].format(".", f64::to_string);
+        // Inserted bases and their values run along the transcript, from the
+        // last query base of the insertion to the first
+        assert_eq!(positions[1].bases, ["c+3caa"]);
+        assert_eq!(mv(&positions[1]), "2,+5,+4,+3");
+        assert_eq!(dwell(&positions[1]), "2,+5,+4,+3");
+        // Deleted reference bases are reverse complemented
+        assert_eq!(positions[2].bases, ["a-2GT"]);
+        assert_eq!(mv(&positions[2]), "6,-2");
+        assert_eq!(positions[3].depth, 0);
+        assert_eq!(positions[5].bases, ["c"]);
+        assert_eq!(positions[6].bases, ["a$"]);
+        assert_eq!(mv(&positions[6]), "8");
+    }
+
+    #[test]
+    fn haplotype_groups() {
+        let dir = TempDir::new();
+        let bam = dir.join("haplotypes.bam");
+        // (name, HP, PS) of reads over the first 10 bases
+        let tags = [
+            ("a", Some(1), Some(200)),
+            ("b", Some(1), Some(100)),
+            ("c", Some(1), Some(200)),
+            ("d", Some(2), Some(100)),
+            ("e", None, None),
+            ("f", Some(3), Some(100)),
+        ];
+        let records = tags
+            .iter()
+            .map(|&(name, hp, ps)| {
+                let mut record = testutil::read(name, 0, 0, &[Cigar::Match(10)], &[b'A'; 10]);
+                if let Some(hp) = hp {
+                    record.push_aux(b"HP", Aux::U8(hp)).unwrap();
+                }
+                if let Some(ps) = ps {
+                    record.push_aux(b"PS", Aux::I32(ps)).unwrap();
+                }
+                record
+            })
+            .collect();
+        testutil::write_bam(&bam, &[("chr1", 20)], records);
+
+        let options = PileupOptions {
+            split_haplotypes: true,
+            output_read_name: true,
+            ..PileupOptions::default()
+        };
+        let (positions, summary) = pileup(&bam, None, "chr1:10-11", &options);
+        let groups: Vec<String> = positions
+            .iter()
+            .map(|p| {
+                format!(
+                    "{} {} {} {:?} {:?}",
+                    p.pos,
+                    p.haplotype.unwrap(),
+                    p.depth,
+                    p.read_names.as_ref().unwrap(),
+                    p.phase_sets.as_ref().unwrap()
+                )
+            })
+            .collect();
+        assert_eq!(
+            groups,
+            [
+                r#"9 hp1 3 ["a", "b", "c"] [100, 200]"#,
+                r#"9 hp2 1 ["d"] [100]"#,
+                r#"9 untagged 1 ["e"] []"#,
+                "10 hp1 0 [] []",
+                "10 hp2 0 [] []",
+                "10 untagged 0 [] []",
+            ]
+        );
+        // HP=3 has no group, so the read is filtered out and counted
+        assert_eq!(summary.filtered_reads[&FilterReason::Haplotype], 1);
+    }
+
+    #[test]
+    fn streamed_positions_match_the_collected_pileup() {
+        let dir = TempDir::new();
+        let bam = dir.join("stream.bam");
+        let records = vec![
+            testutil::read("a", 0, 5, &[Cigar::Match(20)], &[b'A'; 20]),
+            testutil::read("b", 0, 12, &[Cigar::Match(20)], &[b'C'; 20]),
+            testutil::read("c", 1, 0, &[Cigar::Match(8)], &[b'G'; 8]),
+        ];
+        testutil::write_bam(&bam, &[("chr1", 40), ("chr2", 20)], records);
+        let regions: Vec<region::Region> = ["chr1:1-25", "chr2:1-20", "chr1:20-40"]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect();
+        let options = PileupOptions {
+            output_read_name: true,
+            ..PileupOptions::default()
+        };
+
+        let collected: Vec<String> = regions
+            .iter()
+            .flat_map(|r| {
+                let mut summary = RunSummary::default();
+                nanopileup(&bam, r, None, &options, None, &mut summary).unwrap()
+            })
+            .map(|p| format!("{:?}", p))
+            .collect();
+        assert_eq!(collected.len(), 66);
+        for threads in [1, 3] {
+            let mut streamed = Vec::new();
+            let mut summary = RunSummary::default();
+            nanopileup_regions(
+                &bam,
+                &regions,
+                None,
+                &options,
+                None,
+                threads,
+                &mut summary,
+                |p| {
+                    streamed.push(format!("{:?}", p));
+                    Ok(())
+                },
+            )
+            .unwrap();
+            assert_eq!(streamed, collected, "{} threads", threads);
+        }
+
+        // An error from the callback ends the run
+        for threads in [1, 3] {
+            let mut calls = 0;
+            let mut summary = RunSummary::default();
+            let err = nanopileup_regions(
+                &bam,
+                &regions,
+                None,
+                &options,
+                None,
+                threads,
+                &mut summary,
+                |_| {
+                    calls += 1;
+                    if calls == 3 {
+                        anyhow::bail!("output closed");
+                    }
+                    Ok(())
+                },
+            )
+            .unwrap_err();
+            assert_eq!(calls, 3);
+            assert!(format!("{:#}", err).contains("output closed"));
+        }
+    }
+
+    #[test]
+    fn reference_kmers_in_strand_orientation() {
+        let seq = "AACGTtgCA";
+        // The reverse k-mer is the reverse complement, with the position at the
+        // same offset from its 5' end; soft-masked bases are uppercased
+        let kmers = |idx, k, offset| reference_kmers(seq, idx, k, offset);
+        let some = |f: &str, r: &str| (Some(f.to_string()), Some(r.to_string()));
+        assert_eq!(kmers(3, 3, 1), some("CGT", "ACG"));
+        assert_eq!(kmers(3, 3, 0), some("GTT", "CGT"));
+        assert_eq!(kmers(3, 3, 2), some("ACG", "AAC"));
+        // Even k with the default offset k / 2
+        assert_eq!(kmers(3, 4, 2), some("ACGT", "AACG"));
+        // K-mers running off either end of the sequence are None
+        assert_eq!(kmers(0, 3, 1), (None, None));
+        assert_eq!(kmers(8, 3, 1), (None, None));
+        assert_eq!(kmers(1, 3, 0), (Some("ACG".to_string()), None));
+        assert_eq!(kmers(7, 3, 0), (None, Some("GCA".to_string())));
+    }
+
+    #[test]
+    fn kmers_at_region_and_contig_edges() {
+        let dir = TempDir::new();
+        let (bam, fasta) = (dir.join("kmers.bam"), dir.join("ref.fa"));
+        testutil::write_fasta(&fasta, &[("chr1", "ACGTACGTAC")]);
+        let read = testutil::read("a", 0, 0, &[Cigar::Match(10)], b"ACGTACGTAC");
+        testutil::write_bam(&bam, &[("chr1", 10)], vec![read]);
+        let kmers = |region: &str, kmer_size, kmer_offset| {
+            let options = PileupOptions {
+                kmer_size,
+                kmer_offset,
+                ..PileupOptions::default()
+            };
+            let (positions, _) = pileup(&bam, Some(&fasta), region, &options);
+            positions
+                .into_iter()
+                .map(|p| (p.kmer_forward, p.kmer_reverse))
+                .collect::<Vec<_>>()
+        };
+
+        // Reference flanking the region fills in k-mers at its edges
+        let some = |f: &str, r: &str| (Some(f.to_string()), Some(r.to_string()));
+        assert_eq!(kmers("chr1:4-4", 5, None), [some("CGTAC", "GTACG")]);
+        assert_eq!(kmers("chr1:4-4", 4, None), [some("CGTA", "GTAC")]);
+        // but not beyond the ends of the contig
+        let edges = kmers("chr1:1-10", 3, None);
+        assert_eq!(edges[0], (None, None));
+        assert_eq!(edges[1], some("ACG", "CGT"));
+        assert_eq!(edges[9], (None, None));
+
+        let options = PileupOptions {
+            kmer_size: 3,
+            kmer_offset: Some(3),
+            ..PileupOptions::default()
+        };
+        let region = "chr1:1-10".parse().unwrap();
+        let mut summary = RunSummary::default();
+        let err =
+            nanopileup(&bam, &region, Some(&fasta), &options, None, &mut summary).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "K-mer offset 3 must be smaller than the k-mer size 3"
+        );
+    }
 }
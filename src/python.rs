@@ -1,5 +1,8 @@
-use crate::nanopileup::PileupPos;
-use crate::{nanopileup, region};
+use crate::alleles::StrandAlleleCounts;
+use crate::filterexpr::FilterExpr;
+use crate::nanopileup::{PileupOptions, PileupPos, ReadValues, RunSummary};
+use crate::readfilter::TagFilter;
+use crate::{dwell, nanopileup, region, signal};
 use pyo3::exceptions::{PyRuntimeError, PyValueError};
 use pyo3::prelude::*;
 use pyo3::types::PyModule;
@@ -16,15 +19,32 @@ fn runtime_error(err: anyhow::Error) -> PyErr {
 }
 
 fn collect_regions(
+    bam_path: &PathBuf,
     bed_path: Option<&PathBuf>,
     region_strings: Option<Vec<String>>,
+    whole_genome: bool,
+    contigs: Option<Vec<String>>,
+    contig_regex: Option<&str>,
 ) -> PyResult<Vec<region::Region>> {
+    if whole_genome || contigs.is_some() || contig_regex.is_some() {
+        if bed_path.is_some() || region_strings.is_some() {
+            return Err(PyValueError::new_err(
+                "`whole_genome`, `contigs` and `contig_regex` cannot be combined with `bed_fp` or `regions`.",
+            ));
+        }
+        let pattern = contig_regex
+            .map(|re| regex::Regex::new(&format!("^(?:{})$", re)))
+            .transpose()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        return region::contig_regions(bam_path, &contigs.unwrap_or_default(), pattern.as_ref())
+            .map_err(runtime_error);
+    }
     match (bed_path, region_strings) {
         (Some(_), Some(_)) => Err(PyValueError::new_err(
             "Provide either `bed_fp` or `regions`, not both.",
         )),
         (None, None) => Err(PyValueError::new_err(
-            "You must set `bed_fp` or supply at least one region string.",
+            "You must set `bed_fp`, supply at least one region string, or select contigs.",
         )),
         (Some(path), None) => region::parse_bed_file(path).map_err(runtime_error),
         (None, Some(region_list)) => region_list
@@ -55,8 +75,69 @@ pub struct PyPileupPos {
     map_qualities: Option<Vec<u8>>,
     #[pyo3(get)]
     quality_scores: Option<Vec<u8>>,
+    /// (aligned base, [inserted bases], deletion length) per read
+    #[pyo3(get)]
+    mv_values: Option<Vec<ReadValueTuple<i32>>>,
+    /// (aligned base, [inserted bases], deletion length) per read, in `dwell_unit`
+    #[pyo3(get)]
+    dwell_values: Option<Vec<ReadValueTuple<f64>>>,
+    /// (aligned base, [inserted bases], deletion length) per read
+    #[pyo3(get)]
+    dwell_norm_values: Option<Vec<ReadValueTuple<f64>>>,
+    #[pyo3(get)]
+    signal_coords: Option<Vec<Option<(u64, u64)>>>,
+    /// (mean pA, std pA, sample count) per read
+    #[pyo3(get)]
+    signal_stats: Option<Vec<Option<(f32, f32, u32)>>>,
+    /// (mean, median, std, MAD, count) of forward strand dwell
+    #[pyo3(get)]
+    dwell_summary_forward: Option<(f64, f64, f64, f64, usize)>,
+    /// (mean, median, std, MAD, count) of reverse strand dwell
+    #[pyo3(get)]
+    dwell_summary_reverse: Option<(f64, f64, f64, f64, usize)>,
+    /// (canonical base, strand, code, probability) of every modification call per read
+    #[pyo3(get)]
+    mod_probs: Option<Vec<Vec<ModProbTuple>>>,
+    #[pyo3(get)]
+    kmer_forward: Option<String>,
+    #[pyo3(get)]
+    kmer_reverse: Option<String>,
+    /// "hp1", "hp2" or "untagged" when splitting by haplotype
+    #[pyo3(get)]
+    haplotype: Option<String>,
     #[pyo3(get)]
-    mv_values: Option<Vec<String>>,
+    phase_sets: Option<Vec<i64>>,
+    /// (forward, reverse) strand tuples of ([A, C, G, T, N] counts, deletions,
+    /// insertions, [A, C, G, T, N] mean base quality)
+    #[pyo3(get)]
+    allele_counts: Option<(StrandAlleleTuple, StrandAlleleTuple)>,
+}
+
+type ReadValueTuple<T> = (Option<T>, Vec<Option<T>>, Option<u32>);
+
+fn read_value_tuples<T>(values: Option<Vec<ReadValues<T>>>) -> Option<Vec<ReadValueTuple<T>>> {
+    values.map(|v| {
+        v.into_iter()
+            .map(|r| (r.base, r.inserted, r.deletion_len))
+            .collect()
+    })
+}
+
+type ModProbTuple = (char, char, String, f64);
+
+type StrandAlleleTuple = (Vec<u32>, u32, u32, Vec<Option<f64>>);
+
+fn strand_allele_tuple(s: &StrandAlleleCounts) -> StrandAlleleTuple {
+    (
+        s.bases.to_vec(),
+        s.deletions,
+        s.insertions,
+        (0..s.bases.len()).map(|i| s.mean_qual(i)).collect(),
+    )
+}
+
+fn summary_tuple(s: Option<dwell::DwellSummary>) -> Option<(f64, f64, f64, f64, usize)> {
+    s.map(|s| (s.mean, s.median, s.std, s.mad, s.count))
 }
 
 impl From<PileupPos> for PyPileupPos {
@@ -70,7 +151,36 @@ impl From<PileupPos> for PyPileupPos {
             read_names: pos.read_names,
             map_qualities: pos.map_qualities,
             quality_scores: pos.quality_scores,
-            mv_values: pos.mv_values,
+            mv_values: read_value_tuples(pos.mv_values),
+            dwell_values: read_value_tuples(pos.dwell_values),
+            dwell_norm_values: read_value_tuples(pos.dwell_norm_values),
+            signal_coords: pos.signal_coords,
+            signal_stats: pos.signal_stats.map(|v| {
+                v.into_iter()
+                    .map(|s| s.map(|s| (s.mean, s.std, s.count)))
+                    .collect()
+            }),
+            dwell_summary_forward: summary_tuple(pos.dwell_summary.and_then(|s| s.forward)),
+            dwell_summary_reverse: summary_tuple(pos.dwell_summary.and_then(|s| s.reverse)),
+            mod_probs: pos.mod_probs.map(|v| {
+                v.into_iter()
+                    .map(|mods| {
+                        mods.into_iter()
+                            .map(|m| (m.canonical, m.strand, m.code.to_string(), m.prob))
+                            .collect()
+                    })
+                    .collect()
+            }),
+            kmer_forward: pos.kmer_forward,
+            kmer_reverse: pos.kmer_reverse,
+            haplotype: pos.haplotype.map(|h| h.to_string()),
+            phase_sets: pos.phase_sets,
+            allele_counts: pos.allele_counts.map(|c| {
+                (
+                    strand_allele_tuple(&c.forward),
+                    strand_allele_tuple(&c.reverse),
+                )
+            }),
         }
     }
 }
@@ -90,7 +200,37 @@ impl From<PileupPos> for PyPileupPos {
     output_mapq=false,
     output_read_name=false,
     output_mv=false,
+    output_dwell=false,
+    dwell_unit="samples",
+    sample_rate=None,
+    output_signal_coords=false,
+    output_dwell_summary=false,
+    dwell_norm="none",
+    dwell_norm_window=0,
+    mv_validation="lenient",
+    rna=false,
+    kmer_size=0,
+    kmer_offset=None,
+    output_mods=false,
+    count_molecule_once=false,
+    min_read_length=0,
+    min_aligned_length=0,
+    min_aligned_fraction=0.0,
+    max_divergence=None,
+    min_qscore=None,
+    include_tags=None,
+    exclude_tags=None,
+    expr=None,
+    split_haplotypes=false,
+    allele_counts=false,
+    pod5=None,
+    slow5=None,
+    threads=1,
+    whole_genome=false,
+    contigs=None,
+    contig_regex=None,
 ))]
+#[allow(clippy::too_many_arguments)]
 pub fn run_nanopile(
     bam_fp: &str,
     ref_fp: Option<&str>,
@@ -105,34 +245,134 @@ pub fn run_nanopile(
     output_mapq: bool,
     output_read_name: bool,
     output_mv: bool,
+    output_dwell: bool,
+    dwell_unit: &str,
+    sample_rate: Option<f64>,
+    output_signal_coords: bool,
+    output_dwell_summary: bool,
+    dwell_norm: &str,
+    dwell_norm_window: usize,
+    mv_validation: &str,
+    rna: bool,
+    kmer_size: usize,
+    kmer_offset: Option<usize>,
+    output_mods: bool,
+    count_molecule_once: bool,
+    min_read_length: u32,
+    min_aligned_length: u32,
+    min_aligned_fraction: f64,
+    max_divergence: Option<f64>,
+    min_qscore: Option<f64>,
+    include_tags: Option<Vec<String>>,
+    exclude_tags: Option<Vec<String>>,
+    expr: Option<&str>,
+    split_haplotypes: bool,
+    allele_counts: bool,
+    pod5: Option<&str>,
+    slow5: Option<&str>,
+    threads: usize,
+    whole_genome: bool,
+    contigs: Option<Vec<String>>,
+    contig_regex: Option<&str>,
 ) -> PyResult<Vec<PyPileupPos>> {
     let bam_path = PathBuf::from(bam_fp);
     let reference_path = ref_fp.map(PathBuf::from);
     let bed_path = bed_fp.map(PathBuf::from);
 
-    let regions_to_process = collect_regions(bed_path.as_ref(), regions)?;
+    let regions_to_process = collect_regions(
+        &bam_path,
+        bed_path.as_ref(),
+        regions,
+        whole_genome,
+        contigs,
+        contig_regex,
+    )?;
+    let dwell_unit = dwell_unit
+        .parse::<dwell::DwellUnit>()
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let dwell_norm = dwell_norm
+        .parse::<dwell::DwellNorm>()
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let mv_validation = mv_validation
+        .parse::<dwell::MoveValidation>()
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let parse_tags = |tags: Option<Vec<String>>| {
+        tags.unwrap_or_default()
+            .iter()
+            .map(|t| t.parse::<TagFilter>())
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    };
+    let include_tags = parse_tags(include_tags)?;
+    let exclude_tags = parse_tags(exclude_tags)?;
+    let filter_expr = expr
+        .map(str::parse::<FilterExpr>)
+        .transpose()
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let options = PileupOptions {
+        min_mapq,
+        min_baseq,
+        flag_filter,
+        buffer_size,
+        margin,
+        output_bq,
+        output_mapq,
+        output_read_name,
+        output_mv,
+        output_dwell,
+        dwell_unit,
+        sample_rate,
+        output_signal_coords,
+        output_dwell_summary,
+        dwell_norm,
+        dwell_norm_window,
+        mv_validation,
+        keep_read_dwell: false,
+        rna,
+        kmer_size,
+        kmer_offset,
+        keep_observations: false,
+        output_mods,
+        keep_mod_counts: false,
+        mod_threshold: 0.0,
+        count_molecule_once,
+        min_read_length,
+        min_aligned_length,
+        min_aligned_fraction,
+        max_divergence,
+        min_qscore,
+        include_tags,
+        exclude_tags,
+        filter_expr,
+        split_haplotypes,
+        keep_allele_counts: allele_counts,
+    };
+    let signal_source = signal::open_signal_source(
+        pod5.map(PathBuf::from).as_deref(),
+        slow5.map(PathBuf::from).as_deref(),
+    )
+    .map_err(runtime_error)?;
 
+    let mut summary = RunSummary::default();
     let mut aggregated = Vec::new();
-    for region in regions_to_process {
-        let result = nanopileup::nanopileup(
-            &bam_path,
-            &region,
-            reference_path.as_ref(),
-            min_mapq,
-            min_baseq,
-            flag_filter,
-            buffer_size,
-            margin,
-            output_bq,
-            output_mapq,
-            output_read_name,
-            output_mv,
-        )
-        .map_err(runtime_error)?;
-
-        aggregated.extend(result.into_iter().map(PyPileupPos::from));
-    }
+    nanopileup::nanopileup_regions(
+        &bam_path,
+        &regions_to_process,
+        reference_path.as_ref(),
+        &options,
+        signal_source.as_deref(),
+        threads,
+        &mut summary,
+        |p| {
+            aggregated.push(PyPileupPos::from(p));
+            Ok(())
+        },
+    )
+    .map_err(runtime_error)?;
 
+    if !summary.is_empty() {
+        eprintln!("{}", summary);
+    }
     Ok(aggregated)
 }
 
@@ -142,4 +382,3 @@ fn nanopile(_py: Python, m: Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(run_nanopile, m.clone())?)?;
     Ok(())
 }
-
@@ -0,0 +1,380 @@
+use anyhow::{Result, bail};
+use rust_htslib::bam::{self, record::Aux, record::Cigar};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::dwell::aux_int;
+use crate::nanopileup::{HAPLOTYPE_GROUPS, Haplotype, PileupOptions};
+
+/// Reason a read was left out of the pileup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilterReason {
+    Flag,
+    MapQ,
+    ReadLength,
+    AlignedLength,
+    AlignedFraction,
+    Divergence,
+    QScore,
+    IncludeTag,
+    ExcludeTag,
+    Expression,
+    /// Tagged with a haplotype outside `HAPLOTYPE_GROUPS` while splitting by haplotype
+    Haplotype,
+}
+
+impl fmt::Display for FilterReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            FilterReason::Flag => "flag",
+            FilterReason::MapQ => "mapq",
+            FilterReason::ReadLength => "read length",
+            FilterReason::AlignedLength => "aligned length",
+            FilterReason::AlignedFraction => "aligned fraction",
+            FilterReason::Divergence => "divergence",
+            FilterReason::QScore => "qscore",
+            FilterReason::IncludeTag => "missing included tag",
+            FilterReason::ExcludeTag => "excluded tag",
+            FilterReason::Expression => "expression",
+            FilterReason::Haplotype => "other haplotype",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Aux tag predicate written as `TAG` (tag present) or `TAG:VALUE[,VALUE...]`
+/// (tag equal to one of the values).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagFilter {
+    pub tag: [u8; 2],
+    pub values: Vec<String>,
+}
+
+impl FromStr for TagFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (tag, values) = match s.split_once(':') {
+            Some((tag, values)) => (tag, values.split(',').map(str::to_string).collect()),
+            None => (s, Vec::new()),
+        };
+        let tag = match <[u8; 2]>::try_from(tag.as_bytes()) {
+            Ok(tag) if tag.iter().all(u8::is_ascii_alphanumeric) => tag,
+            _ => bail!("Invalid tag filter '{}', expected TAG or TAG:VALUE", s),
+        };
+        Ok(Self { tag, values })
+    }
+}
+
+impl TagFilter {
+    pub fn matches(&self, record: &bam::Record) -> bool {
+        let Ok(aux) = record.aux(&self.tag) else {
+            return false;
+        };
+        self.values.is_empty() || self.values.iter().any(|v| aux_equals(&aux, v))
+    }
+}
+
+/// Compare a scalar aux value with its SAM text form; arrays never match.
+fn aux_equals(aux: &Aux, value: &str) -> bool {
+    let int = match *aux {
+        Aux::I8(v) => v as i64,
+        Aux::U8(v) => v as i64,
+        Aux::I16(v) => v as i64,
+        Aux::U16(v) => v as i64,
+        Aux::I32(v) => v as i64,
+        Aux::U32(v) => v as i64,
+        Aux::Char(c) => return value.as_bytes() == [c],
+        Aux::String(s) | Aux::HexByteArray(s) => return s == value,
+        Aux::Float(v) => return value.parse::<f64>().is_ok_and(|x| x == v as f64),
+        Aux::Double(v) => return value.parse::<f64>().is_ok_and(|x| x == v),
+        _ => return false,
+    };
+    value.parse::<i64>().is_ok_and(|x| x == int)
+}
+
+fn aux_float(record: &bam::Record, tag: &[u8]) -> Option<f64> {
+    match record.aux(tag).ok()? {
+        Aux::Float(v) => Some(v as f64),
+        Aux::Double(v) => Some(v),
+        _ => aux_int(record, tag).map(|v| v as f64),
+    }
+}
+
+/// Length of the basecalled read, including hard-clipped bases.
+fn read_length(record: &bam::Record) -> u32 {
+    record
+        .cigar()
+        .iter()
+        .map(|op| match op {
+            Cigar::Match(n)
+            | Cigar::Ins(n)
+            | Cigar::SoftClip(n)
+            | Cigar::HardClip(n)
+            | Cigar::Equal(n)
+            | Cigar::Diff(n) => *n,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Read bases within the alignment, i.e. without clipped bases.
+fn aligned_length(record: &bam::Record) -> u32 {
+    record
+        .cigar()
+        .iter()
+        .map(|op| match op {
+            Cigar::Match(n) | Cigar::Ins(n) | Cigar::Equal(n) | Cigar::Diff(n) => *n,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Gap-compressed divergence: the `de` tag written by minimap2, otherwise
+/// derived from `NM` with each insertion or deletion counted as one difference.
+fn divergence(record: &bam::Record) -> Option<f64> {
+    if let Some(de) = aux_float(record, b"de") {
+        return Some(de);
+    }
+    let nm = aux_int(record, b"NM")?;
+    let (mut matches, mut gap_bases, mut gaps) = (0i64, 0i64, 0i64);
+    for op in record.cigar().iter() {
+        match op {
+            Cigar::Match(n) | Cigar::Equal(n) | Cigar::Diff(n) => matches += *n as i64,
+            Cigar::Ins(n) | Cigar::Del(n) => {
+                gap_bases += *n as i64;
+                gaps += 1;
+            }
+            _ => {}
+        }
+    }
+    let columns = matches + gaps;
+    if columns == 0 {
+        return None;
+    }
+    Some((nm - gap_bases + gaps).max(0) as f64 / columns as f64)
+}
+
+/// Mean read quality: the `qs` tag written by the basecaller, otherwise the
+/// Phred value of the mean base error probability.
+fn qscore(record: &bam::Record) -> Option<f64> {
+    if let Some(qs) = aux_float(record, b"qs") {
+        return Some(qs);
+    }
+    let qual = record.qual();
+    if qual.is_empty() || qual[0] == 255 {
+        return None;
+    }
+    let mean_error = qual
+        .iter()
+        .map(|&q| 10f64.powf(-(q as f64) / 10.0))
+        .sum::<f64>()
+        / qual.len() as f64;
+    Some(-10.0 * mean_error.log10())
+}
+
+/// First filter of `options` that rejects `record`, if any. Divergence and
+/// qscore filters keep reads whose value cannot be determined.
+pub fn filter_read(record: &bam::Record, options: &PileupOptions) -> Option<FilterReason> {
+    if (record.flags() as u32) & options.flag_filter != 0 {
+        return Some(FilterReason::Flag);
+    }
+    if record.mapq() < options.min_mapq {
+        return Some(FilterReason::MapQ);
+    }
+    let read_len = read_length(record);
+    if read_len < options.min_read_length {
+        return Some(FilterReason::ReadLength);
+    }
+    let aligned_len = aligned_length(record);
+    if aligned_len < options.min_aligned_length {
+        return Some(FilterReason::AlignedLength);
+    }
+    if options.min_aligned_fraction > 0.0
+        && (read_len == 0 || (aligned_len as f64 / read_len as f64) < options.min_aligned_fraction)
+    {
+        return Some(FilterReason::AlignedFraction);
+    }
+    if let Some(max) = options.max_divergence
+        && divergence(record).is_some_and(|d| d > max)
+    {
+        return Some(FilterReason::Divergence);
+    }
+    if let Some(min) = options.min_qscore
+        && qscore(record).is_some_and(|q| q < min)
+    {
+        return Some(FilterReason::QScore);
+    }
+    if !options.include_tags.iter().all(|f| f.matches(record)) {
+        return Some(FilterReason::IncludeTag);
+    }
+    if options.exclude_tags.iter().any(|f| f.matches(record)) {
+        return Some(FilterReason::ExcludeTag);
+    }
+    if let Some(expr) = &options.filter_expr
+        && !expr.matches(record)
+    {
+        return Some(FilterReason::Expression);
+    }
+    if options.split_haplotypes && !HAPLOTYPE_GROUPS.contains(&Haplotype::from_record(record)) {
+        return Some(FilterReason::Haplotype);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::CigarString;
+
+    /// A 27 base read, 20 of them aligned, with one mismatch, a 1 base
+    /// insertion and a 1 base deletion.
+    fn record() -> bam::Record {
+        let cigar = CigarString(vec![
+            Cigar::HardClip(2),
+            Cigar::SoftClip(3),
+            Cigar::Match(10),
+            Cigar::Ins(1),
+            Cigar::Match(4),
+            Cigar::Del(1),
+            Cigar::Match(5),
+            Cigar::SoftClip(2),
+        ]);
+        let mut record = bam::Record::new();
+        record.set(b"read1", Some(&cigar), &[b'A'; 25], &[20; 25]);
+        record.set_mapq(30);
+        record.push_aux(b"NM", Aux::U8(3)).unwrap();
+        record.push_aux(b"RG", Aux::String("run1")).unwrap();
+        record
+    }
+
+    fn filter(record: &bam::Record, set: impl FnOnce(&mut PileupOptions)) -> Option<FilterReason> {
+        let mut options = PileupOptions::default();
+        set(&mut options);
+        filter_read(record, &options)
+    }
+
+    #[test]
+    fn gap_compressed_divergence() {
+        // minimap2 writes de as 1 - mlen / (blen - gap bases + gap opens), i.e.
+        // mismatches plus gap opens over aligned columns plus gap opens. For
+        // 50M2I30M3D20M with 5 mismatches that is 7 / 102, written as de:f:0.0686
+        let mut mm2 = bam::Record::new();
+        let cigar = CigarString(vec![
+            Cigar::Match(50),
+            Cigar::Ins(2),
+            Cigar::Match(30),
+            Cigar::Del(3),
+            Cigar::Match(20),
+        ]);
+        mm2.set(b"read1", Some(&cigar), &[b'A'; 102], &[20; 102]);
+        mm2.push_aux(b"NM", Aux::U8(10)).unwrap();
+        let derived = divergence(&mm2).unwrap();
+        assert!((derived - 0.0686).abs() < 5e-5, "{}", derived);
+        // The tag wins when present
+        mm2.push_aux(b"de", Aux::Float(0.0686)).unwrap();
+        assert_eq!(divergence(&mm2), Some(0.0686f32 as f64));
+
+        assert_eq!(divergence(&record()), Some(3.0 / 21.0));
+        let mut untagged = bam::Record::new();
+        untagged.set(b"read1", Some(&cigar), &[b'A'; 102], &[20; 102]);
+        assert_eq!(divergence(&untagged), None);
+    }
+
+    #[test]
+    fn mean_qscore() {
+        // The mean error probability, not the mean Phred value
+        let mut record = bam::Record::new();
+        record.set(b"read1", None, b"ACG", &[10, 20, 30]);
+        let mean_error: f64 = (0.1 + 0.01 + 0.001) / 3.0;
+        let derived = qscore(&record).unwrap();
+        assert!((derived - -10.0 * mean_error.log10()).abs() < 1e-9);
+        assert!((derived - 14.318).abs() < 1e-3, "{}", derived);
+
+        record.push_aux(b"qs", Aux::Float(17.5)).unwrap();
+        assert_eq!(qscore(&record), Some(17.5));
+        let mut integer = bam::Record::new();
+        integer.set(b"read1", None, b"ACG", &[10, 20, 30]);
+        integer.push_aux(b"qs", Aux::U8(12)).unwrap();
+        assert_eq!(qscore(&integer), Some(12.0));
+
+        let mut missing = bam::Record::new();
+        missing.set(b"read1", None, b"ACG", &[255; 3]);
+        assert_eq!(qscore(&missing), None);
+    }
+
+    #[test]
+    fn filter_reasons() {
+        let record = record();
+        assert_eq!(filter(&record, |_| {}), None);
+
+        let mut reverse = record.clone();
+        reverse.set_reverse();
+        // The first failing filter is reported
+        let reason = filter(&reverse, |o| {
+            o.flag_filter = 0x10;
+            o.min_mapq = 60;
+        });
+        assert_eq!(reason, Some(FilterReason::Flag));
+        assert_eq!(
+            filter(&record, |o| o.min_mapq = 31),
+            Some(FilterReason::MapQ)
+        );
+        assert_eq!(filter(&record, |o| o.min_mapq = 30), None);
+
+        // Hard clips count towards the read length, clips not towards the aligned length
+        let reason = filter(&record, |o| o.min_read_length = 28);
+        assert_eq!(reason, Some(FilterReason::ReadLength));
+        assert_eq!(filter(&record, |o| o.min_read_length = 27), None);
+        let reason = filter(&record, |o| o.min_aligned_length = 21);
+        assert_eq!(reason, Some(FilterReason::AlignedLength));
+        assert_eq!(filter(&record, |o| o.min_aligned_length = 20), None);
+        let reason = filter(&record, |o| o.min_aligned_fraction = 0.75);
+        assert_eq!(reason, Some(FilterReason::AlignedFraction));
+        assert_eq!(filter(&record, |o| o.min_aligned_fraction = 0.74), None);
+
+        let reason = filter(&record, |o| o.max_divergence = Some(0.14));
+        assert_eq!(reason, Some(FilterReason::Divergence));
+        assert_eq!(filter(&record, |o| o.max_divergence = Some(0.15)), None);
+        let reason = filter(&record, |o| o.min_qscore = Some(20.5));
+        assert_eq!(reason, Some(FilterReason::QScore));
+        assert_eq!(filter(&record, |o| o.min_qscore = Some(19.5)), None);
+
+        let tags = |s: &str| vec![s.parse::<TagFilter>().unwrap()];
+        let reason = filter(&record, |o| o.include_tags = tags("RG:run2,run3"));
+        assert_eq!(reason, Some(FilterReason::IncludeTag));
+        assert_eq!(filter(&record, |o| o.include_tags = tags("RG:run1")), None);
+        let reason = filter(&record, |o| o.exclude_tags = tags("NM:3"));
+        assert_eq!(reason, Some(FilterReason::ExcludeTag));
+        assert_eq!(filter(&record, |o| o.exclude_tags = tags("SA")), None);
+
+        let reason = filter(&record, |o| {
+            o.filter_expr = Some("mapq > 40".parse().unwrap())
+        });
+        assert_eq!(reason, Some(FilterReason::Expression));
+        assert_eq!(
+            filter(&record, |o| o.filter_expr =
+                Some("mapq > 20".parse().unwrap())),
+            None
+        );
+
+        // Only haplotypes without a group are filtered, and only when splitting
+        let mut tagged = record.clone();
+        tagged.push_aux(b"HP", Aux::U8(3)).unwrap();
+        let reason = filter(&tagged, |o| o.split_haplotypes = true);
+        assert_eq!(reason, Some(FilterReason::Haplotype));
+        assert_eq!(filter(&tagged, |_| {}), None);
+        assert_eq!(filter(&record, |o| o.split_haplotypes = true), None);
+    }
+
+    #[test]
+    fn unknown_values_keep_the_read() {
+        let mut record = bam::Record::new();
+        record.set(b"read1", None, b"ACG", &[255; 3]);
+        let reason = filter(&record, |o| {
+            o.max_divergence = Some(0.0);
+            o.min_qscore = Some(40.0);
+        });
+        assert_eq!(reason, None);
+    }
+}
@@ -1,4 +1,6 @@
 use anyhow::{Context, Result};
+use regex::Regex;
+use rust_htslib::bam::{self, Read};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
@@ -105,3 +107,98 @@ pub fn parse_bed_file<P: AsRef<Path>>(path: P) -> Result<Vec<Region>> {
     }
     Ok(regions)
 }
+
+/// Whole-contig regions for the contigs in the header of `bam_path`, in header
+/// order. Without `names` or `pattern` every contig is used; otherwise the
+/// contigs named in `names` or matching `pattern`. Contigs without mapped reads
+/// according to the BAM index are skipped unless they are named in `names`.
+pub fn contig_regions<P: AsRef<Path>>(
+    bam_path: P,
+    names: &[String],
+    pattern: Option<&Regex>,
+) -> Result<Vec<Region>> {
+    let path_ref = bam_path.as_ref();
+    let mut bam = bam::IndexedReader::from_path(path_ref).with_context(|| {
+        format!(
+            "Failed to open indexed BAM file located at '{}'",
+            path_ref.display()
+        )
+    })?;
+    let header = bam.header().clone();
+    let contig_names: Vec<String> = header
+        .target_names()
+        .iter()
+        .map(|name| String::from_utf8_lossy(name).to_string())
+        .collect();
+    if let Some(missing) = names.iter().find(|name| !contig_names.contains(name)) {
+        return Err(anyhow::anyhow!(
+            "Contig '{}' is not in the header of '{}'",
+            missing,
+            path_ref.display()
+        ));
+    }
+
+    let stats = bam.index_stats().with_context(|| {
+        format!(
+            "Failed to read index statistics of '{}'",
+            path_ref.display()
+        )
+    })?;
+    let select_all = names.is_empty() && pattern.is_none();
+    Ok(stats
+        .into_iter()
+        .filter(|&(tid, _, _, _)| tid >= 0)
+        .map(|(tid, len, mapped, _)| (&contig_names[tid as usize], len, mapped))
+        .filter(|(name, _, mapped)| {
+            names.contains(name)
+                || (*mapped > 0 && (select_all || pattern.is_some_and(|re| re.is_match(name))))
+        })
+        .map(|(name, len, _)| Region::new(name.clone(), 0, len as usize))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, TempDir};
+    use rust_htslib::bam::record::Cigar;
+
+    #[test]
+    fn contigs_without_reads() {
+        let dir = TempDir::new();
+        let bam = dir.join("contigs.bam");
+        let contigs = [("chr1", 100), ("chr2", 50), ("chrM", 20), ("scaffold", 30)];
+        let reads = vec![
+            testutil::read("r1", 0, 10, &[Cigar::Match(4)], b"ACGT"),
+            testutil::read("r2", 2, 0, &[Cigar::Match(4)], b"ACGT"),
+        ];
+        testutil::write_bam(&bam, &contigs, reads);
+        let names = |regions: Vec<Region>| -> Vec<String> {
+            regions.into_iter().map(|r| r.chromosome).collect()
+        };
+
+        // Whole genome and patterns skip contigs without mapped reads
+        let regions = contig_regions(&bam, &[], None).unwrap();
+        assert_eq!(regions[0], Region::new("chr1".to_string(), 0, 100));
+        assert_eq!(names(regions), ["chr1", "chrM"]);
+        let re = Regex::new("^chr.*$").unwrap();
+        assert_eq!(
+            names(contig_regions(&bam, &[], Some(&re)).unwrap()),
+            ["chr1", "chrM"]
+        );
+
+        // Named contigs are kept, in header order
+        let named = ["scaffold".to_string(), "chr2".to_string()];
+        let regions = contig_regions(&bam, &named, None).unwrap();
+        assert_eq!(names(regions), ["chr2", "scaffold"]);
+        let regions = contig_regions(&bam, &named[..1], Some(&re)).unwrap();
+        assert_eq!(names(regions), ["chr1", "chrM", "scaffold"]);
+
+        let missing = contig_regions(&bam, &["chr3".to_string()], None).unwrap_err();
+        assert!(
+            missing
+                .to_string()
+                .contains("Contig 'chr3' is not in the header")
+        );
+    }
+}
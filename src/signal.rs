@@ -0,0 +1,99 @@
+use anyhow::{Context, Result};
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+
+mod arrow;
+pub mod pod5;
+pub mod slow5;
+
+pub use pod5::Pod5Reader;
+pub use slow5::Slow5Reader;
+
+/// Random access to the raw signal of reads, independent of the archive format.
+pub trait SignalSource: Send + Sync {
+    /// Number of reads that can be looked up.
+    fn num_reads(&self) -> usize;
+
+    /// Calibrated signal (pA) of `read_id`, None if the read is not present.
+    fn fetch_signal(&self, read_id: &str) -> Result<Option<Vec<f32>>>;
+}
+
+/// Open whichever signal archive was given; at most one of `pod5` and `slow5` may be set.
+pub fn open_signal_source(
+    pod5: Option<&Path>,
+    slow5: Option<&Path>,
+) -> Result<Option<Box<dyn SignalSource>>> {
+    let source: Box<dyn SignalSource> = match (pod5, slow5) {
+        (None, None) => return Ok(None),
+        (Some(_), Some(_)) => {
+            return Err(anyhow::anyhow!(
+                "Provide either a POD5 or a SLOW5/BLOW5 signal input, not both"
+            ));
+        }
+        (Some(path), None) => Box::new(
+            Pod5Reader::open(path)
+                .with_context(|| format!("Failed to open POD5 input '{}'", path.display()))?,
+        ),
+        (None, Some(path)) => Box::new(
+            Slow5Reader::open(path)
+                .with_context(|| format!("Failed to open SLOW5 input '{}'", path.display()))?,
+        ),
+    };
+    if source.num_reads() == 0 {
+        eprintln!(
+            "Warning: signal input '{}' contains no reads; signal statistics will be empty.",
+            pod5.or(slow5).unwrap().display()
+        );
+    }
+    Ok(Some(source))
+}
+
+/// Summary of the raw signal (in pA) that the move table assigns to one base.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SignalStats {
+    pub mean: f32,
+    pub std: f32,
+    pub count: u32,
+}
+
+impl SignalStats {
+    pub fn from_samples(samples: &[f32]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let n = samples.len() as f64;
+        let mean = samples.iter().map(|&x| x as f64).sum::<f64>() / n;
+        let var = samples
+            .iter()
+            .map(|&x| (x as f64 - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        Some(Self {
+            mean: mean as f32,
+            std: var.sqrt() as f32,
+            count: samples.len() as u32,
+        })
+    }
+}
+
+impl fmt::Display for SignalStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2}:{:.2}:{}", self.mean, self.std, self.count)
+    }
+}
+
+/// Decompress a zlib stream whose decompressed size is not known up front.
+pub(crate) fn zlib_decompress(src: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    flate2::read::ZlibDecoder::new(src)
+        .read_to_end(&mut out)
+        .context("zlib decompression failed")?;
+    Ok(out)
+}
+
+/// Decompress a zstd frame, growing the output as data is decoded rather than
+/// trusting a size recorded in the file.
+pub(crate) fn zstd_decompress(src: &[u8]) -> Result<Vec<u8>> {
+    zstd::stream::decode_all(src).context("zstd decompression failed")
+}
@@ -0,0 +1,336 @@
+//! Access to the Arrow IPC files embedded in a POD5 file. Footers, schemas and
+//! record batch messages are parsed with arrow-ipc, which trusts the offsets it
+//! is handed, so every block and buffer is checked against the table first.
+
+use anyhow::{Context, Result};
+use arrow_array::RecordBatch;
+use arrow_buffer::Buffer;
+use arrow_ipc::reader::FileDecoder;
+use arrow_ipc::{Block, MetadataVersion, Precision, Type};
+use arrow_schema::{DataType, Schema};
+use std::fs::File;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+/// Location of one record batch message and of its buffers.
+pub(crate) struct BatchLayout {
+    pub rows: u64,
+    block: Block,
+    /// Offset and length of every buffer within the message body
+    buffers: Vec<(u64, u64)>,
+}
+
+/// A top-level column and the position of its first buffer in every record batch.
+pub(crate) struct Column<'a> {
+    pub data_type: &'a DataType,
+    buffer_index: usize,
+}
+
+/// An Arrow IPC file located at `base` inside a larger file.
+pub(crate) struct ArrowFile {
+    base: u64,
+    schema: Arc<Schema>,
+    version: MetadataVersion,
+    pub batches: Vec<BatchLayout>,
+}
+
+impl ArrowFile {
+    pub(crate) fn open(file: &File, base: u64, len: u64) -> Result<Self> {
+        let file_len = file.metadata()?.len();
+        if len < 10 || base.checked_add(len).is_none_or(|end| end > file_len) {
+            return Err(anyhow::anyhow!(
+                "Embedded table at offset {} with length {} does not fit in the file",
+                base,
+                len
+            ));
+        }
+        let mut tail = [0u8; 10];
+        file.read_exact_at(&mut tail, base + len - 10)
+            .context("Failed to read Arrow file trailer")?;
+        let footer_len = arrow_ipc::reader::read_footer_length(tail)
+            .context("Embedded table is not an Arrow IPC file")? as u64;
+        if footer_len > len - 10 {
+            return Err(anyhow::anyhow!(
+                "Invalid Arrow footer length {}",
+                footer_len
+            ));
+        }
+        let mut footer_buf = vec![0u8; footer_len as usize];
+        file.read_exact_at(&mut footer_buf, base + len - 10 - footer_len)
+            .context("Failed to read Arrow footer")?;
+
+        let footer = arrow_ipc::root_as_footer(&footer_buf)
+            .map_err(|e| anyhow::anyhow!("Malformed Arrow footer: {}", e))?;
+        let ipc_schema = footer
+            .schema()
+            .ok_or_else(|| anyhow::anyhow!("Arrow footer has no schema"))?;
+        // The schema conversion panics on fields it cannot represent rather than returning an error
+        if !ipc_schema
+            .fields()
+            .is_some_and(|fields| fields.iter().all(supported_field))
+        {
+            return Err(anyhow::anyhow!("Unsupported Arrow column type in schema"));
+        }
+        let schema = Arc::new(arrow_ipc::convert::fb_to_schema(ipc_schema));
+        let mut table = Self {
+            base,
+            schema,
+            version: footer.version(),
+            batches: Vec::new(),
+        };
+        for block in footer.recordBatches().into_iter().flatten() {
+            let batch = table
+                .read_layout(file, len, block)
+                .context("Malformed Arrow record batch")?;
+            table.batches.push(batch);
+        }
+        Ok(table)
+    }
+
+    /// Check that the message in `block` lies inside the table and that its
+    /// nodes and buffers fit the schema and the body.
+    fn read_layout(&self, file: &File, len: u64, block: &Block) -> Result<BatchLayout> {
+        let (offset, meta_len, body_len) = (
+            u64::try_from(block.offset())?,
+            u64::try_from(block.metaDataLength())?,
+            u64::try_from(block.bodyLength())?,
+        );
+        if offset
+            .checked_add(meta_len)
+            .and_then(|end| end.checked_add(body_len))
+            .is_none_or(|end| end > len)
+        {
+            return Err(anyhow::anyhow!("Message lies outside the table"));
+        }
+        let mut meta = vec![0u8; meta_len as usize];
+        file.read_exact_at(&mut meta, self.base + offset)?;
+        // Messages start with an optional 0xFFFFFFFF continuation marker and the metadata size
+        let prefix = if meta.starts_with(&[0xff; 4]) { 8 } else { 4 };
+        let message = meta
+            .get(prefix..)
+            .and_then(|fb| arrow_ipc::root_as_message(fb).ok())
+            .ok_or_else(|| anyhow::anyhow!("Unreadable message header"))?;
+        let batch = message
+            .header_as_record_batch()
+            .ok_or_else(|| anyhow::anyhow!("Message is not a record batch"))?;
+        if batch.compression().is_some() {
+            return Err(anyhow::anyhow!(
+                "Compressed Arrow record batches are not supported"
+            ));
+        }
+
+        let buffers = batch
+            .buffers()
+            .into_iter()
+            .flatten()
+            .map(|b| {
+                let (start, length) = (u64::try_from(b.offset())?, u64::try_from(b.length())?);
+                if start.checked_add(length).is_none_or(|end| end > body_len) {
+                    return Err(anyhow::anyhow!("Buffer lies outside the message body"));
+                }
+                Ok((start, length))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let nodes = batch
+            .nodes()
+            .into_iter()
+            .flatten()
+            .map(|node| (node.length(), node.null_count()))
+            .collect::<Vec<_>>();
+        let (mut node_iter, mut buffer_iter) = (nodes.iter(), buffers.iter());
+        for field in self.schema.fields() {
+            check_array(
+                field.data_type(),
+                body_len,
+                &mut node_iter,
+                &mut buffer_iter,
+            )?;
+        }
+        // Every supported array needs at least one bit per value
+        let rows = u64::try_from(batch.length())?;
+        if rows > body_len.saturating_mul(8) {
+            return Err(anyhow::anyhow!("Invalid record batch length {}", rows));
+        }
+
+        Ok(BatchLayout {
+            rows,
+            block: *block,
+            buffers,
+        })
+    }
+
+    pub(crate) fn column(&self, name: &str) -> Result<Column<'_>> {
+        let fields = self.schema.fields();
+        let idx = fields
+            .iter()
+            .position(|f| f.name() == name)
+            .ok_or_else(|| anyhow::anyhow!("Arrow table has no '{}' column", name))?;
+        Ok(Column {
+            data_type: fields[idx].data_type(),
+            buffer_index: fields[..idx]
+                .iter()
+                .map(|f| buffer_count(f.data_type()))
+                .sum(),
+        })
+    }
+
+    /// Decode a whole record batch, keeping only the columns named in `columns`.
+    pub(crate) fn read_batch(
+        &self,
+        file: &File,
+        batch: &BatchLayout,
+        columns: &[&str],
+    ) -> Result<RecordBatch> {
+        let projection = columns
+            .iter()
+            .map(|&name| {
+                self.schema
+                    .index_of(name)
+                    .map_err(|_| anyhow::anyhow!("Arrow table has no '{}' column", name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // Both lengths were checked against the table when the layout was read
+        let size = batch.block.metaDataLength() as u64 + batch.block.bodyLength() as u64;
+        let mut data = vec![0u8; size as usize];
+        file.read_exact_at(&mut data, self.base + batch.block.offset() as u64)
+            .context("Failed to read Arrow record batch")?;
+
+        let decoder =
+            FileDecoder::new(self.schema.clone(), self.version).with_projection(projection);
+        decoder
+            .read_record_batch(&batch.block, &Buffer::from_vec(data))?
+            .ok_or_else(|| anyhow::anyhow!("Arrow message is not a record batch"))
+    }
+
+    /// Read values `range` of the `n`th buffer of `column`, where every value
+    /// takes `width` bytes.
+    pub(crate) fn read_values(
+        &self,
+        file: &File,
+        batch: &BatchLayout,
+        column: &Column,
+        n: usize,
+        width: u64,
+        range: Range<u64>,
+    ) -> Result<Vec<u8>> {
+        let (offset, length) = *batch
+            .buffers
+            .get(column.buffer_index + n)
+            .ok_or_else(|| anyhow::anyhow!("Record batch is missing buffers"))?;
+        let start = range.start.checked_mul(width);
+        let end = range.end.checked_mul(width);
+        let (start, end) = match (start, end) {
+            (Some(start), Some(end)) if start <= end && end <= length => (start, end),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Values {}..{} lie outside an Arrow buffer of {} bytes",
+                    range.start,
+                    range.end,
+                    length
+                ));
+            }
+        };
+        let body_start = batch.block.offset() as u64 + batch.block.metaDataLength() as u64;
+        let mut data = vec![0u8; (end - start) as usize];
+        file.read_exact_at(&mut data, self.base + body_start + offset + start)?;
+        Ok(data)
+    }
+}
+
+/// Number of buffers an array of `data_type` occupies in a record batch.
+fn buffer_count(data_type: &DataType) -> usize {
+    match data_type {
+        DataType::Binary | DataType::LargeBinary | DataType::Utf8 | DataType::LargeUtf8 => 3,
+        DataType::List(child) | DataType::LargeList(child) => 2 + buffer_count(child.data_type()),
+        // Validity and values; dictionary encoded columns carry their indices
+        _ => 2,
+    }
+}
+
+/// Consume the node and buffers of an array of `data_type` and its children,
+/// checking what arrow-ipc would otherwise assert on while decoding.
+fn check_array<'a>(
+    data_type: &DataType,
+    body_len: u64,
+    nodes: &mut impl Iterator<Item = &'a (i64, i64)>,
+    buffers: &mut impl Iterator<Item = &'a (u64, u64)>,
+) -> Result<()> {
+    let &(length, null_count) = nodes
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Record batch has fewer arrays than the schema"))?;
+    // Every supported array needs at least one bit per value
+    if !(0..=length).contains(&null_count) || length as u64 > body_len.saturating_mul(8) {
+        return Err(anyhow::anyhow!(
+            "Invalid array length {} with {} nulls",
+            length,
+            null_count
+        ));
+    }
+    let child = match data_type {
+        DataType::List(child) | DataType::LargeList(child) => Some(child.data_type()),
+        _ => None,
+    };
+    let own = buffer_count(data_type) - child.map_or(0, buffer_count);
+    for i in 0..own {
+        let &(_, buffer_len) = buffers
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Record batch has fewer buffers than the schema"))?;
+        if i == 0 && null_count > 0 && buffer_len.saturating_mul(8) < length as u64 {
+            return Err(anyhow::anyhow!("Validity bitmap is shorter than its array"));
+        }
+        // Offsets and values after the validity bitmap must be whole values
+        let width = match (data_type, i) {
+            (_, 0) => 1,
+            (DataType::Binary | DataType::Utf8 | DataType::List(_), 1) => 4,
+            (DataType::LargeBinary | DataType::LargeUtf8 | DataType::LargeList(_), 1) => 8,
+            (DataType::Dictionary(key, _), _) => key.primitive_width().unwrap_or(1),
+            _ => data_type.primitive_width().unwrap_or(1),
+        };
+        if buffer_len % width as u64 != 0 {
+            return Err(anyhow::anyhow!(
+                "Buffer of {} bytes does not hold whole {} byte values",
+                buffer_len,
+                width
+            ));
+        }
+    }
+    match child {
+        Some(child) => check_array(child, body_len, nodes, buffers),
+        None => Ok(()),
+    }
+}
+
+/// Whether arrow-ipc can convert `field` to a schema field without panicking,
+/// limited to the types found in POD5 tables.
+fn supported_field(field: arrow_ipc::Field) -> bool {
+    if field.name().is_none() {
+        return false;
+    }
+    if let Some(dictionary) = field.dictionary()
+        && !dictionary
+            .indexType()
+            .is_some_and(|int| matches!(int.bitWidth(), 8 | 16 | 32 | 64))
+    {
+        return false;
+    }
+    match field.type_type() {
+        Type::Bool | Type::Binary | Type::LargeBinary | Type::Utf8 | Type::LargeUtf8 => true,
+        Type::Int => field
+            .type_as_int()
+            .is_some_and(|int| matches!(int.bitWidth(), 8 | 16 | 32 | 64)),
+        Type::FloatingPoint => field.type_as_floating_point().is_some_and(|float| {
+            matches!(
+                float.precision(),
+                Precision::HALF | Precision::SINGLE | Precision::DOUBLE
+            )
+        }),
+        Type::FixedSizeBinary => field
+            .type_as_fixed_size_binary()
+            .is_some_and(|fsb| fsb.byteWidth() > 0),
+        Type::List | Type::LargeList => field
+            .children()
+            .is_some_and(|children| children.len() == 1 && supported_field(children.get(0))),
+        _ => false,
+    }
+}
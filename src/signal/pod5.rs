@@ -0,0 +1,556 @@
+use super::SignalSource;
+use super::arrow::ArrowFile;
+use anyhow::{Context, Result};
+use arrow_array::RecordBatch;
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Float32Type, UInt64Type};
+use arrow_schema::DataType;
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+const POD5_SIGNATURE: [u8; 8] = [0x8b, b'P', b'O', b'D', b'\r', b'\n', 0x1a, b'\n'];
+const FOOTER_MAGIC: &[u8; 8] = b"FOOTER\0\0";
+const SECTION_MARKER_LEN: u64 = 16;
+
+// ContentType values of the embedded files listed in the POD5 footer
+const READS_TABLE: i16 = 0;
+const SIGNAL_TABLE: i16 = 1;
+
+struct Pod5File {
+    path: PathBuf,
+    file: File,
+    signal_table: ArrowFile,
+    /// First signal table row of every record batch
+    batch_starts: Vec<u64>,
+    /// Signal chunks are VBZ compressed rather than plain int16 lists
+    vbz: bool,
+}
+
+struct ReadEntry {
+    file: usize,
+    signal_rows: Vec<u64>,
+    calibration_offset: f32,
+    calibration_scale: f32,
+}
+
+/// Raw signal lookup by read ID over one POD5 file or a directory of them.
+pub struct Pod5Reader {
+    files: Vec<Pod5File>,
+    reads: HashMap<[u8; 16], ReadEntry>,
+}
+
+impl Pod5Reader {
+    /// Open `path`, which is either a single POD5 file or a directory containing `.pod5` files.
+    pub fn open(path: &Path) -> Result<Self> {
+        let paths = if path.is_dir() {
+            let mut paths = std::fs::read_dir(path)
+                .with_context(|| format!("Failed to list POD5 directory '{}'", path.display()))?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|ext| ext == "pod5"))
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        } else {
+            vec![path.to_path_buf()]
+        };
+        if paths.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No .pod5 files found in '{}'",
+                path.display()
+            ));
+        }
+
+        let mut reader = Self {
+            files: Vec::new(),
+            reads: HashMap::new(),
+        };
+        for p in paths {
+            reader
+                .add_file(&p)
+                .with_context(|| format!("Failed to index POD5 file '{}'", p.display()))?;
+        }
+        Ok(reader)
+    }
+
+    fn add_file(&mut self, path: &Path) -> Result<()> {
+        let file = File::open(path)?;
+        let (reads_table, signal_table) = open_tables(&file)?;
+
+        let vbz = match signal_table.column("signal")?.data_type {
+            DataType::LargeBinary => true,
+            DataType::LargeList(child) if *child.data_type() == DataType::Int16 => false,
+            other => {
+                return Err(anyhow::anyhow!(
+                    "Unsupported POD5 signal column type {}",
+                    other
+                ));
+            }
+        };
+        if signal_table.column("samples")?.data_type != &DataType::UInt32 {
+            return Err(anyhow::anyhow!(
+                "POD5 samples column is not a uint32 column"
+            ));
+        }
+        let mut batch_starts = Vec::with_capacity(signal_table.batches.len());
+        let mut total = 0u64;
+        for batch in &signal_table.batches {
+            batch_starts.push(total);
+            total += batch.rows;
+        }
+
+        let file_idx = self.files.len();
+        for layout in &reads_table.batches {
+            let batch = reads_table.read_batch(
+                &file,
+                layout,
+                &[
+                    "read_id",
+                    "signal",
+                    "calibration_offset",
+                    "calibration_scale",
+                ],
+            )?;
+            let ids = batch
+                .column(0)
+                .as_fixed_size_binary_opt()
+                .filter(|ids| ids.value_length() == 16)
+                .ok_or_else(|| anyhow::anyhow!("POD5 read_id column is not a 16 byte UUID"))?;
+            let rows = batch
+                .column(1)
+                .as_list_opt::<i32>()
+                .filter(|rows| rows.value_type() == DataType::UInt64)
+                .ok_or_else(|| {
+                    anyhow::anyhow!("POD5 signal column is not a list of row indices")
+                })?;
+            let offsets = float_column(&batch, 2)?;
+            let scales = float_column(&batch, 3)?;
+
+            for i in 0..batch.num_rows() {
+                let id: [u8; 16] = ids.value(i).try_into().unwrap();
+                let signal_rows = rows.value(i).as_primitive::<UInt64Type>().values().to_vec();
+                self.reads.entry(id).or_insert(ReadEntry {
+                    file: file_idx,
+                    signal_rows,
+                    calibration_offset: offsets.value(i),
+                    calibration_scale: scales.value(i),
+                });
+            }
+        }
+
+        self.files.push(Pod5File {
+            path: path.to_path_buf(),
+            file,
+            signal_table,
+            batch_starts,
+            vbz,
+        });
+        Ok(())
+    }
+}
+
+impl SignalSource for Pod5Reader {
+    fn num_reads(&self) -> usize {
+        self.reads.len()
+    }
+
+    fn fetch_signal(&self, read_id: &str) -> Result<Option<Vec<f32>>> {
+        let Some(entry) = parse_uuid(read_id).and_then(|id| self.reads.get(&id)) else {
+            return Ok(None);
+        };
+        let pod5 = &self.files[entry.file];
+        let mut signal = Vec::new();
+        for &row in &entry.signal_rows {
+            let chunk = pod5.read_chunk(row).with_context(|| {
+                format!(
+                    "Failed to read signal of read '{}' from '{}'",
+                    read_id,
+                    pod5.path.display()
+                )
+            })?;
+            signal.extend(
+                chunk
+                    .into_iter()
+                    .map(|x| (x as f32 + entry.calibration_offset) * entry.calibration_scale),
+            );
+        }
+        Ok(Some(signal))
+    }
+}
+
+impl Pod5File {
+    fn read_chunk(&self, row: u64) -> Result<Vec<i16>> {
+        let batch_idx = self
+            .batch_starts
+            .partition_point(|&start| start <= row)
+            .checked_sub(1)
+            .filter(|&i| row - self.batch_starts[i] < self.signal_table.batches[i].rows)
+            .ok_or_else(|| anyhow::anyhow!("Signal row {} is out of range", row))?;
+        let batch = &self.signal_table.batches[batch_idx];
+        let local = row - self.batch_starts[batch_idx];
+        let table = &self.signal_table;
+        let signal_col = table.column("signal")?;
+
+        let bounds = table.read_values(&self.file, batch, &signal_col, 1, 8, local..local + 2)?;
+        let start = i64::from_le_bytes(bounds[..8].try_into().unwrap());
+        let end = i64::from_le_bytes(bounds[8..].try_into().unwrap());
+        let (Ok(start), Ok(end)) = (u64::try_from(start), u64::try_from(end)) else {
+            return Err(anyhow::anyhow!(
+                "Invalid signal offsets {}..{} in row {}",
+                start,
+                end,
+                row
+            ));
+        };
+
+        if self.vbz {
+            let samples_col = table.column("samples")?;
+            let count =
+                table.read_values(&self.file, batch, &samples_col, 1, 4, local..local + 1)?;
+            let count = u32::from_le_bytes(count[..].try_into().unwrap()) as usize;
+            let compressed = table.read_values(&self.file, batch, &signal_col, 2, 1, start..end)?;
+            decode_vbz(&compressed, count)
+        } else {
+            let raw = table.read_values(&self.file, batch, &signal_col, 3, 2, start..end)?;
+            Ok(raw
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                .collect())
+        }
+    }
+}
+
+/// Read-only view of a flatbuffer table, enough to walk the POD5 footer.
+#[derive(Clone, Copy)]
+struct Table<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Table<'a> {
+    fn root(buf: &'a [u8]) -> Option<Self> {
+        Some(Self {
+            buf,
+            pos: read_u32(buf, 0)? as usize,
+        })
+    }
+
+    /// Absolute position of field `id`, None if the field is absent.
+    fn field(&self, id: usize) -> Option<usize> {
+        let vtable = self
+            .pos
+            .checked_add_signed(-(read_i32(self.buf, self.pos)? as isize))?;
+        let vtable_len = read_u16(self.buf, vtable)? as usize;
+        let entry = 4 + 2 * id;
+        if entry + 2 > vtable_len {
+            return None;
+        }
+        match read_u16(self.buf, vtable + entry)? {
+            0 => None,
+            off => Some(self.pos + off as usize),
+        }
+    }
+
+    fn scalar<const N: usize>(&self, id: usize) -> Option<[u8; N]> {
+        let pos = self.field(id)?;
+        self.buf.get(pos..pos + N)?.try_into().ok()
+    }
+
+    fn i16(&self, id: usize, default: i16) -> i16 {
+        self.scalar(id).map_or(default, i16::from_le_bytes)
+    }
+
+    fn i64(&self, id: usize, default: i64) -> i64 {
+        self.scalar(id).map_or(default, i64::from_le_bytes)
+    }
+
+    /// The tables in the vector of field `id`.
+    fn tables(&self, id: usize) -> Vec<Table<'a>> {
+        let indirect = |pos: usize| Some(pos + read_u32(self.buf, pos)? as usize);
+        let Some(start) = self.field(id).and_then(indirect) else {
+            return Vec::new();
+        };
+        let len = read_u32(self.buf, start).unwrap_or(0) as usize;
+        (0..len)
+            .map_while(|i| indirect(start + 4 + 4 * i))
+            .map(|pos| Table { buf: self.buf, pos })
+            .collect()
+    }
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(buf.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(buf.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn read_i32(buf: &[u8], pos: usize) -> Option<i32> {
+    Some(i32::from_le_bytes(buf.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+/// Locate the reads and signal tables through the flatbuffer footer at the end of the file.
+fn open_tables(file: &File) -> Result<(ArrowFile, ArrowFile)> {
+    let file_len = file.metadata()?.len();
+    let mut signature = [0u8; 8];
+    file.read_exact_at(&mut signature, 0)?;
+    if signature != POD5_SIGNATURE || file_len < 3 * 8 + SECTION_MARKER_LEN + 8 {
+        return Err(anyhow::anyhow!("Not a POD5 file"));
+    }
+
+    // Layout of the tail: footer magic, footer padded to 8 bytes, unpadded
+    // footer length (i64), section marker, signature
+    let len_pos = file_len - 8 - SECTION_MARKER_LEN - 8;
+    let mut len_bytes = [0u8; 8];
+    file.read_exact_at(&mut len_bytes, len_pos)?;
+    let footer_len = u64::try_from(i64::from_le_bytes(len_bytes))
+        .ok()
+        .filter(|&len| len.next_multiple_of(8) <= len_pos - 8)
+        .ok_or_else(|| anyhow::anyhow!("Invalid POD5 footer length"))?;
+    let footer_pos = len_pos - footer_len.next_multiple_of(8);
+    let mut magic = [0u8; 8];
+    file.read_exact_at(&mut magic, footer_pos - 8)?;
+    if &magic != FOOTER_MAGIC {
+        return Err(anyhow::anyhow!("POD5 footer not found"));
+    }
+    let mut footer = vec![0u8; footer_len as usize];
+    file.read_exact_at(&mut footer, footer_pos)?;
+
+    let root = Table::root(&footer).ok_or_else(|| anyhow::anyhow!("Malformed POD5 footer"))?;
+    let mut reads_table = None;
+    let mut signal_table = None;
+    for embedded in root.tables(3) {
+        let (Ok(offset), Ok(length)) = (
+            u64::try_from(embedded.i64(0, 0)),
+            u64::try_from(embedded.i64(1, 0)),
+        ) else {
+            return Err(anyhow::anyhow!(
+                "Invalid embedded table location in POD5 footer"
+            ));
+        };
+        match embedded.i16(3, READS_TABLE) {
+            READS_TABLE => reads_table = Some(ArrowFile::open(file, offset, length)?),
+            SIGNAL_TABLE => signal_table = Some(ArrowFile::open(file, offset, length)?),
+            _ => {}
+        }
+    }
+    match (reads_table, signal_table) {
+        (Some(reads), Some(signal)) => Ok((reads, signal)),
+        _ => Err(anyhow::anyhow!(
+            "POD5 footer does not list both a reads and a signal table"
+        )),
+    }
+}
+
+fn float_column(batch: &RecordBatch, idx: usize) -> Result<&arrow_array::Float32Array> {
+    batch
+        .column(idx)
+        .as_primitive_opt::<Float32Type>()
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "POD5 column '{}' is not a float32 column",
+                batch.schema().field(idx).name()
+            )
+        })
+}
+
+/// Decode a VBZ chunk: zstd over svb16 (stream VByte, 1 or 2 bytes per value) of
+/// zigzag encoded sample deltas.
+fn decode_vbz(compressed: &[u8], count: usize) -> Result<Vec<i16>> {
+    let encoded = super::zstd_decompress(compressed)?;
+    let key_len = count.div_ceil(8);
+    // Every value takes at least one byte after the keys
+    if encoded.len() < key_len + count {
+        return Err(anyhow::anyhow!("Truncated VBZ signal chunk"));
+    }
+    let (keys, data) = encoded.split_at(key_len);
+
+    let mut out = Vec::with_capacity(count);
+    let mut pos = 0;
+    let mut prev: i16 = 0;
+    for i in 0..count {
+        let value = if (keys[i / 8] >> (i % 8)) & 1 == 1 {
+            let v = data
+                .get(pos..pos + 2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]));
+            pos += 2;
+            v
+        } else {
+            let v = data.get(pos).map(|&b| b as u16);
+            pos += 1;
+            v
+        }
+        .ok_or_else(|| anyhow::anyhow!("Truncated VBZ signal chunk"))?;
+        let delta = ((value >> 1) as i16) ^ -((value & 1) as i16);
+        prev = prev.wrapping_add(delta);
+        out.push(prev);
+    }
+    Ok(out)
+}
+
+/// Parse a canonical UUID string (with or without hyphens) into its 16 bytes.
+fn parse_uuid(s: &str) -> Option<[u8; 16]> {
+    let hex: Vec<u8> = s.bytes().filter(|&b| b != b'-').collect();
+    if hex.len() != 32 {
+        return None;
+    }
+    let mut out = [0u8; 16];
+    for (i, pair) in hex.chunks_exact(2).enumerate() {
+        out[i] = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Written by tests/data/make_signal_fixtures.py
+    const DATA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+
+    /// Read IDs with their calibrated signal, see the fixture script.
+    fn expected_reads() -> Vec<(&'static str, Vec<f32>)> {
+        vec![
+            (
+                "0b3f8a52-6c1e-4d2a-9f47-1e5c8d2b7a60",
+                (0..10).map(|x| x as f32).collect(),
+            ),
+            (
+                "7c9e6679-7425-40de-944b-e07fc1f90ae7",
+                [-500, 300, 1200, -32768, 32767, 5, 5, -1]
+                    .iter()
+                    .map(|&x| (x as f32 + 10.0) * 0.5)
+                    .collect(),
+            ),
+            ("f47ac10b-58cc-4372-a567-0e02b2c3d479", vec![0.0; 9]),
+        ]
+    }
+
+    fn assert_signals(reader: &Pod5Reader) {
+        assert_eq!(reader.num_reads(), 3);
+        for (read_id, signal) in expected_reads() {
+            assert_eq!(
+                reader.fetch_signal(read_id).unwrap(),
+                Some(signal),
+                "{}",
+                read_id
+            );
+        }
+    }
+
+    #[test]
+    fn vbz_compressed_signal() {
+        let reader = Pod5Reader::open(Path::new(&format!("{}/signal.pod5", DATA))).unwrap();
+        assert!(reader.files[0].vbz);
+        assert_signals(&reader);
+    }
+
+    #[test]
+    fn uncompressed_signal() {
+        let path = format!("{}/signal_uncompressed.pod5", DATA);
+        let reader = Pod5Reader::open(Path::new(&path)).unwrap();
+        assert!(!reader.files[0].vbz);
+        assert_signals(&reader);
+    }
+
+    #[test]
+    fn written_by_pod5lib() {
+        let path = format!("{}/signal_pod5lib.pod5", DATA);
+        let reader = Pod5Reader::open(Path::new(&path)).unwrap();
+        assert!(reader.files[0].vbz);
+        assert_signals(&reader);
+    }
+
+    #[test]
+    fn directory_of_files() {
+        let reader = Pod5Reader::open(Path::new(DATA)).unwrap();
+        assert_eq!(reader.files.len(), 3);
+        assert_signals(&reader);
+    }
+
+    #[test]
+    fn read_id_lookup() {
+        let reader = Pod5Reader::open(Path::new(&format!("{}/signal.pod5", DATA))).unwrap();
+        let without_hyphens = "f47ac10b58cc4372a5670e02b2c3d479";
+        assert_eq!(
+            reader.fetch_signal(without_hyphens).unwrap(),
+            Some(vec![0.0; 9])
+        );
+        let absent = "00000000-0000-0000-0000-000000000000";
+        assert_eq!(reader.fetch_signal(absent).unwrap(), None);
+        assert_eq!(reader.fetch_signal("read1").unwrap(), None);
+    }
+
+    #[test]
+    fn vbz_errors() {
+        let chunk = zstd::bulk::compress(&[0, 2, 4], 0).unwrap();
+        assert_eq!(decode_vbz(&chunk, 2).unwrap(), vec![1, 3]);
+        // Three values need one key byte and at least one byte each
+        let err = decode_vbz(&chunk, 3).unwrap_err();
+        assert_eq!(err.to_string(), "Truncated VBZ signal chunk");
+        // A sample count far beyond the chunk is rejected before allocating
+        let err = decode_vbz(&chunk, u32::MAX as usize).unwrap_err();
+        assert_eq!(err.to_string(), "Truncated VBZ signal chunk");
+        // Two byte values are flagged in the key
+        let chunk = zstd::bulk::compress(&[0b10, 0, 0x2c, 0x01], 0).unwrap();
+        assert_eq!(decode_vbz(&chunk, 2).unwrap(), vec![0, 150]);
+        assert!(decode_vbz(&chunk[..chunk.len() - 1], 2).is_err());
+        assert!(decode_vbz(b"not zstd", 2).is_err());
+    }
+
+    /// Open `bytes` as a POD5 file and fetch every read, which may fail but
+    /// must not panic.
+    fn open_and_fetch(bytes: &[u8], name: &str) -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("nanopile-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, bytes)?;
+        let result = Pod5Reader::open(&path).and_then(|reader| {
+            for (read_id, _) in expected_reads() {
+                reader.fetch_signal(read_id)?;
+            }
+            Ok(())
+        });
+        std::fs::remove_file(&path)?;
+        result
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        for name in [
+            "signal.pod5",
+            "signal_uncompressed.pod5",
+            "signal_pod5lib.pod5",
+        ] {
+            let bytes = std::fs::read(format!("{}/{}", DATA, name)).unwrap();
+            open_and_fetch(&bytes, name).unwrap();
+            for len in (0..bytes.len()).step_by(bytes.len() / 40) {
+                assert!(
+                    open_and_fetch(&bytes[..len], name).is_err(),
+                    "{} bytes",
+                    len
+                );
+            }
+            // Corrupting padding or unused columns is harmless, anything else is an error
+            for pos in (0..bytes.len()).step_by(bytes.len() / 200) {
+                let mut corrupt = bytes.clone();
+                corrupt[pos] ^= 0xa5;
+                let _ = open_and_fetch(&corrupt, name);
+            }
+        }
+    }
+
+    #[test]
+    fn huge_lengths_are_errors() {
+        let bytes = std::fs::read(format!("{}/signal.pod5", DATA)).unwrap();
+        // The footer length sits before the closing section marker and signature
+        let len_pos = bytes.len() - 8 - 16 - 8;
+        for footer_len in [i64::MAX, -8, bytes.len() as i64] {
+            let mut corrupt = bytes.clone();
+            corrupt[len_pos..len_pos + 8].copy_from_slice(&footer_len.to_le_bytes());
+            let err = open_and_fetch(&corrupt, "footer_len.pod5").unwrap_err();
+            assert!(format!("{:#}", err).contains("Invalid POD5 footer length"));
+        }
+    }
+}
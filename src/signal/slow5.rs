@@ -0,0 +1,450 @@
+use super::SignalSource;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+const BLOW5_MAGIC: &[u8] = b"BLOW5\x01";
+const BLOW5_EOF: &[u8] = b"5WOLB";
+// The fixed part of the BLOW5 header is padded to this size, followed by the text header length
+const BLOW5_HEADER_SIZE_OFFSET: u64 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Slow5Format {
+    Ascii,
+    Binary {
+        record_compression: u8,
+        signal_compression: u8,
+    },
+}
+
+/// Column positions of an ASCII SLOW5 record.
+#[derive(Debug, Clone, Copy)]
+struct AsciiColumns {
+    digitisation: usize,
+    offset: usize,
+    range: usize,
+    len_raw_signal: usize,
+    raw_signal: usize,
+}
+
+/// Raw signal lookup by read ID in a SLOW5 (text) or BLOW5 (binary) file.
+pub struct Slow5Reader {
+    path: PathBuf,
+    file: File,
+    format: Slow5Format,
+    columns: Option<AsciiColumns>,
+    /// Byte offset and length of every record
+    index: HashMap<String, (u64, u64)>,
+}
+
+impl Slow5Reader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open SLOW5 file '{}'", path.display()))?;
+        let mut magic = [0u8; 6];
+        let is_binary = file.read_exact_at(&mut magic, 0).is_ok() && magic == BLOW5_MAGIC;
+
+        let mut reader = Self {
+            path: path.to_path_buf(),
+            file,
+            format: Slow5Format::Ascii,
+            columns: None,
+            index: HashMap::new(),
+        };
+        if is_binary {
+            reader.index_binary()
+        } else {
+            reader.index_ascii()
+        }
+        .with_context(|| format!("Failed to index SLOW5 file '{}'", path.display()))?;
+        Ok(reader)
+    }
+
+    fn index_ascii(&mut self) -> Result<()> {
+        let mut lines = BufReader::new(&self.file);
+        let mut offset = 0u64;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let n = lines.read_line(&mut line)? as u64;
+            if n == 0 {
+                break;
+            }
+            if let Some(header) = line.strip_prefix("#read_id") {
+                let names: Vec<&str> = std::iter::once("read_id")
+                    .chain(header.trim_end().split('\t').skip(1))
+                    .collect();
+                let col = |name: &str| {
+                    names
+                        .iter()
+                        .position(|&c| c == name)
+                        .ok_or_else(|| anyhow::anyhow!("SLOW5 header has no '{}' column", name))
+                };
+                self.columns = Some(AsciiColumns {
+                    digitisation: col("digitisation")?,
+                    offset: col("offset")?,
+                    range: col("range")?,
+                    len_raw_signal: col("len_raw_signal")?,
+                    raw_signal: col("raw_signal")?,
+                });
+            } else if !line.starts_with(['#', '@']) && !line.trim().is_empty() {
+                let read_id = line.split('\t').next().unwrap_or_default().to_string();
+                self.index.insert(read_id, (offset, n));
+            }
+            offset += n;
+        }
+        if self.columns.is_none() {
+            return Err(anyhow::anyhow!("Missing '#read_id' column header line"));
+        }
+        Ok(())
+    }
+
+    fn index_binary(&mut self) -> Result<()> {
+        let mut fixed = [0u8; BLOW5_HEADER_SIZE_OFFSET as usize + 4];
+        self.file.read_exact_at(&mut fixed, 0)?;
+        // Magic, version (3 bytes), record compression, number of read groups
+        // (u32), then signal compression from format version 0.2.0 on
+        let version = (fixed[6], fixed[7], fixed[8]);
+        let signal_compression = if version >= (0, 2, 0) { fixed[14] } else { 0 };
+        self.format = Slow5Format::Binary {
+            record_compression: fixed[9],
+            signal_compression,
+        };
+        let header_size = u32::from_le_bytes(fixed[64..68].try_into().unwrap()) as u64;
+
+        let file_len = self.file.metadata()?.len();
+        let mut pos = BLOW5_HEADER_SIZE_OFFSET + 4 + header_size;
+        loop {
+            if file_len.saturating_sub(pos) == BLOW5_EOF.len() as u64 {
+                let mut eof = [0u8; 5];
+                self.file.read_exact_at(&mut eof, pos)?;
+                if eof == BLOW5_EOF {
+                    break;
+                }
+            }
+            if file_len.saturating_sub(pos) < 8 {
+                return Err(anyhow::anyhow!(
+                    "BLOW5 file has no end of file marker, it may be truncated"
+                ));
+            }
+            let mut size = [0u8; 8];
+            self.file.read_exact_at(&mut size, pos)?;
+            let size = u64::from_le_bytes(size);
+            if size > file_len - pos - 8 {
+                return Err(anyhow::anyhow!(
+                    "BLOW5 record at byte {} runs past the end of the file",
+                    pos
+                ));
+            }
+            let record = self.read_binary_record(pos + 8, size)?;
+            let id_len = u16::from_le_bytes(
+                record
+                    .get(..2)
+                    .ok_or_else(|| anyhow::anyhow!("Truncated BLOW5 record"))?
+                    .try_into()
+                    .unwrap(),
+            ) as usize;
+            let read_id = record
+                .get(2..2 + id_len)
+                .ok_or_else(|| anyhow::anyhow!("Truncated BLOW5 record"))?;
+            self.index.insert(
+                String::from_utf8_lossy(read_id).to_string(),
+                (pos + 8, size),
+            );
+            pos += 8 + size;
+        }
+        Ok(())
+    }
+
+    /// Read and decompress the binary record stored at `offset`.
+    fn read_binary_record(&self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        let mut raw = vec![0u8; size as usize];
+        self.file.read_exact_at(&mut raw, offset)?;
+        match self.format {
+            Slow5Format::Binary {
+                record_compression: 0,
+                ..
+            } => Ok(raw),
+            Slow5Format::Binary {
+                record_compression: 1,
+                ..
+            } => super::zlib_decompress(&raw),
+            Slow5Format::Binary {
+                record_compression: 2,
+                ..
+            } => super::zstd_decompress(&raw),
+            Slow5Format::Binary {
+                record_compression, ..
+            } => Err(anyhow::anyhow!(
+                "Unsupported BLOW5 record compression {}",
+                record_compression
+            )),
+            Slow5Format::Ascii => unreachable!("ASCII records are not compressed"),
+        }
+    }
+
+    fn parse_ascii_record(&self, line: &str) -> Result<Vec<f32>> {
+        let cols = self.columns.expect("columns are set while indexing");
+        let fields: Vec<&str> = line.trim_end().split('\t').collect();
+        let field = |idx: usize| {
+            fields
+                .get(idx)
+                .copied()
+                .ok_or_else(|| anyhow::anyhow!("SLOW5 record has too few columns"))
+        };
+        let digitisation: f32 = field(cols.digitisation)?.parse()?;
+        let offset: f32 = field(cols.offset)?.parse()?;
+        let range: f32 = field(cols.range)?.parse()?;
+        let len: usize = field(cols.len_raw_signal)?.parse()?;
+        let scale = range / digitisation;
+        let signal = field(cols.raw_signal)?
+            .split(',')
+            .map(|v| Ok((v.parse::<i16>()? as f32 + offset) * scale))
+            .collect::<Result<Vec<_>>>()?;
+        if signal.len() != len {
+            return Err(anyhow::anyhow!(
+                "SLOW5 record has {} samples but len_raw_signal is {}",
+                signal.len(),
+                len
+            ));
+        }
+        Ok(signal)
+    }
+
+    fn parse_binary_record(&self, record: &[u8]) -> Result<Vec<f32>> {
+        let mut cursor = Cursor {
+            buf: record,
+            pos: 0,
+        };
+        let id_len = u16::from_le_bytes(cursor.take()?) as usize;
+        cursor.skip(id_len + 4)?; // read_id, read_group
+        let digitisation = f64::from_le_bytes(cursor.take()?);
+        let offset = f64::from_le_bytes(cursor.take()?);
+        let range = f64::from_le_bytes(cursor.take()?);
+        cursor.skip(8)?; // sampling_rate
+        // Number of samples, or of bytes when the signal is compressed
+        let len = u64::from_le_bytes(cursor.take()?) as usize;
+
+        let raw = match self.format {
+            Slow5Format::Binary {
+                signal_compression: 0,
+                ..
+            } => cursor
+                .bytes(len.saturating_mul(2))?
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                .collect(),
+            Slow5Format::Binary {
+                signal_compression: 1,
+                ..
+            } => decode_svb_zd(cursor.bytes(len)?)?,
+            _ => {
+                return Err(anyhow::anyhow!("Unsupported BLOW5 signal compression"));
+            }
+        };
+
+        let scale = range / digitisation;
+        Ok(raw
+            .into_iter()
+            .map(|x| ((x as f64 + offset) * scale) as f32)
+            .collect())
+    }
+}
+
+impl SignalSource for Slow5Reader {
+    fn num_reads(&self) -> usize {
+        self.index.len()
+    }
+
+    fn fetch_signal(&self, read_id: &str) -> Result<Option<Vec<f32>>> {
+        let Some(&(offset, size)) = self.index.get(read_id) else {
+            return Ok(None);
+        };
+        let context = || {
+            format!(
+                "Failed to read signal of read '{}' from '{}'",
+                read_id,
+                self.path.display()
+            )
+        };
+        let signal = if self.format == Slow5Format::Ascii {
+            let mut line = vec![0u8; size as usize];
+            self.file
+                .read_exact_at(&mut line, offset)
+                .with_context(context)?;
+            self.parse_ascii_record(&String::from_utf8_lossy(&line))
+        } else {
+            self.read_binary_record(offset, size)
+                .and_then(|record| self.parse_binary_record(&record))
+        }
+        .with_context(context)?;
+        Ok(Some(signal))
+    }
+}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let out = self
+            .buf
+            .get(self.pos..self.pos.saturating_add(n))
+            .ok_or_else(|| anyhow::anyhow!("Truncated BLOW5 record"))?;
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn skip(&mut self, n: usize) -> Result<()> {
+        self.bytes(n).map(|_| ())
+    }
+}
+
+/// Decode svb-zd signal: a u32 value count followed by stream VByte encoded
+/// zigzag deltas of the samples.
+fn decode_svb_zd(buf: &[u8]) -> Result<Vec<i16>> {
+    let mut cursor = Cursor { buf, pos: 0 };
+    let count = u32::from_le_bytes(cursor.take()?) as usize;
+    let keys = cursor.bytes(count.div_ceil(4))?;
+
+    let mut out = Vec::with_capacity(count);
+    let mut prev: i32 = 0;
+    for i in 0..count {
+        let width = ((keys[i / 4] >> (2 * (i % 4))) & 0b11) as usize + 1;
+        let mut value = [0u8; 4];
+        value[..width].copy_from_slice(cursor.bytes(width)?);
+        let zigzag = u32::from_le_bytes(value);
+        let delta = ((zigzag >> 1) as i32) ^ -((zigzag & 1) as i32);
+        prev = prev.wrapping_add(delta);
+        out.push(prev as i16);
+    }
+    if cursor.pos != buf.len() {
+        return Err(anyhow::anyhow!(
+            "svb-zd block has {} trailing bytes",
+            buf.len() - cursor.pos
+        ));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Written with slow5lib by tests/data/make_slow5_fixtures.c
+    const DATA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+
+    /// The same reads as the POD5 fixtures, calibrated to pA.
+    fn expected_reads() -> Vec<(&'static str, Vec<f32>)> {
+        vec![
+            (
+                "0b3f8a52-6c1e-4d2a-9f47-1e5c8d2b7a60",
+                (0..10).map(|x| x as f32).collect(),
+            ),
+            (
+                "7c9e6679-7425-40de-944b-e07fc1f90ae7",
+                [-500, 300, 1200, -32768, 32767, 5, 5, -1]
+                    .iter()
+                    .map(|&x| (x as f32 + 10.0) * 0.5)
+                    .collect(),
+            ),
+            ("f47ac10b-58cc-4372-a567-0e02b2c3d479", vec![0.0; 9]),
+        ]
+    }
+
+    fn open(name: &str) -> Slow5Reader {
+        Slow5Reader::open(Path::new(&format!("{}/{}", DATA, name))).unwrap()
+    }
+
+    fn assert_signals(reader: &Slow5Reader) {
+        assert_eq!(reader.num_reads(), 3);
+        for (read_id, signal) in expected_reads() {
+            assert_eq!(
+                reader.fetch_signal(read_id).unwrap(),
+                Some(signal),
+                "{}",
+                read_id
+            );
+        }
+        let absent = "00000000-0000-0000-0000-000000000000";
+        assert_eq!(reader.fetch_signal(absent).unwrap(), None);
+    }
+
+    #[test]
+    fn ascii() {
+        let reader = open("signal.slow5");
+        assert_eq!(reader.format, Slow5Format::Ascii);
+        assert_signals(&reader);
+    }
+
+    #[test]
+    fn binary_uncompressed() {
+        let reader = open("signal_uncompressed.blow5");
+        assert_eq!(
+            reader.format,
+            Slow5Format::Binary {
+                record_compression: 0,
+                signal_compression: 0
+            }
+        );
+        assert_signals(&reader);
+    }
+
+    #[test]
+    fn binary_zlib() {
+        let reader = open("signal_zlib.blow5");
+        assert_eq!(
+            reader.format,
+            Slow5Format::Binary {
+                record_compression: 1,
+                signal_compression: 0
+            }
+        );
+        assert_signals(&reader);
+    }
+
+    #[test]
+    fn binary_zstd_svb_zd() {
+        let reader = open("signal_zstd_svb.blow5");
+        assert_eq!(
+            reader.format,
+            Slow5Format::Binary {
+                record_compression: 2,
+                signal_compression: 1
+            }
+        );
+        assert_signals(&reader);
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let bytes = std::fs::read(format!("{}/signal_zstd_svb.blow5", DATA)).unwrap();
+        let path = std::env::temp_dir().join(format!("nanopile-test-{}.blow5", std::process::id()));
+        for len in [bytes.len() - 5, bytes.len() - 40, 100, 66] {
+            std::fs::write(&path, &bytes[..len]).unwrap();
+            assert!(Slow5Reader::open(&path).is_err(), "truncated to {}", len);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn svb_zd() {
+        // Count, one key byte (1 and 2 byte values), zigzag deltas 0, +150, -1
+        let buf = [3, 0, 0, 0, 0b0000_0100, 0, 0x2c, 0x01, 1];
+        assert_eq!(decode_svb_zd(&buf).unwrap(), vec![0, 150, 149]);
+        assert!(decode_svb_zd(&buf[..8]).is_err());
+        let err = decode_svb_zd(&[buf.as_slice(), &[0]].concat()).unwrap_err();
+        assert_eq!(err.to_string(), "svb-zd block has 1 trailing bytes");
+        // A huge count fails on the missing keys before allocating
+        assert!(decode_svb_zd(&[0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+}
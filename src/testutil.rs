@@ -0,0 +1,90 @@
+//! Small indexed BAM and FASTA fixtures for tests that run a whole pileup.
+
+use rust_htslib::bam::{
+    self,
+    header::HeaderRecord,
+    record::{Cigar, CigarString},
+};
+use rust_htslib::faidx;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Directory under the system temp dir, removed with its files when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        // Tests run in parallel, so every fixture gets its own directory
+        static DIRS: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "nanopile-test-{}-{}",
+            std::process::id(),
+            DIRS.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Forward alignment of `seq` to contig `tid` from `pos` (0-based), with
+/// mapping quality 60 and base quality 30.
+pub fn read(name: &str, tid: i32, pos: i64, cigar: &[Cigar], seq: &[u8]) -> bam::Record {
+    let mut record = bam::Record::new();
+    record.set(
+        name.as_bytes(),
+        Some(&CigarString(cigar.to_vec())),
+        seq,
+        &vec![30; seq.len()],
+    );
+    record.set_flags(0);
+    record.set_tid(tid);
+    record.set_pos(pos);
+    record.set_mapq(60);
+    record
+}
+
+/// Write `records` in coordinate order to an indexed BAM at `path`, with one
+/// `@SQ` line per `(name, length)` in `contigs`.
+pub fn write_bam(path: &Path, contigs: &[(&str, usize)], mut records: Vec<bam::Record>) {
+    let mut header = bam::Header::new();
+    header.push_record(
+        HeaderRecord::new(b"HD")
+            .push_tag(b"VN", "1.6")
+            .push_tag(b"SO", "coordinate"),
+    );
+    for (name, len) in contigs {
+        header.push_record(
+            HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", name)
+                .push_tag(b"LN", len),
+        );
+    }
+    records.sort_by_key(|r| (r.tid(), r.pos()));
+    {
+        let mut writer = bam::Writer::from_path(path, &header, bam::Format::Bam).unwrap();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+    }
+    bam::index::build(path, None, bam::index::Type::Bai, 1).unwrap();
+}
+
+/// Write an indexed FASTA at `path` with one `(name, sequence)` per contig.
+pub fn write_fasta(path: &Path, contigs: &[(&str, &str)]) {
+    let fasta: String = contigs
+        .iter()
+        .map(|(name, seq)| format!(">{}\n{}\n", name, seq))
+        .collect();
+    std::fs::write(path, fasta).unwrap();
+    faidx::build(path).unwrap();
+}
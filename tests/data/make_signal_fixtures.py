@@ -0,0 +1,349 @@
+#!/usr/bin/env python3
+"""Write the small signal archives used by the unit tests in src/signal/.
+
+The POD5 files follow the layout of files written by the pod5 library: a
+signature, Arrow IPC (Feather v2) tables with extension type metadata, a
+dictionary encoded column and a run info table, separated by section markers
+and indexed by a flatbuffer footer. Needs the `zstd` command line tool.
+
+    cd tests/data && python3 make_signal_fixtures.py
+
+signal_pod5lib.pod5 is not written here: it holds the same reads written by
+the pod5lib crate (0.1.0), an independent POD5 writer, so the reader is also
+checked against a file whose layout this script did not decide.
+"""
+import struct
+import subprocess
+import uuid
+
+# read ID, chunks of raw ADC values, calibration offset, calibration scale
+READS = [
+    ("0b3f8a52-6c1e-4d2a-9f47-1e5c8d2b7a60", [[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]], 0.0, 1.0),
+    (
+        "7c9e6679-7425-40de-944b-e07fc1f90ae7",
+        [[-500, 300, 1200, -32768], [32767, 5, 5, -1]],
+        10.0,
+        0.5,
+    ),
+    ("f47ac10b-58cc-4372-a567-0e02b2c3d479", [[100] * 9], -100.0, 2.0),
+]
+
+
+def zstd(data):
+    return subprocess.run(["zstd", "-q", "-c"], input=data, capture_output=True, check=True).stdout
+
+
+def pad8(data):
+    return data + b"\0" * (-len(data) % 8)
+
+
+class Builder:
+    """Front to back flatbuffer builder; objects are written before their children."""
+
+    SIZES = {"u8": 1, "bool": 1, "i16": 2, "i32": 4, "i64": 8, "off": 4}
+    FORMATS = {"u8": "<B", "bool": "<B", "i16": "<h", "i32": "<i", "i64": "<q"}
+
+    def __init__(self):
+        self.buf = bytearray(4)
+
+    def align(self, n, extra=0):
+        while (len(self.buf) + extra) % n:
+            self.buf.append(0)
+
+    def table(self, fields):
+        count = max((f[0] for f in fields), default=-1) + 1
+        layout, size = {}, 4
+        for fid, ty, _ in sorted(fields, key=lambda f: -self.SIZES[f[1]]):
+            width = self.SIZES[ty]
+            size += -size % width
+            layout[fid] = size
+            size += width
+        self.align(2)
+        vtable = len(self.buf)
+        self.buf += struct.pack("<HH", 4 + 2 * count, size)
+        for i in range(count):
+            self.buf += struct.pack("<H", layout.get(i, 0))
+        self.align(8)
+        pos = len(self.buf)
+        self.buf += bytearray(size)
+        struct.pack_into("<i", self.buf, pos, pos - vtable)
+        children = []
+        for fid, ty, value in fields:
+            at = pos + layout[fid]
+            if ty == "off":
+                children.append((at, value))
+            else:
+                struct.pack_into(self.FORMATS[ty], self.buf, at, value)
+        for at, child in children:
+            struct.pack_into("<I", self.buf, at, child(self) - at)
+        return pos
+
+    def string(self, text):
+        self.align(4)
+        pos = len(self.buf)
+        data = text.encode()
+        self.buf += struct.pack("<I", len(data)) + data + b"\0"
+        return pos
+
+    def tables(self, children):
+        self.align(4)
+        pos = len(self.buf)
+        self.buf += struct.pack("<I", len(children))
+        slots = []
+        for _ in children:
+            slots.append(len(self.buf))
+            self.buf += bytearray(4)
+        for slot, child in zip(slots, children):
+            struct.pack_into("<I", self.buf, slot, child(self) - slot)
+        return pos
+
+    def structs(self, count, data):
+        self.align(8, 4)
+        pos = len(self.buf)
+        self.buf += struct.pack("<I", count) + data
+        return pos
+
+    @classmethod
+    def finish(cls, root):
+        builder = cls()
+        struct.pack_into("<I", builder.buf, 0, root(builder))
+        builder.align(8)
+        return bytes(builder.buf)
+
+
+def T(fields):
+    return lambda b: b.table(fields)
+
+
+def S(text):
+    return lambda b: b.string(text)
+
+
+def V(children):
+    return lambda b: b.tables(children)
+
+
+def ST(count, data):
+    return lambda b: b.structs(count, data)
+
+
+def key_values(pairs):
+    return V([T([(0, "off", S(k)), (1, "off", S(v))]) for k, v in pairs])
+
+
+# Arrow schema types: (Type union id, type table)
+def int_t(bits, signed):
+    return (2, T([(0, "i32", bits), (1, "bool", int(signed))]))
+
+
+def float_t():
+    return (3, T([(0, "i16", 1)]))
+
+
+def utf8_t():
+    return (5, T([]))
+
+
+def list_t():
+    return (12, T([]))
+
+
+def fixed_binary_t(width):
+    return (15, T([(0, "i32", width)]))
+
+
+def large_binary_t():
+    return (19, T([]))
+
+
+def large_list_t():
+    return (21, T([]))
+
+
+def field(name, ty, children=(), extension=None, dictionary=None):
+    type_id, type_table = ty
+    fields = [
+        (0, "off", S(name)),
+        (1, "bool", 1),
+        (2, "u8", type_id),
+        (3, "off", type_table),
+        (5, "off", V(list(children))),
+    ]
+    if dictionary is not None:
+        dict_id, index_type = dictionary
+        fields.append((4, "off", T([(0, "i64", dict_id), (1, "off", index_type[1])])))
+    if extension is not None:
+        fields.append((6, "off", key_values([("ARROW:extension:name", extension)])))
+    return T(fields)
+
+
+def record_batch(rows, nodes, buffers):
+    body, layout = bytearray(), []
+    for data in buffers:
+        layout.append((len(body), len(data)))
+        body += pad8(data)
+    batch = T([
+        (0, "i64", rows),
+        (1, "off", ST(len(nodes), b"".join(struct.pack("<qq", *n) for n in nodes))),
+        (2, "off", ST(len(layout), b"".join(struct.pack("<qq", *b) for b in layout))),
+    ])
+    return batch, bytes(body)
+
+
+def message(header_type, header, body):
+    meta = Builder.finish(T([(0, "i16", 4), (1, "u8", header_type), (2, "off", header), (3, "i64", len(body))]))
+    meta = pad8(struct.pack("<Ii", 0xFFFFFFFF, len(meta)) + meta)
+    return meta, body
+
+
+def arrow_file(fields, batches, dictionaries=(), metadata=()):
+    """Arrow IPC file; batches and dictionaries are (rows, nodes, buffers) tuples."""
+    schema = T([(0, "i16", 0), (1, "off", V(fields)), (2, "off", key_values(metadata))])
+    out = bytearray(b"ARROW1\0\0")
+    meta, _ = message(1, schema, b"")
+    out += meta
+
+    def write_blocks(messages):
+        blocks = []
+        for meta, body in messages:
+            blocks.append((len(out), len(meta), len(body)))
+            out.extend(meta + body)
+        return b"".join(struct.pack("<qiiq", o, m, 0, b) for o, m, b in blocks), len(blocks)
+
+    dict_messages = []
+    for dict_id, (rows, nodes, buffers) in dictionaries:
+        batch, body = record_batch(rows, nodes, buffers)
+        dict_messages.append(message(2, T([(0, "i64", dict_id), (1, "off", batch)]), body))
+    dict_blocks, dict_count = write_blocks(dict_messages)
+    batch_blocks, batch_count = write_blocks(
+        [message(3, *record_batch(*batch)) for batch in batches]
+    )
+    out += struct.pack("<Ii", 0xFFFFFFFF, 0)
+    footer = Builder.finish(T([
+        (0, "i16", 4),
+        (1, "off", schema),
+        (2, "off", ST(dict_count, dict_blocks)),
+        (3, "off", ST(batch_count, batch_blocks)),
+    ]))
+    out += footer + struct.pack("<i", len(footer)) + b"ARROW1"
+    return bytes(out)
+
+
+def svb16(values):
+    """pod5's VBZ payload: zigzag deltas in stream VByte with 1 or 2 byte values."""
+    keys, data, prev = bytearray((len(values) + 7) // 8), bytearray(), 0
+    for i, v in enumerate(values):
+        delta = (v - prev + 0x8000) % 0x10000 - 0x8000
+        prev = v
+        zigzag = ((delta << 1) ^ (delta >> 15)) & 0xFFFF
+        if zigzag > 0xFF:
+            keys[i // 8] |= 1 << (i % 8)
+            data += struct.pack("<H", zigzag)
+        else:
+            data.append(zigzag)
+    return bytes(keys + data)
+
+
+def signal_batch(chunks, vbz):
+    ids = b"".join(uuid.UUID(read_id).bytes for read_id, _ in chunks)
+    samples = b"".join(struct.pack("<I", len(c)) for _, c in chunks)
+    n = len(chunks)
+    offsets = [0]
+    if vbz:
+        blobs = [zstd(svb16(c)) for _, c in chunks]
+        for blob in blobs:
+            offsets.append(offsets[-1] + len(blob))
+        buffers = [b"", ids, b"", struct.pack(f"<{n + 1}q", *offsets), b"".join(blobs), b"", samples]
+        return n, [(n, 0), (n, 0), (n, 0)], buffers
+    for _, c in chunks:
+        offsets.append(offsets[-1] + len(c))
+    values = b"".join(struct.pack(f"<{len(c)}h", *c) for _, c in chunks)
+    buffers = [b"", ids, b"", struct.pack(f"<{n + 1}q", *offsets), b"", values, b"", samples]
+    return n, [(n, 0), (n, 0), (offsets[-1], 0), (n, 0)], buffers
+
+
+def pod5(path, vbz):
+    chunks, read_rows = [], []
+    for read_id, read_chunks, _, _ in READS:
+        read_rows.append(list(range(len(chunks), len(chunks) + len(read_chunks))))
+        chunks += [(read_id, c) for c in read_chunks]
+    if vbz:
+        signal_field = field("signal", large_binary_t(), extension="minknow.vbz")
+    else:
+        signal_field = field("signal", large_list_t(), [field("item", int_t(16, True))])
+    file_metadata = [("MINKNOW:pod5_version", "0.3.2"), ("MINKNOW:software", "make_signal_fixtures")]
+    signal_table = arrow_file(
+        [field("read_id", fixed_binary_t(16), extension="minknow.uuid"), signal_field, field("samples", int_t(32, False))],
+        # Split the chunks over two record batches
+        [signal_batch(chunks[:2], vbz), signal_batch(chunks[2:], vbz)],
+        metadata=file_metadata,
+    )
+
+    n = len(READS)
+    ids = b"".join(uuid.UUID(r[0]).bytes for r in READS)
+    list_offsets, rows = [0], []
+    for r in read_rows:
+        rows += r
+        list_offsets.append(len(rows))
+    u32 = lambda values: struct.pack(f"<{len(values)}I", *values)
+    u64 = lambda values: struct.pack(f"<{len(values)}Q", *values)
+    f32 = lambda values: struct.pack(f"<{len(values)}f", *values)
+    reads_table = arrow_file(
+        [
+            field("read_id", fixed_binary_t(16), extension="minknow.uuid"),
+            field("signal", list_t(), [field("item", int_t(64, False))]),
+            field("read_number", int_t(32, False)),
+            field("start", int_t(64, False)),
+            field("pore_type", utf8_t(), dictionary=(0, int_t(16, True))),
+            field("channel", int_t(16, False)),
+            field("calibration_offset", float_t()),
+            field("calibration_scale", float_t()),
+            field("num_samples", int_t(64, False)),
+        ],
+        [(
+            n,
+            [(n, 0), (n, 0), (len(rows), 0)] + [(n, 0)] * 7,
+            [
+                b"", ids,
+                b"", u32(list_offsets), b"", u64(rows),
+                b"", u32(range(1, n + 1)),
+                b"", u64([4000 * i for i in range(n)]),
+                b"", struct.pack(f"<{n}h", *[0] * n),
+                b"", struct.pack(f"<{n}H", *range(100, 100 + n)),
+                b"", f32([r[2] for r in READS]),
+                b"", f32([r[3] for r in READS]),
+                b"", u64([sum(map(len, r[1])) for r in READS]),
+            ],
+        )],
+        dictionaries=[(0, (1, [(1, 0)], [b"", u32([0, 7]), b"not_set"]))],
+        metadata=file_metadata,
+    )
+    run_info_table = arrow_file(
+        [field("acquisition_id", utf8_t()), field("sample_rate", int_t(16, False))],
+        [(1, [(1, 0), (1, 0)], [b"", u32([0, 3]), b"abc", b"", struct.pack("<H", 5000)])],
+        metadata=file_metadata,
+    )
+
+    signature = b"\x8bPOD\r\n\x1a\n"
+    marker = uuid.UUID("5c1a7c3b-8a4f-4a52-9d1e-3f1f3f2a6b11").bytes
+    out = bytearray(signature + marker)
+    embedded = []
+    # ContentType: 0 reads table, 1 signal table, 4 run info table
+    for content_type, table in ((1, signal_table), (4, run_info_table), (0, reads_table)):
+        embedded.append((len(out), len(table), content_type))
+        out += pad8(table) + marker
+    out += b"FOOTER\0\0"
+    footer = Builder.finish(T([
+        (0, "off", S("0c7ed2b8-6d3a-4c9e-b2f1-7a8e9d0c1b2a")),
+        (1, "off", S("make_signal_fixtures")),
+        (2, "off", S("0.3.2")),
+        (3, "off", V([T([(0, "i64", o), (1, "i64", l), (2, "i16", 0), (3, "i16", c)]) for o, l, c in embedded])),
+    ]))
+    out += footer + struct.pack("<q", len(footer)) + marker + signature
+    with open(path, "wb") as f:
+        f.write(out)
+
+
+if __name__ == "__main__":
+    pod5("signal.pod5", vbz=True)
+    pod5("signal_uncompressed.pod5", vbz=False)